@@ -1,8 +1,17 @@
-use anyhow::{bail, Result};
-use clap;
-use serde::{Deserialize, Serialize};
+use anyhow::{anyhow, bail, Context, Result};
+use once_cell::sync::{Lazy, OnceCell};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::{HashMap, HashSet};
 use std::fmt;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Duration;
 
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
 pub enum ChainType {
     Elrond,
     Ethers,
@@ -11,168 +20,305 @@ pub enum ChainType {
     Tendermint,
     Substrate,
     Stellar,
+    /// A chain that isn't in the chain registry, read back from stored data.
+    #[serde(skip)]
+    Unknown,
 }
 
-#[derive(Serialize, Deserialize, Debug, Copy, Clone, Hash, Eq, PartialEq)]
-#[serde(try_from = "String")]
+/// How a chain relates to other chains.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, Eq, PartialEq, Default)]
 #[serde(rename_all = "lowercase")]
-#[derive(clap::ArgEnum)]
-pub enum Chain {
-    Arbitrum,
-    Avalanche,
-    Binance,
-    Celo,
-    CosmosHub,
-    Cronos,
-    Elrond,
-    Ethereum,
-    Fantom,
-    Harmony,
-    Heco,
-    KuCoin,
-    Kusama,
-    Moonriver,
-    Near,
-    OKEx,
-    Optimism,
-    Osmosis,
-    Polkadot,
-    Polygon,
-    Rootstock,
-    SecretNetwork,
-    Solana,
-    Stellar,
-    Terra,
+pub enum Layer {
+    #[default]
+    L1,
+    /// A rollup, settling to its parent chain
+    L2,
+    /// A separate chain bridged to its parent chain
+    Sidechain,
+    /// A chain secured by its parent relay chain
+    Parachain,
+}
+
+impl fmt::Display for Layer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let layer = match self {
+            Layer::L1 => "L1",
+            Layer::L2 => "L2",
+            Layer::Sidechain => "sidechain",
+            Layer::Parachain => "parachain",
+        };
+        f.write_str(layer)
+    }
+}
+
+/// A chain from the chain registry.
+///
+/// Chains are defined in `chains.toml` rather than in code, so that chains
+/// using an existing `ChainType` can be added without rebuilding realtps.
+/// Programs load the registry once with [`load_registry`] before using any
+/// chains. Until then, and in programs that never load one, like tests, the
+/// `chains.toml` that realtps was built with is used.
+///
+/// A chain id read back from stored data that isn't in the registry, e.g. of
+/// a chain since removed from it, is still a `Chain`, of `ChainType::Unknown`.
+#[derive(Copy, Clone)]
+pub struct Chain(&'static ChainInfo);
+
+/// A chain's entry in the chain registry.
+#[derive(Deserialize, Debug)]
+pub struct ChainInfo {
+    /// The chain id, used in db paths, urls and the RPC configuration.
+    pub id: String,
+    /// Chain name showed on the website
+    pub name: String,
+    #[serde(rename = "type")]
+    pub chain_type: ChainType,
+    /// The expected time between blocks, in milliseconds.
+    pub block_time_ms: u64,
+    /// Whether block numbers are slots, some of which have no block, rather
+    /// than consecutive heights.
+    #[serde(default)]
+    pub slots: bool,
+    #[serde(default)]
+    pub layer: Layer,
+    /// The id of the chain an L2, sidechain or parachain is attached to.
+    pub parent: Option<String>,
+    /// A block explorer url, with `{block}` in place of the block number.
+    pub explorer: Option<String>,
+    pub homepage: Option<String>,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// Why the chain is not enabled.
+    pub disabled_reason: Option<String>,
+    /// The id of the chain this is a test network of. Test networks are only
+    /// imported and shown when asked for.
+    pub testnet_of: Option<String>,
+    /// The chain's section of the about page.
+    pub about: Option<String>,
+    pub notes: Option<String>,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+#[derive(Deserialize, Debug)]
+struct ChainRegistry {
+    chain: Vec<ChainInfo>,
+}
+
+/// The registry set by `load_registry`.
+static REGISTRY: OnceCell<ChainRegistry> = OnceCell::new();
+
+/// The built-in registry, used until one is loaded.
+static DEFAULT_REGISTRY: OnceCell<ChainRegistry> = OnceCell::new();
+
+/// Chains read from stored data that aren't in the registry, by id.
+static UNKNOWN_CHAINS: Lazy<Mutex<HashMap<String, &'static ChainInfo>>> =
+    Lazy::new(Default::default);
+
+static BUILTIN_REGISTRY: &str = include_str!("../../../chains.toml");
+
+pub static CHAIN_REGISTRY_PATH: &str = "chains.toml";
+
+static EXPLORER_BLOCK: &str = "{block}";
+
+/// Loads the chain registry from a file.
+///
+/// Must be called before any `Chain` is used, and only once. Chains used
+/// before it is called come from the built-in registry.
+pub fn load_registry<P: AsRef<Path>>(path: P) -> Result<()> {
+    let path = path.as_ref();
+    let registry = fs::read_to_string(path)
+        .context(format!("unable to load chain registry {}", path.display()))?;
+    let registry = parse_registry(&registry)
+        .context(format!("unable to parse chain registry {}", path.display()))?;
+
+    REGISTRY
+        .set(registry)
+        .map_err(|_| anyhow!("chain registry is already loaded"))
+}
+
+fn registry() -> &'static ChainRegistry {
+    REGISTRY.get().unwrap_or_else(|| {
+        DEFAULT_REGISTRY
+            .get_or_init(|| parse_registry(BUILTIN_REGISTRY).expect("built-in chain registry"))
+    })
+}
+
+fn is_valid_id(id: &str) -> bool {
+    !id.is_empty()
+        && id
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+}
+
+fn parse_registry(registry: &str) -> Result<ChainRegistry> {
+    let registry: ChainRegistry = toml::from_str(registry)?;
+
+    let mut ids = HashSet::new();
+    for info in &registry.chain {
+        if !is_valid_id(&info.id) {
+            bail!(
+                "chain id {:?} must be lowercase letters, digits and dashes",
+                info.id
+            );
+        }
+        if !ids.insert(&info.id) {
+            bail!("chain {} is defined more than once", info.id);
+        }
+        if info.block_time_ms == 0 {
+            bail!("block time for {} must be more than 0", info.id);
+        }
+        if let Some(ref explorer) = info.explorer {
+            if !explorer.contains(EXPLORER_BLOCK) {
+                bail!("explorer url for {} has no {}", info.id, EXPLORER_BLOCK);
+            }
+        }
+    }
+
+    let is_testnet = |id: &String| {
+        registry
+            .chain
+            .iter()
+            .any(|info| info.id == *id && info.testnet_of.is_some())
+    };
+
+    for info in &registry.chain {
+        if let Some(ref parent) = info.parent {
+            if *parent == info.id || !ids.contains(parent) {
+                bail!("parent {} of {} is not another chain", parent, info.id);
+            }
+        }
+        if let Some(ref mainnet) = info.testnet_of {
+            if !ids.contains(mainnet) || is_testnet(mainnet) {
+                bail!("{} is a testnet of {}, which is not a mainnet", info.id, mainnet);
+            }
+        }
+    }
+
+    Ok(registry)
 }
 
 impl Chain {
+    /// The enabled mainnet chains.
     pub fn all_chains() -> Vec<Chain> {
-        vec![
-            Chain::Arbitrum,
-            Chain::Avalanche,
-            Chain::Binance,
-            Chain::Celo,
-            // todo rpc disappeared
-            // Chain::CosmosHub,
-            Chain::Cronos,
-            Chain::Elrond,
-            Chain::Ethereum,
-            Chain::Fantom,
-            Chain::Harmony,
-            // todo ssl handshake failure
-            // Chain::Heco,
-            Chain::KuCoin,
-            Chain::Kusama,
-            Chain::Moonriver,
-            Chain::Near,
-            Chain::OKEx,
-            Chain::Optimism,
-            // todo banned
-            // Chain::Osmosis,
-            Chain::Polkadot,
-            Chain::Polygon,
-            Chain::Rootstock,
-            // todo banned?
-            // Chain::SecretNetwork,
-            Chain::Solana,
-            Chain::Stellar,
-            Chain::Terra,
-        ]
+        registry()
+            .chain
+            .iter()
+            .filter(|info| info.enabled && info.testnet_of.is_none())
+            .map(Chain)
+            .collect()
+    }
+
+    /// The enabled test networks.
+    pub fn all_testnets() -> Vec<Chain> {
+        registry()
+            .chain
+            .iter()
+            .filter(|info| info.enabled && info.testnet_of.is_some())
+            .map(Chain)
+            .collect()
+    }
+
+    /// Every chain in the registry, including disabled chains and test
+    /// networks.
+    pub fn registered_chains() -> Vec<Chain> {
+        registry().chain.iter().map(Chain).collect()
+    }
+
+    pub fn info(&self) -> &'static ChainInfo {
+        self.0
+    }
+
+    pub fn id(&self) -> &'static str {
+        &self.0.id
     }
 
     /// Chain names showed on the website
     pub fn description(&self) -> &'static str {
-        match *self {
-            Chain::Arbitrum => "Arbitrum",
-            Chain::Avalanche => "Avalanche C-Chain",
-            Chain::Binance => "Binance Smart Chain",
-            Chain::Celo => "Celo",
-            Chain::CosmosHub => "Cosmos Hub",
-            Chain::Cronos => "Cronos",
-            Chain::Elrond => "Elrond",
-            Chain::Ethereum => "Ethereum",
-            Chain::Fantom => "Fantom",
-            Chain::Harmony => "Harmony",
-            Chain::Heco => "Heco",
-            Chain::KuCoin => "KuCoin",
-            Chain::Kusama => "Kusama",
-            Chain::Moonriver => "Moonriver",
-            Chain::Near => "NEAR",
-            Chain::OKEx => "OKEx",
-            Chain::Optimism => "Optimism",
-            Chain::Osmosis => "Osmosis",
-            Chain::Polkadot => "Polkadot",
-            Chain::Polygon => "Polygon PoS",
-            Chain::Rootstock => "Rootstock",
-            Chain::SecretNetwork => "Secret Network",
-            Chain::Solana => "Solana",
-            Chain::Stellar => "Stellar",
-            Chain::Terra => "Terra",
-        }
+        &self.0.name
     }
 
     pub fn chain_type(&self) -> ChainType {
-        match self {
-            Chain::Arbitrum
-            | Chain::Avalanche
-            | Chain::Binance
-            | Chain::Celo
-            | Chain::Cronos
-            | Chain::Ethereum
-            | Chain::Fantom
-            | Chain::Harmony
-            | Chain::Heco
-            | Chain::KuCoin
-            | Chain::Moonriver
-            | Chain::OKEx
-            | Chain::Optimism
-            | Chain::Polygon
-            | Chain::Rootstock => ChainType::Ethers,
-            Chain::Elrond => ChainType::Elrond,
-            Chain::Near => ChainType::Near,
-            Chain::Solana => ChainType::Solana,
-            Chain::Stellar => ChainType::Stellar,
-            Chain::CosmosHub | Chain::Osmosis | Chain::SecretNetwork | Chain::Terra => {
-                ChainType::Tendermint
-            }
-            Chain::Kusama | Chain::Polkadot => ChainType::Substrate,
-        }
+        self.0.chain_type
+    }
+
+    pub fn block_time(&self) -> Duration {
+        Duration::from_millis(self.0.block_time_ms)
+    }
+
+    pub fn is_testnet(&self) -> bool {
+        self.0.testnet_of.is_some()
+    }
+
+    /// The chain this is a test network of.
+    pub fn mainnet(&self) -> Option<Chain> {
+        let mainnet = self.0.testnet_of.as_ref()?;
+        Some(Chain::try_from(mainnet.as_str()).expect("mainnet chain"))
+    }
+
+    pub fn parent(&self) -> Option<Chain> {
+        let parent = self.0.parent.as_ref()?;
+        Some(Chain::try_from(parent.as_str()).expect("parent chain"))
+    }
+
+    /// A chain that isn't in the registry, disabled and without a client.
+    fn unknown(id: String) -> Chain {
+        let mut unknown_chains = UNKNOWN_CHAINS.lock().expect("unknown chains");
+        let info = unknown_chains.entry(id.clone()).or_insert_with(|| {
+            Box::leak(Box::new(ChainInfo {
+                name: id.clone(),
+                id,
+                chain_type: ChainType::Unknown,
+                block_time_ms: 1000,
+                slots: false,
+                layer: Layer::default(),
+                parent: None,
+                explorer: None,
+                homepage: None,
+                enabled: false,
+                disabled_reason: Some("not in the chain registry".to_string()),
+                testnet_of: None,
+                about: None,
+                notes: None,
+            }))
+        });
+        Chain(info)
+    }
+
+    /// The block explorer url for a block, if the chain has an explorer.
+    pub fn explorer_url(&self, block_number: u64) -> Option<String> {
+        let explorer = self.0.explorer.as_ref()?;
+        Some(explorer.replace(EXPLORER_BLOCK, &block_number.to_string()))
+    }
+}
+
+impl PartialEq for Chain {
+    fn eq(&self, other: &Chain) -> bool {
+        self.id() == other.id()
+    }
+}
+
+impl Eq for Chain {}
+
+impl Hash for Chain {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id().hash(state)
     }
 }
 
-// For parsing command line used in `structopt`.
+// For parsing the command line and urls.
 impl<'a> TryFrom<&'a str> for Chain {
     type Error = anyhow::Error;
 
     fn try_from(value: &'a str) -> Result<Self> {
-        match value {
-            "arbitrum" => Ok(Chain::Arbitrum),
-            "avalanche" => Ok(Chain::Avalanche),
-            "binance" => Ok(Chain::Binance),
-            "celo" => Ok(Chain::Celo),
-            "cosmoshub" => Ok(Chain::CosmosHub),
-            "cronos" => Ok(Chain::Cronos),
-            "elrond" => Ok(Chain::Elrond),
-            "ethereum" => Ok(Chain::Ethereum),
-            "fantom" => Ok(Chain::Fantom),
-            "harmony" => Ok(Chain::Harmony),
-            "heco" => Ok(Chain::Heco),
-            "kucoin" => Ok(Chain::KuCoin),
-            "kusama" => Ok(Chain::Kusama),
-            "moonriver" => Ok(Chain::Moonriver),
-            "near" => Ok(Chain::Near),
-            "okex" => Ok(Chain::OKEx),
-            "optimism" => Ok(Chain::Optimism),
-            "osmosis" => Ok(Chain::Osmosis),
-            "polkadot" => Ok(Chain::Polkadot),
-            "polygon" => Ok(Chain::Polygon),
-            "rootstock" => Ok(Chain::Rootstock),
-            "secretnetwork" => Ok(Chain::SecretNetwork),
-            "solana" => Ok(Chain::Solana),
-            "stellar" => Ok(Chain::Stellar),
-            "terra" => Ok(Chain::Terra),
-            chain => bail!("failed parsing chain name {}", chain),
-        }
+        registry()
+            .chain
+            .iter()
+            .find(|info| info.id == value)
+            .map(Chain)
+            .ok_or_else(|| anyhow!("failed parsing chain name {}", value))
     }
 }
 
@@ -185,9 +331,59 @@ impl TryFrom<String> for Chain {
     }
 }
 
+impl FromStr for Chain {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        Chain::try_from(value)
+    }
+}
+
+impl Serialize for Chain {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.id())
+    }
+}
+
+// Stored data may name chains that have since left the registry, so unlike
+// parsing, deserializing accepts any valid id.
+impl<'de> Deserialize<'de> for Chain {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let id = String::deserialize(deserializer)?;
+        match Chain::try_from(id.as_str()) {
+            Ok(chain) => Ok(chain),
+            Err(_) if is_valid_id(&id) => Ok(Chain::unknown(id)),
+            Err(e) => Err(serde::de::Error::custom(e)),
+        }
+    }
+}
+
 // Displays a "chain id". Used in `JsonDb` paths and logging.
 impl fmt::Display for Chain {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        self.serialize(f)
+        f.write_str(self.id())
+    }
+}
+
+impl fmt::Debug for Chain {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.id())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn deserialize_unknown_chain() -> Result<()> {
+        let chain: Chain = serde_json::from_str("\"removed-chain\"")?;
+        assert_eq!(chain.id(), "removed-chain");
+        assert_eq!(chain.chain_type(), ChainType::Unknown);
+        assert!(!chain.info().enabled);
+        assert!(Chain::try_from("removed-chain").is_err());
+
+        assert!(serde_json::from_str::<Chain>("\"../db\"").is_err());
+        Ok(())
     }
 }
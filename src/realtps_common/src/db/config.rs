@@ -0,0 +1,169 @@
+use super::*;
+use anyhow::{anyhow, Context};
+use std::env;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// The available `Db` implementations.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, Eq, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum DbKind {
+    #[default]
+    Json,
+    Sqlite,
+    Sled,
+    Segment,
+}
+
+impl DbKind {
+    pub fn default_path(&self) -> &'static str {
+        match self {
+            DbKind::Json => JSON_DB_DIR,
+            DbKind::Sqlite => SQLITE_DB_PATH,
+            DbKind::Sled => SLED_DB_PATH,
+            DbKind::Segment => SEGMENT_DB_DIR,
+        }
+    }
+}
+
+impl FromStr for DbKind {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "json" => Ok(DbKind::Json),
+            "sqlite" => Ok(DbKind::Sqlite),
+            "sled" => Ok(DbKind::Sled),
+            "segment" => Ok(DbKind::Segment),
+            kind => Err(anyhow!("unknown db kind {}", kind)),
+        }
+    }
+}
+
+impl fmt::Display for DbKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.serialize(f)
+    }
+}
+
+/// Which `Db` to use and where it lives.
+///
+/// Written as `<kind>:<path>`, e.g. `json:./db` or
+/// `sqlite:/mnt/realtps/realtps.sqlite`. A bare `<kind>` uses that kind's
+/// default path, relative to the working directory.
+///
+/// Shared by `realtps_import`'s `--db` flag, `realtps_web`'s `db` setting,
+/// and the `REALTPS_DB` environment variable. `realtps_import` reads the
+/// variable only when `--db` isn't given, while for `realtps_web` it
+/// overrides the `db` setting.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+#[serde(try_from = "String", into = "String")]
+pub struct DbConfig {
+    pub kind: DbKind,
+    pub path: PathBuf,
+}
+
+pub static DB_ENV_VAR: &str = "REALTPS_DB";
+
+static LOCK_DIR: &str = "locks";
+
+impl DbConfig {
+    pub fn new(kind: DbKind) -> DbConfig {
+        DbConfig {
+            kind,
+            path: PathBuf::from(kind.default_path()),
+        }
+    }
+
+    /// Reads the `REALTPS_DB` environment variable, if it is set.
+    pub fn from_env() -> Result<Option<DbConfig>> {
+        match env::var(DB_ENV_VAR) {
+            Ok(value) => Ok(Some(
+                value
+                    .parse()
+                    .context(format!("unable to parse {}", DB_ENV_VAR))?,
+            )),
+            Err(env::VarError::NotPresent) => Ok(None),
+            Err(e) => Err(e).context(format!("unable to read {}", DB_ENV_VAR)),
+        }
+    }
+
+    /// Where the `ChainLock`s for this db live: inside a `JsonDb`'s or
+    /// `SegmentDb`'s directory, and next to the other kinds' files.
+    pub fn lock_dir(&self) -> PathBuf {
+        match self.kind {
+            DbKind::Json | DbKind::Segment => self.path.join(LOCK_DIR),
+            DbKind::Sqlite | DbKind::Sled => {
+                let mut lock_dir = self.path.clone().into_os_string();
+                lock_dir.push(".");
+                lock_dir.push(LOCK_DIR);
+                PathBuf::from(lock_dir)
+            }
+        }
+    }
+
+    /// Takes the lock giving this process the right to write `chain`'s data.
+    pub fn lock_chain(&self, chain: Chain) -> Result<ChainLock> {
+        ChainLock::try_lock(&self.lock_dir(), chain)
+    }
+
+    /// Opens the configured `Db`.
+    ///
+    /// A sled db can only be open in one process at a time, so fails to open
+    /// while another process has it open.
+    pub fn open(&self) -> Result<Arc<dyn Db>> {
+        let db: Arc<dyn Db> = match self.kind {
+            DbKind::Json => Arc::new(JsonDb::new(&self.path)),
+            DbKind::Sqlite => Arc::new(SqliteDb::open(&self.path)?),
+            DbKind::Sled => Arc::new(SledDb::open(&self.path)?),
+            DbKind::Segment => Arc::new(SegmentDb::new(&self.path)),
+        };
+
+        Ok(db)
+    }
+}
+
+impl Default for DbConfig {
+    fn default() -> DbConfig {
+        DbConfig::new(DbKind::default())
+    }
+}
+
+impl FromStr for DbConfig {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value.split_once(':') {
+            Some((kind, path)) if !path.is_empty() => Ok(DbConfig {
+                kind: kind.parse()?,
+                path: PathBuf::from(path),
+            }),
+            Some((kind, _)) => Err(anyhow!("missing path for {} db", kind)),
+            None => Ok(DbConfig::new(value.parse()?)),
+        }
+    }
+}
+
+// For serde deserializing.
+impl TryFrom<String> for DbConfig {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        value.parse()
+    }
+}
+
+// For serde serializing.
+impl From<DbConfig> for String {
+    fn from(config: DbConfig) -> String {
+        config.to_string()
+    }
+}
+
+impl fmt::Display for DbConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.kind, self.path.display())
+    }
+}
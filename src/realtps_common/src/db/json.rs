@@ -0,0 +1,555 @@
+use super::*;
+use anyhow::{anyhow, bail};
+use fs2::FileExt;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// A `Db` storing one JSON file per block, and one per meta value, under
+/// `<root>/<chain>/blocks` and `<root>/<chain>/meta`.
+///
+/// The TPS history is a JSON Lines file, `<root>/<chain>/meta/tps_history`,
+/// appended to after every calculation, and reorg events are another,
+/// `<root>/<chain>/meta/reorgs`.
+///
+/// The TPS and calculation log are written and read together under an
+/// advisory lock on `<root>/<chain>/meta.lock`, so readers in other
+/// processes never see one without the other.
+pub struct JsonDb {
+    root: PathBuf,
+}
+
+pub static JSON_DB_DIR: &str = "db";
+
+static META_LOCK_FILE: &str = "meta.lock";
+
+impl JsonDb {
+    pub fn new<P: AsRef<Path>>(root: P) -> JsonDb {
+        JsonDb {
+            root: root.as_ref().to_path_buf(),
+        }
+    }
+}
+
+impl Default for JsonDb {
+    fn default() -> JsonDb {
+        JsonDb::new(JSON_DB_DIR)
+    }
+}
+
+impl Db for JsonDb {
+    fn store_block(&self, block: Block) -> Result<()> {
+        write_json_db(
+            &self.root,
+            &format!("{}", block.chain),
+            &format!("{}", DB_DIR_BLOCKS),
+            &format!("{}", block.block_number),
+            &block,
+        )
+    }
+
+    fn load_block(&self, chain: Chain, block_number: u64) -> Result<Option<Block>> {
+        read_json_db(
+            &self.root,
+            &format!("{}", chain),
+            &format!("{}", DB_DIR_BLOCKS),
+            &format!("{}", block_number),
+        )
+    }
+
+    // Block timestamps increase with block numbers, so this is a binary
+    // search over the block files.
+    fn find_block_at_or_before_timestamp(
+        &self,
+        chain: Chain,
+        timestamp: u64,
+    ) -> Result<Option<Block>> {
+        let block_numbers = self.list_block_numbers(chain)?;
+        let load_block = |i: usize| {
+            self.load_block(chain, block_numbers[i])?
+                .ok_or_else(|| anyhow!("block {} for {} disappeared", block_numbers[i], chain))
+        };
+
+        // Blocks before `low` are at or before `timestamp`; blocks from
+        // `high` on are after it.
+        let (mut low, mut high) = (0, block_numbers.len());
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if load_block(mid)?.timestamp <= timestamp {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+
+        match low {
+            0 => Ok(None),
+            low => Ok(Some(load_block(low - 1)?)),
+        }
+    }
+
+    fn store_highest_block_number(&self, chain: Chain, block_number: u64) -> Result<()> {
+        write_json_db(
+            &self.root,
+            &format!("{}", chain),
+            &format!("{}", DB_DIR_META),
+            &format!("{}", HIGHEST_BLOCK_NUMBER),
+            &block_number,
+        )
+    }
+
+    fn load_highest_block_number(&self, chain: Chain) -> Result<Option<u64>> {
+        read_json_db(
+            &self.root,
+            &format!("{}", chain),
+            &format!("{}", DB_DIR_META),
+            &format!("{}", HIGHEST_BLOCK_NUMBER),
+        )
+    }
+
+    fn store_finalized_block_number(&self, chain: Chain, block_number: u64) -> Result<()> {
+        write_json_db(
+            &self.root,
+            &chain.to_string(),
+            DB_DIR_META,
+            FINALIZED_BLOCK_NUMBER,
+            &block_number,
+        )
+    }
+
+    fn load_finalized_block_number(&self, chain: Chain) -> Result<Option<u64>> {
+        read_json_db(
+            &self.root,
+            &chain.to_string(),
+            DB_DIR_META,
+            FINALIZED_BLOCK_NUMBER,
+        )
+    }
+
+    fn store_tps(&self, chain: Chain, tps: f64) -> Result<()> {
+        write_json_db(
+            &self.root,
+            &format!("{}", chain),
+            &format!("{}", DB_DIR_META),
+            &format!("{}", TRANSACTIONS_PER_SECOND),
+            &tps,
+        )
+    }
+
+    fn load_tps(&self, chain: Chain) -> Result<Option<f64>> {
+        read_json_db(
+            &self.root,
+            &format!("{}", chain),
+            &format!("{}", DB_DIR_META),
+            &format!("{}", TRANSACTIONS_PER_SECOND),
+        )
+    }
+
+    fn append_tps_record(&self, chain: Chain, record: &TpsRecord) -> Result<()> {
+        append_json_line(&self.root, chain, TPS_HISTORY, record)
+    }
+
+    fn load_tps_history(
+        &self,
+        chain: Chain,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Result<Vec<TpsRecord>> {
+        let records = read_json_lines::<TpsRecord>(&self.root, chain, TPS_HISTORY)?;
+        Ok(records
+            .into_iter()
+            .map(|decoded| decoded.record)
+            .filter(|record| record.calculated_at >= from && record.calculated_at <= to)
+            .collect())
+    }
+
+    fn append_reorg_event(&self, event: &ReorgEvent) -> Result<()> {
+        append_json_line(&self.root, event.chain, REORGS, event)
+    }
+
+    fn load_reorg_events(
+        &self,
+        chain: Chain,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Result<Vec<ReorgEvent>> {
+        let events = read_json_lines::<ReorgEvent>(&self.root, chain, REORGS)?;
+        Ok(events
+            .into_iter()
+            .map(|decoded| decoded.record)
+            .filter(|event| event.detected_at >= from && event.detected_at <= to)
+            .collect())
+    }
+
+    fn remove_block(&self, chain: Chain, block: u64) -> Result<()> {
+        let file_path = self
+            .root
+            .join(chain.to_string())
+            .join(DB_DIR_BLOCKS)
+            .join(block.to_string());
+        fs::remove_file(file_path)?;
+        Ok(())
+    }
+
+    fn list_block_numbers(&self, chain: Chain) -> Result<Vec<u64>> {
+        let files = list_json_db(&self.root, &chain.to_string(), DB_DIR_BLOCKS)?;
+        let mut block_numbers = files
+            .iter()
+            .map(|file| {
+                file.parse()
+                    .map_err(|_| anyhow!("unexpected block file {} for {}", file, chain))
+            })
+            .collect::<Result<Vec<u64>>>()?;
+        block_numbers.sort_unstable();
+        Ok(block_numbers)
+    }
+
+    fn store_calculation_log(&self, chain: Chain, log: &CalculationLog) -> Result<()> {
+        write_json_db(
+            &self.root,
+            &format!("{}", chain),
+            &format!("{}", DB_DIR_META),
+            &format!("{}", CALCULATION_LOG),
+            log,
+        )
+    }
+
+    fn load_calculation_log(&self, chain: Chain) -> Result<Option<CalculationLog>> {
+        read_json_db(
+            &self.root,
+            &format!("{}", chain),
+            &format!("{}", DB_DIR_META),
+            &format!("{}", CALCULATION_LOG),
+        )
+    }
+
+    fn store_tps_and_calculation_log(
+        &self,
+        chain: Chain,
+        tps: f64,
+        log: &CalculationLog,
+    ) -> Result<()> {
+        let _lock = self.lock_meta(chain, MetaLock::Exclusive)?;
+        self.store_calculation_log(chain, log)?;
+        self.store_tps(chain, tps)
+    }
+
+    fn load_tps_and_calculation_log(
+        &self,
+        chain: Chain,
+    ) -> Result<(Option<f64>, Option<CalculationLog>)> {
+        let _lock = self.lock_meta(chain, MetaLock::Shared)?;
+        Ok((self.load_tps(chain)?, self.load_calculation_log(chain)?))
+    }
+
+    fn upgrade_records(&self, chain: Chain) -> Result<u64> {
+        let mut num_upgraded = 0;
+
+        for file in list_json_db(&self.root, &chain.to_string(), DB_DIR_BLOCKS)? {
+            if self.upgrade_json_file::<Block>(chain, DB_DIR_BLOCKS, &file)? {
+                num_upgraded += 1;
+            }
+        }
+
+        for file in list_json_db(&self.root, &chain.to_string(), DB_DIR_META)? {
+            let upgraded = if file == HIGHEST_BLOCK_NUMBER || file == FINALIZED_BLOCK_NUMBER {
+                self.upgrade_json_file::<u64>(chain, DB_DIR_META, &file)?
+            } else if file == TRANSACTIONS_PER_SECOND {
+                self.upgrade_json_file::<f64>(chain, DB_DIR_META, &file)?
+            } else if file == CALCULATION_LOG {
+                self.upgrade_json_file::<CalculationLog>(chain, DB_DIR_META, &file)?
+            } else if file == TPS_HISTORY {
+                self.upgrade_json_lines::<TpsRecord>(chain, TPS_HISTORY)?
+            } else if file == REORGS {
+                self.upgrade_json_lines::<ReorgEvent>(chain, REORGS)?
+            } else {
+                false
+            };
+            if upgraded {
+                num_upgraded += 1;
+            }
+        }
+
+        Ok(num_upgraded)
+    }
+
+    fn list_temp_files(&self, chain: Chain) -> Result<Vec<PathBuf>> {
+        let chain_dir = self.root.join(chain.to_string());
+        let mut temp_files = vec![];
+        for sub_dir in [DB_DIR_BLOCKS, DB_DIR_META] {
+            let dir = chain_dir.join(sub_dir);
+            for file in read_json_dir(&dir)? {
+                if is_temp_file(&file) {
+                    temp_files.push(dir.join(file));
+                }
+            }
+        }
+        Ok(temp_files)
+    }
+
+    fn remove_temp_file(&self, chain: Chain, path: &Path) -> Result<()> {
+        let chain_dir = self.root.join(chain.to_string());
+        let is_chain_temp_file = path.starts_with(&chain_dir)
+            && matches!(path.file_name(), Some(file) if is_temp_file(&file.to_string_lossy()));
+        if !is_chain_temp_file {
+            bail!("{} is not a temp file for {}", path.display(), chain);
+        }
+        fs::remove_file(path)?;
+        Ok(())
+    }
+}
+
+enum MetaLock {
+    Shared,
+    Exclusive,
+}
+
+impl JsonDb {
+    /// Locks `<chain>/meta.lock`, so that readers and writers of several meta
+    /// files see them all at once. The lock is released when the file is
+    /// dropped.
+    ///
+    /// Readers don't create the lock file; if it doesn't exist, no writer has
+    /// locked the chain yet, and there's nothing to lock.
+    fn lock_meta(&self, chain: Chain, lock: MetaLock) -> Result<Option<File>> {
+        let chain_dir = self.root.join(chain.to_string());
+        let path = chain_dir.join(META_LOCK_FILE);
+
+        let file = match lock {
+            MetaLock::Shared => match File::open(&path) {
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+                file => file?,
+            },
+            MetaLock::Exclusive => {
+                fs::create_dir_all(&chain_dir)?;
+                fs::OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .truncate(false)
+                    .open(&path)?
+            }
+        };
+        match lock {
+            MetaLock::Shared => file.lock_shared()?,
+            MetaLock::Exclusive => file.lock_exclusive()?,
+        }
+
+        Ok(Some(file))
+    }
+
+    /// Rewrites `file` in the current record version if it is outdated.
+    fn upgrade_json_file<T: Record>(
+        &self,
+        chain: Chain,
+        sub_dir: &str,
+        file: &str,
+    ) -> Result<bool> {
+        let chain = chain.to_string();
+        let decoded = read_json_db_decoded::<T>(&self.root, &chain, sub_dir, file)?;
+        match decoded {
+            Some(decoded) if decoded.is_outdated() => {
+                write_json_db(&self.root, &chain, sub_dir, file, &decoded.record)?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Rewrites a whole JSON Lines meta file, like the TPS history, if any of
+    /// its records are outdated.
+    fn upgrade_json_lines<T: Record>(&self, chain: Chain, file: &str) -> Result<bool> {
+        let records = read_json_lines::<T>(&self.root, chain, file)?;
+        if !records.iter().any(Decoded::is_outdated) {
+            return Ok(false);
+        }
+
+        let file_dir = self.root.join(chain.to_string()).join(DB_DIR_META);
+        let temp_file_path = file_dir.join(format!("{}.{}.temp", file, rand::random::<u32>()));
+
+        let mut writer = BufWriter::new(File::create(&temp_file_path)?);
+        for decoded in records {
+            serde_json::to_writer(&mut writer, &encode_record(&decoded.record))?;
+            writer.write_all(b"\n")?;
+        }
+        writer.flush()?;
+        drop(writer);
+
+        fs::rename(temp_file_path, file_dir.join(file))?;
+
+        Ok(true)
+    }
+}
+
+fn write_json_db<T>(root: &Path, chain: &str, sub_dir: &str, file: &str, data: &T) -> Result<()>
+where
+    T: Record,
+{
+    let file_dir = root.join(chain).join(sub_dir);
+    fs::create_dir_all(&file_dir)?;
+
+    let file_path = file_dir.join(file);
+    let temp_file_path = file_dir.join(format!("{}.{}.temp", file, rand::random::<u32>()));
+
+    let file = File::create(&temp_file_path)?;
+    let mut writer = BufWriter::new(file);
+
+    match serde_json::to_writer(&mut writer, &encode_record(data)) {
+        Err(e) => {
+            fs::remove_file(temp_file_path)?;
+            bail!(e)
+        }
+        Ok(()) => {
+            fs::rename(temp_file_path, file_path)?;
+            Ok(())
+        }
+    }
+}
+
+fn read_json_db<T>(root: &Path, chain: &str, sub_dir: &str, file: &str) -> Result<Option<T>>
+where
+    T: Record,
+{
+    let decoded = read_json_db_decoded(root, chain, sub_dir, file)?;
+    Ok(decoded.map(|decoded| decoded.record))
+}
+
+fn read_json_db_decoded<T>(
+    root: &Path,
+    chain: &str,
+    sub_dir: &str,
+    file: &str,
+) -> Result<Option<Decoded<T>>>
+where
+    T: Record,
+{
+    let path = root.join(chain).join(sub_dir).join(file);
+
+    let file = File::open(path);
+    match file {
+        Err(e) => match e.kind() {
+            std::io::ErrorKind::NotFound => Ok(None),
+            _ => bail!(e),
+        },
+        Ok(file) => {
+            let reader = BufReader::new(file);
+            let data = serde_json::from_reader(reader)?;
+            Ok(Some(decode_record(data)?))
+        }
+    }
+}
+
+/// Appends a record to a JSON Lines meta file, like the TPS history.
+fn append_json_line<T: Record>(root: &Path, chain: Chain, file: &str, record: &T) -> Result<()> {
+    let file_dir = root.join(chain.to_string()).join(DB_DIR_META);
+    fs::create_dir_all(&file_dir)?;
+
+    let mut line = serde_json::to_vec(&encode_record(record))?;
+    line.push(b'\n');
+
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .read(true)
+        .write(true)
+        .open(file_dir.join(file))?;
+
+    // One write per record, so a crash can only leave a torn last line.
+    // Drop it before appending, or this record would be glued onto it.
+    let len = file.seek(SeekFrom::End(0))?;
+    if len > 0 {
+        let mut last_byte = [0; 1];
+        file.seek(SeekFrom::Start(len - 1))?;
+        file.read_exact(&mut last_byte)?;
+
+        if last_byte[0] != b'\n' {
+            let mut contents = vec![];
+            file.seek(SeekFrom::Start(0))?;
+            file.read_to_end(&mut contents)?;
+            let complete_len = contents
+                .iter()
+                .rposition(|b| *b == b'\n')
+                .map_or(0, |i| i + 1);
+            file.set_len(complete_len as u64)?;
+            file.seek(SeekFrom::End(0))?;
+        }
+    }
+
+    file.write_all(&line)?;
+
+    Ok(())
+}
+
+fn read_json_lines<T: Record>(root: &Path, chain: Chain, file: &str) -> Result<Vec<Decoded<T>>> {
+    let path = root.join(chain.to_string()).join(DB_DIR_META).join(file);
+
+    let contents = match fs::read_to_string(path) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        contents => contents?,
+    };
+
+    // Every complete record ends in a newline. Anything after the last
+    // newline is a record torn by a crash, and is ignored.
+    let complete_len = contents.rfind('\n').map_or(0, |i| i + 1);
+
+    contents[..complete_len]
+        .lines()
+        .map(|line| decode_record(serde_json::from_str(line)?))
+        .collect()
+}
+
+/// Lists the files in a db directory, skipping temp files.
+fn list_json_db(root: &Path, chain: &str, sub_dir: &str) -> Result<Vec<String>> {
+    let files = read_json_dir(&root.join(chain).join(sub_dir))?;
+    Ok(files
+        .into_iter()
+        .filter(|file| !is_temp_file(file))
+        .collect())
+}
+
+fn read_json_dir(dir: &Path) -> Result<Vec<String>> {
+    let entries = match fs::read_dir(dir) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        entries => entries?,
+    };
+
+    let mut files = vec![];
+    for entry in entries {
+        files.push(entry?.file_name().to_string_lossy().into_owned());
+    }
+
+    Ok(files)
+}
+
+/// Temp files are named `<file>.<random>.temp` by `write_json_db`.
+fn is_temp_file(file: &str) -> bool {
+    file.ends_with(".temp")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn append_after_torn_line() -> Result<()> {
+        let root = std::env::temp_dir().join(format!("realtps-json-{}", std::process::id()));
+        let chain = Chain::try_from("solana")?;
+        let file = "test.jsonl";
+
+        append_json_line(&root, chain, file, &1u64)?;
+        let path = root.join(chain.to_string()).join(DB_DIR_META).join(file);
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&path)?
+            .write_all(b"{\"version\":1,\"da")?;
+        append_json_line(&root, chain, file, &2u64)?;
+
+        let records: Vec<u64> = read_json_lines(&root, chain, file)?
+            .into_iter()
+            .map(|decoded| decoded.record)
+            .collect();
+        fs::remove_dir_all(&root)?;
+        assert_eq!(records, vec![1, 2]);
+
+        Ok(())
+    }
+}
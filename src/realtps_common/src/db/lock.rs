@@ -0,0 +1,55 @@
+use super::*;
+use anyhow::{bail, Context};
+use fs2::FileExt;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+/// An advisory lock giving one process at a time the right to write a
+/// chain's data, held until dropped.
+///
+/// The lock is an exclusive `flock` on `<chain>.lock` in the db's lock
+/// directory, so the OS releases it if the process holding it dies.
+pub struct ChainLock {
+    chain: Chain,
+    _file: File,
+}
+
+impl ChainLock {
+    /// Takes the lock for `chain`, failing if another process holds it.
+    pub fn try_lock(lock_dir: &Path, chain: Chain) -> Result<ChainLock> {
+        fs::create_dir_all(lock_dir)?;
+
+        let path = lock_dir.join(format!("{}.lock", chain));
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .context(format!("unable to open lock file {}", path.display()))?;
+
+        if let Err(e) = file.try_lock_exclusive() {
+            if e.kind() == fs2::lock_contended_error().kind() {
+                let owner = fs::read_to_string(&path).unwrap_or_default();
+                bail!(
+                    "{} is locked by another process (pid {})",
+                    chain,
+                    owner.trim()
+                );
+            }
+            return Err(e).context(format!("unable to lock {}", path.display()));
+        }
+
+        // Record who holds the lock, for the error above
+        file.set_len(0)?;
+        write!(file, "{}", std::process::id())?;
+        file.flush()?;
+
+        Ok(ChainLock { chain, _file: file })
+    }
+
+    pub fn chain(&self) -> Chain {
+        self.chain
+    }
+}
@@ -0,0 +1,280 @@
+use super::*;
+use anyhow::anyhow;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// A `Db` that keeps everything in memory, for tests and dry runs.
+///
+/// A `MemoryDb` can be layered over another `Db` with `MemoryDb::over`.
+/// Reads of anything not written to the `MemoryDb` then fall through to the
+/// underlying `Db`, and writes never reach it.
+#[derive(Default)]
+pub struct MemoryDb {
+    chains: Mutex<HashMap<Chain, ChainData>>,
+    fallback: Option<Arc<dyn Db>>,
+}
+
+#[derive(Default)]
+struct ChainData {
+    blocks: BTreeMap<u64, Block>,
+    /// Blocks removed from the `MemoryDb` that may still be in the fallback.
+    removed_blocks: BTreeSet<u64>,
+    highest_block_number: Option<u64>,
+    finalized_block_number: Option<u64>,
+    tps: Option<f64>,
+    tps_history: Vec<TpsRecord>,
+    reorg_events: Vec<ReorgEvent>,
+    calculation_log: Option<CalculationLog>,
+}
+
+impl MemoryDb {
+    pub fn new() -> MemoryDb {
+        MemoryDb::default()
+    }
+
+    pub fn over(db: Arc<dyn Db>) -> MemoryDb {
+        MemoryDb {
+            chains: Default::default(),
+            fallback: Some(db),
+        }
+    }
+
+    /// The numbers of the blocks written to this `MemoryDb` for `chain`, in
+    /// ascending order. Doesn't include blocks only in the fallback `Db`.
+    pub fn block_numbers(&self, chain: Chain) -> Vec<u64> {
+        self.lock()
+            .get(&chain)
+            .map(|data| data.blocks.keys().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// The blocks written to this `MemoryDb` for `chain`, in ascending order.
+    pub fn blocks(&self, chain: Chain) -> Vec<Block> {
+        self.lock()
+            .get(&chain)
+            .map(|data| data.blocks.values().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<Chain, ChainData>> {
+        // Nothing can panic while the lock is held
+        self.chains.lock().expect("memory db poisoned")
+    }
+
+    fn with_chain<T>(&self, chain: Chain, f: impl FnOnce(&mut ChainData) -> T) -> T {
+        let mut chains = self.lock();
+        f(chains.entry(chain).or_default())
+    }
+
+    fn fallback<T>(&self, f: impl FnOnce(&dyn Db) -> Result<Option<T>>) -> Result<Option<T>> {
+        match self.fallback {
+            Some(ref db) => f(db.as_ref()),
+            None => Ok(None),
+        }
+    }
+}
+
+impl Db for MemoryDb {
+    fn store_block(&self, block: Block) -> Result<()> {
+        self.with_chain(block.chain, |data| {
+            data.removed_blocks.remove(&block.block_number);
+            data.blocks.insert(block.block_number, block);
+        });
+        Ok(())
+    }
+
+    fn load_block(&self, chain: Chain, block_number: u64) -> Result<Option<Block>> {
+        let (block, removed) = self.with_chain(chain, |data| {
+            (
+                data.blocks.get(&block_number).cloned(),
+                data.removed_blocks.contains(&block_number),
+            )
+        });
+        match (block, removed) {
+            (Some(block), _) => Ok(Some(block)),
+            (None, true) => Ok(None),
+            (None, false) => self.fallback(|db| db.load_block(chain, block_number)),
+        }
+    }
+
+    fn load_blocks_in_range(&self, chain: Chain, from: u64, to: u64) -> Result<Vec<Block>> {
+        if from > to {
+            return Ok(vec![]);
+        }
+        let fallback_blocks = self
+            .fallback(|db| db.load_blocks_in_range(chain, from, to).map(Some))?
+            .unwrap_or_default();
+        let blocks = self.with_chain(chain, |data| {
+            let mut blocks: BTreeMap<u64, Block> = fallback_blocks
+                .into_iter()
+                .filter(|block| !data.removed_blocks.contains(&block.block_number))
+                .map(|block| (block.block_number, block))
+                .collect();
+            for (block_number, block) in data.blocks.range(from..=to) {
+                blocks.insert(*block_number, block.clone());
+            }
+            blocks
+        });
+        Ok(blocks.into_values().collect())
+    }
+
+    fn store_highest_block_number(&self, chain: Chain, block_number: u64) -> Result<()> {
+        self.with_chain(chain, |data| data.highest_block_number = Some(block_number));
+        Ok(())
+    }
+
+    fn load_highest_block_number(&self, chain: Chain) -> Result<Option<u64>> {
+        match self.with_chain(chain, |data| data.highest_block_number) {
+            Some(block_number) => Ok(Some(block_number)),
+            None => self.fallback(|db| db.load_highest_block_number(chain)),
+        }
+    }
+
+    fn store_finalized_block_number(&self, chain: Chain, block_number: u64) -> Result<()> {
+        self.with_chain(chain, |data| {
+            data.finalized_block_number = Some(block_number)
+        });
+        Ok(())
+    }
+
+    fn load_finalized_block_number(&self, chain: Chain) -> Result<Option<u64>> {
+        match self.with_chain(chain, |data| data.finalized_block_number) {
+            Some(block_number) => Ok(Some(block_number)),
+            None => self.fallback(|db| db.load_finalized_block_number(chain)),
+        }
+    }
+
+    fn store_tps(&self, chain: Chain, tps: f64) -> Result<()> {
+        self.with_chain(chain, |data| data.tps = Some(tps));
+        Ok(())
+    }
+
+    fn load_tps(&self, chain: Chain) -> Result<Option<f64>> {
+        match self.with_chain(chain, |data| data.tps) {
+            Some(tps) => Ok(Some(tps)),
+            None => self.fallback(|db| db.load_tps(chain)),
+        }
+    }
+
+    fn append_tps_record(&self, chain: Chain, record: &TpsRecord) -> Result<()> {
+        let record = record.clone();
+        self.with_chain(chain, |data| data.tps_history.push(record));
+        Ok(())
+    }
+
+    fn load_tps_history(
+        &self,
+        chain: Chain,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Result<Vec<TpsRecord>> {
+        let mut records = self
+            .fallback(|db| db.load_tps_history(chain, from, to).map(Some))?
+            .unwrap_or_default();
+        self.with_chain(chain, |data| {
+            records.extend(
+                data.tps_history
+                    .iter()
+                    .filter(|record| record.calculated_at >= from && record.calculated_at <= to)
+                    .cloned(),
+            )
+        });
+        records.sort_by_key(|record| record.calculated_at);
+        Ok(records)
+    }
+
+    fn append_reorg_event(&self, event: &ReorgEvent) -> Result<()> {
+        let event = event.clone();
+        self.with_chain(event.chain, |data| data.reorg_events.push(event));
+        Ok(())
+    }
+
+    fn load_reorg_events(
+        &self,
+        chain: Chain,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Result<Vec<ReorgEvent>> {
+        let mut events = self
+            .fallback(|db| db.load_reorg_events(chain, from, to).map(Some))?
+            .unwrap_or_default();
+        self.with_chain(chain, |data| {
+            events.extend(
+                data.reorg_events
+                    .iter()
+                    .filter(|event| event.detected_at >= from && event.detected_at <= to)
+                    .cloned(),
+            )
+        });
+        events.sort_by_key(|event| event.detected_at);
+        Ok(events)
+    }
+
+    fn remove_block(&self, chain: Chain, block: u64) -> Result<()> {
+        let removed = self.with_chain(chain, |data| data.blocks.remove(&block).is_some());
+        let in_fallback = self.fallback(|db| db.load_block(chain, block))?.is_some();
+        if !removed && !in_fallback {
+            return Err(anyhow!("no block {} for chain {}", block, chain));
+        }
+        self.with_chain(chain, |data| data.removed_blocks.insert(block));
+        Ok(())
+    }
+
+    fn list_block_numbers(&self, chain: Chain) -> Result<Vec<u64>> {
+        let fallback_block_numbers = self
+            .fallback(|db| db.list_block_numbers(chain).map(Some))?
+            .unwrap_or_default();
+        let block_numbers = self.with_chain(chain, |data| {
+            let mut block_numbers: BTreeSet<u64> = fallback_block_numbers
+                .into_iter()
+                .filter(|block_number| !data.removed_blocks.contains(block_number))
+                .collect();
+            block_numbers.extend(data.blocks.keys());
+            block_numbers
+        });
+        Ok(block_numbers.into_iter().collect())
+    }
+
+    fn store_calculation_log(&self, chain: Chain, log: &CalculationLog) -> Result<()> {
+        let log = log.clone();
+        self.with_chain(chain, |data| data.calculation_log = Some(log));
+        Ok(())
+    }
+
+    fn load_calculation_log(&self, chain: Chain) -> Result<Option<CalculationLog>> {
+        match self.with_chain(chain, |data| data.calculation_log.clone()) {
+            Some(log) => Ok(Some(log)),
+            None => self.fallback(|db| db.load_calculation_log(chain)),
+        }
+    }
+
+    fn store_tps_and_calculation_log(
+        &self,
+        chain: Chain,
+        tps: f64,
+        log: &CalculationLog,
+    ) -> Result<()> {
+        let log = log.clone();
+        self.with_chain(chain, |data| {
+            data.tps = Some(tps);
+            data.calculation_log = Some(log);
+        });
+        Ok(())
+    }
+
+    fn load_tps_and_calculation_log(
+        &self,
+        chain: Chain,
+    ) -> Result<(Option<f64>, Option<CalculationLog>)> {
+        let tps_and_log = self.with_chain(chain, |data| {
+            data.tps.map(|tps| (tps, data.calculation_log.clone()))
+        });
+        match tps_and_log {
+            Some((tps, log)) => Ok((Some(tps), log)),
+            None => match self.fallback {
+                Some(ref db) => db.load_tps_and_calculation_log(chain),
+                None => Ok((None, None)),
+            },
+        }
+    }
+}
@@ -0,0 +1,249 @@
+use crate::chain::Chain;
+use anyhow::{bail, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+mod config;
+mod json;
+mod lock;
+mod memory;
+mod record;
+mod segment;
+mod sled;
+mod sqlite;
+
+pub use self::config::*;
+pub use self::json::*;
+pub use self::lock::*;
+pub use self::memory::*;
+pub use self::record::*;
+pub use self::segment::*;
+pub use self::sled::*;
+pub use self::sqlite::*;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Block {
+    pub chain: Chain,
+    pub block_number: u64,
+    /// The previous block number, not always block_number - 1, as in Solana,
+    /// where the "block" number is really a "slot" number, and slots may be
+    /// empty.
+    pub prev_block_number: Option<u64>,
+    pub timestamp: u64, // seconds since unix epoch
+    pub num_txs: u64,
+    pub hash: String,
+    // FIXME this could be None, like prev_block_number
+    pub parent_hash: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CalculationLog {
+    pub calculating_start: DateTime<Utc>,
+    pub calculating_end: DateTime<Utc>,
+    pub newest_block_timestamp: DateTime<Utc>,
+    pub oldest_block_timestamp: DateTime<Utc>,
+    /// How many of the newest blocks weren't finalized yet, and may still
+    /// be replaced by a reorg.
+    #[serde(default)]
+    pub unfinalized_blocks: u64,
+}
+
+/// The result of one TPS calculation, kept as a time series per chain.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TpsRecord {
+    pub calculated_at: DateTime<Utc>,
+    pub tps: f64,
+    /// The blocks the calculation covered.
+    pub oldest_block_number: u64,
+    pub newest_block_number: u64,
+    pub oldest_block_timestamp: DateTime<Utc>,
+    pub newest_block_timestamp: DateTime<Utc>,
+    pub num_txs: u64,
+}
+
+/// A reorg found while importing: stored blocks that turned out not to be on
+/// the chain, and were replaced.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ReorgEvent {
+    pub chain: Chain,
+    /// The highest replaced block.
+    pub block_number: u64,
+    /// The hash of the replaced block.
+    pub old_hash: String,
+    /// The hash of the block that replaced it.
+    pub new_hash: String,
+    /// How many blocks were replaced, from `block_number` down.
+    pub depth: u64,
+    pub detected_at: DateTime<Utc>,
+}
+
+pub trait Db: Send + Sync + 'static {
+    fn store_block(&self, block: Block) -> Result<()>;
+    fn load_block(&self, chain: Chain, block_number: u64) -> Result<Option<Block>>;
+
+    /// The stored blocks numbered from `from` to `to`, inclusive, in
+    /// ascending order.
+    ///
+    /// The default implementation loads every block number in the range.
+    fn load_blocks_in_range(&self, chain: Chain, from: u64, to: u64) -> Result<Vec<Block>> {
+        let mut blocks = vec![];
+        for block_number in from..=to {
+            if let Some(block) = self.load_block(chain, block_number)? {
+                blocks.push(block);
+            }
+        }
+        Ok(blocks)
+    }
+
+    /// The newest block with a timestamp at or before `timestamp`.
+    ///
+    /// The default implementation walks back from the highest known block.
+    fn find_block_at_or_before_timestamp(
+        &self,
+        chain: Chain,
+        timestamp: u64,
+    ) -> Result<Option<Block>> {
+        let mut next_block_number = self.load_highest_block_number(chain)?;
+        while let Some(block_number) = next_block_number {
+            let block = match self.load_block(chain, block_number)? {
+                Some(block) => block,
+                None => break,
+            };
+            if block.timestamp <= timestamp {
+                return Ok(Some(block));
+            }
+            next_block_number = block.prev_block_number;
+        }
+        Ok(None)
+    }
+
+    fn store_highest_block_number(&self, chain: Chain, block_number: u64) -> Result<()>;
+    fn load_highest_block_number(&self, chain: Chain) -> Result<Option<u64>>;
+
+    /// The highest block number that can no longer be replaced by a reorg.
+    ///
+    /// Stored blocks above it were imported before they were final. Chains
+    /// imported before finality was tracked have none.
+    fn store_finalized_block_number(&self, chain: Chain, block_number: u64) -> Result<()>;
+    fn load_finalized_block_number(&self, chain: Chain) -> Result<Option<u64>>;
+
+    fn store_tps(&self, chain: Chain, tps: f64) -> Result<()>;
+    fn load_tps(&self, chain: Chain) -> Result<Option<f64>>;
+
+    fn append_tps_record(&self, chain: Chain, record: &TpsRecord) -> Result<()>;
+    /// The records calculated between `from` and `to`, inclusive, oldest first.
+    fn load_tps_history(
+        &self,
+        chain: Chain,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Result<Vec<TpsRecord>>;
+
+    fn append_reorg_event(&self, event: &ReorgEvent) -> Result<()>;
+    /// The reorgs detected between `from` and `to`, inclusive, oldest first.
+    fn load_reorg_events(
+        &self,
+        chain: Chain,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Result<Vec<ReorgEvent>>;
+
+    fn remove_block(&self, chain: Chain, block: u64) -> Result<()>;
+
+    /// Removes every stored block numbered below `block_number`, returning
+    /// how many were removed.
+    fn remove_blocks_before(&self, chain: Chain, block_number: u64) -> Result<u64> {
+        let block_numbers = self.list_block_numbers(chain)?;
+        let mut num_removed = 0;
+        for block in block_numbers.into_iter().filter(|n| *n < block_number) {
+            self.remove_block(chain, block)?;
+            num_removed += 1;
+        }
+        Ok(num_removed)
+    }
+
+    /// The numbers of every block stored for `chain`, on the canonical chain
+    /// or not, in ascending order.
+    fn list_block_numbers(&self, chain: Chain) -> Result<Vec<u64>>;
+
+    fn store_calculation_log(&self, chain: Chain, log: &CalculationLog) -> Result<()>;
+
+    fn load_calculation_log(&self, chain: Chain) -> Result<Option<CalculationLog>>;
+
+    /// Stores the TPS and the log of the calculation that produced it.
+    ///
+    /// Backends that support it commit both in one atomic write, so that
+    /// `load_tps_and_calculation_log` never sees one without the other.
+    fn store_tps_and_calculation_log(
+        &self,
+        chain: Chain,
+        tps: f64,
+        log: &CalculationLog,
+    ) -> Result<()> {
+        self.store_calculation_log(chain, log)?;
+        self.store_tps(chain, tps)
+    }
+
+    /// Loads the TPS and the log of the calculation that produced it, as
+    /// written together by `store_tps_and_calculation_log`.
+    fn load_tps_and_calculation_log(
+        &self,
+        chain: Chain,
+    ) -> Result<(Option<f64>, Option<CalculationLog>)> {
+        Ok((self.load_tps(chain)?, self.load_calculation_log(chain)?))
+    }
+
+    /// Stores a batch of blocks.
+    ///
+    /// Backends that support it commit the batch in one write.
+    fn store_blocks(&self, blocks: Vec<Block>) -> Result<()> {
+        for block in blocks {
+            self.store_block(block)?;
+        }
+        Ok(())
+    }
+
+    /// Stores `blocks` and then the new highest block number.
+    ///
+    /// Backends that support it commit both in one atomic write, so the
+    /// highest block number never refers to blocks that weren't stored.
+    fn store_blocks_and_highest_block_number(
+        &self,
+        chain: Chain,
+        blocks: Vec<Block>,
+        highest_block_number: u64,
+    ) -> Result<()> {
+        self.store_blocks(blocks)?;
+        self.store_highest_block_number(chain, highest_block_number)
+    }
+
+    /// Rewrites any of `chain`'s records stored in an older format in the
+    /// current format, returning how many were rewritten.
+    ///
+    /// Backends that don't serialize records have nothing to upgrade.
+    fn upgrade_records(&self, _chain: Chain) -> Result<u64> {
+        Ok(0)
+    }
+
+    /// Files left behind by writes that never completed, e.g. because the
+    /// process crashed.
+    ///
+    /// Only backends that write through temporary files have any.
+    fn list_temp_files(&self, _chain: Chain) -> Result<Vec<PathBuf>> {
+        Ok(vec![])
+    }
+
+    fn remove_temp_file(&self, _chain: Chain, path: &Path) -> Result<()> {
+        bail!("no temp file {}", path.display())
+    }
+}
+
+pub static DB_DIR_BLOCKS: &str = "blocks";
+pub static DB_DIR_META: &str = "meta";
+pub static HIGHEST_BLOCK_NUMBER: &str = "highest_block_number";
+pub static FINALIZED_BLOCK_NUMBER: &str = "finalized_block_number";
+pub static TRANSACTIONS_PER_SECOND: &str = "tps";
+pub static TPS_HISTORY: &str = "tps_history";
+pub static REORGS: &str = "reorgs";
+pub static CALCULATION_LOG: &str = "calculation_log";
@@ -0,0 +1,146 @@
+use super::*;
+use anyhow::{anyhow, bail};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+/// A value that a `Db` serializes, e.g. to a file.
+///
+/// Records are stored inside an envelope carrying the version of their
+/// format, `{"version": 1, "data": ...}`, so that when the format changes,
+/// records written by older versions of realtps can still be read.
+///
+/// Version 0 is the unversioned format written before records had envelopes.
+/// Its data is the same as version 1.
+pub trait Record: Serialize + DeserializeOwned {
+    /// The version of the format written by this version of realtps.
+    const VERSION: u32;
+
+    /// Converts the data of a record from `version` to `version + 1`.
+    fn upgrade(version: u32, data: Value) -> Result<Value> {
+        match version {
+            0 => Ok(data),
+            _ => bail!("no upgrade from record version {}", version),
+        }
+    }
+}
+
+impl Record for Block {
+    const VERSION: u32 = 1;
+}
+
+impl Record for CalculationLog {
+    const VERSION: u32 = 1;
+}
+
+impl Record for TpsRecord {
+    const VERSION: u32 = 1;
+}
+
+impl Record for ReorgEvent {
+    const VERSION: u32 = 1;
+}
+
+// Highest block number
+impl Record for u64 {
+    const VERSION: u32 = 1;
+}
+
+// TPS
+impl Record for f64 {
+    const VERSION: u32 = 1;
+}
+
+#[derive(Serialize)]
+pub struct Envelope<'a, T> {
+    version: u32,
+    data: &'a T,
+}
+
+/// A record read from a `Db`, upgraded to the current version.
+pub struct Decoded<T> {
+    pub record: T,
+    /// The version the record was stored as.
+    pub version: u32,
+}
+
+impl<T: Record> Decoded<T> {
+    /// Whether the stored record needs to be rewritten in the current version.
+    pub fn is_outdated(&self) -> bool {
+        self.version < T::VERSION
+    }
+}
+
+/// Wraps a record in its versioned envelope, ready to be serialized.
+pub fn encode_record<T: Record>(record: &T) -> Envelope<'_, T> {
+    Envelope {
+        version: T::VERSION,
+        data: record,
+    }
+}
+
+/// Unwraps a record from its versioned envelope, upgrading it from older
+/// versions as needed.
+pub fn decode_record<T: Record>(value: Value) -> Result<Decoded<T>> {
+    let (stored_version, mut data) = match value {
+        Value::Object(mut map)
+            if map.len() == 2 && map.contains_key("version") && map.contains_key("data") =>
+        {
+            let version = map
+                .remove("version")
+                .and_then(|version| version.as_u64())
+                .ok_or_else(|| anyhow!("record version is not a number"))?;
+            let data = map.remove("data").expect("data");
+            (u32::try_from(version)?, data)
+        }
+        value => (0, value),
+    };
+
+    if stored_version > T::VERSION {
+        bail!(
+            "record version {} is newer than supported version {}",
+            stored_version,
+            T::VERSION
+        );
+    }
+
+    for version in stored_version..T::VERSION {
+        data = T::upgrade(version, data)?;
+    }
+
+    Ok(Decoded {
+        record: serde_json::from_value(data)?,
+        version: stored_version,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn decode_unversioned_record() -> Result<()> {
+        let decoded = decode_record::<u64>(json!(5))?;
+        assert_eq!(decoded.record, 5);
+        assert_eq!(decoded.version, 0);
+        assert!(decoded.is_outdated());
+        Ok(())
+    }
+
+    #[test]
+    fn roundtrip_record() -> Result<()> {
+        let value = serde_json::to_value(encode_record(&1.5f64))?;
+        assert_eq!(value, json!({ "version": 1, "data": 1.5 }));
+
+        let decoded = decode_record::<f64>(value)?;
+        assert_eq!(decoded.record, 1.5);
+        assert!(!decoded.is_outdated());
+        Ok(())
+    }
+
+    #[test]
+    fn reject_newer_record() {
+        let value = json!({ "version": 2, "data": 5 });
+        assert!(decode_record::<u64>(value).is_err());
+    }
+}
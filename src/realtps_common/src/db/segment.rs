@@ -0,0 +1,738 @@
+use super::*;
+use anyhow::{anyhow, Context};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// A `Db` storing blocks in append-only segment files, one per chain per hour
+/// of block timestamps, under `<root>/<chain>/segments`.
+///
+/// Each segment `<hour>.log` holds JSON block records, one per line, and has
+/// a sidecar `<hour>.idx` of fixed-size entries locating each block in it.
+/// Storing a block appends to both; removing one, or storing it again in
+/// another segment, appends a tombstone to its old segment's index. When the
+/// same block number was written more than once, the entry written last
+/// wins. A segment left without blocks is deleted, and
+/// `remove_blocks_before` deletes whole segments instead of removing blocks
+/// one at a time.
+///
+/// Meta values, TPS history and reorg events are stored as in a `JsonDb` at
+/// the same root.
+///
+/// A chain's index is read into memory the first time the chain is used, so
+/// a `SegmentDb` doesn't see blocks written by other processes after that.
+pub struct SegmentDb {
+    root: PathBuf,
+    meta: JsonDb,
+    chains: Mutex<HashMap<Chain, Arc<Mutex<ChainSegments>>>>,
+}
+
+pub static SEGMENT_DB_DIR: &str = "segments";
+
+static DB_DIR_SEGMENTS: &str = "segments";
+
+/// Block timestamps covered by one segment.
+const SEGMENT_SECONDS: u64 = 60 * 60;
+
+/// How many blocks `upgrade_records` reads at a time.
+const UPGRADE_BATCH_SIZE: usize = 1000;
+
+/// Block number, sequence number, offset, length.
+const INDEX_ENTRY_LEN: usize = 8 + 8 + 8 + 4;
+
+/// Where the latest write of a block is.
+#[derive(Copy, Clone)]
+struct Location {
+    segment: u64,
+    seq: u64,
+    offset: u64,
+    /// Zero for a removed block
+    len: u32,
+}
+
+#[derive(Default)]
+struct ChainSegments {
+    dir: PathBuf,
+    /// Every stored block
+    blocks: BTreeMap<u64, Location>,
+    /// The stored blocks in each segment, empty for a segment whose blocks
+    /// were all removed or moved before it was loaded
+    segments: BTreeMap<u64, BTreeSet<u64>>,
+    next_seq: u64,
+    /// The segment last written to, kept open for appending
+    writer: Option<SegmentWriter>,
+}
+
+/// The entries of a segment's index that can be trusted.
+#[derive(Default)]
+struct SegmentIndex {
+    entries: Vec<(u64, Location)>,
+    /// The length of the index they take up
+    idx_len: u64,
+    /// The end of the last record they point at in the log
+    log_len: u64,
+}
+
+struct SegmentWriter {
+    segment: u64,
+    log: File,
+    log_len: u64,
+    idx: File,
+}
+
+impl SegmentDb {
+    pub fn new<P: AsRef<Path>>(root: P) -> SegmentDb {
+        SegmentDb {
+            root: root.as_ref().to_path_buf(),
+            meta: JsonDb::new(root),
+            chains: Default::default(),
+        }
+    }
+
+    fn chain(&self, chain: Chain) -> Result<Arc<Mutex<ChainSegments>>> {
+        let mut chains = self
+            .chains
+            .lock()
+            .map_err(|_| anyhow!("segment db poisoned"))?;
+        if let Some(segments) = chains.get(&chain) {
+            return Ok(segments.clone());
+        }
+
+        let dir = self.root.join(chain.to_string()).join(DB_DIR_SEGMENTS);
+        let segments = ChainSegments::load(dir)
+            .context(format!("unable to load segment index for {}", chain))?;
+        let segments = Arc::new(Mutex::new(segments));
+        chains.insert(chain, segments.clone());
+        Ok(segments)
+    }
+
+    fn with_chain<T>(
+        &self,
+        chain: Chain,
+        f: impl FnOnce(&mut ChainSegments) -> Result<T>,
+    ) -> Result<T> {
+        let segments = self.chain(chain)?;
+        let mut segments = lock(&segments)?;
+        f(&mut segments)
+    }
+}
+
+impl Default for SegmentDb {
+    fn default() -> SegmentDb {
+        SegmentDb::new(SEGMENT_DB_DIR)
+    }
+}
+
+impl Db for SegmentDb {
+    fn store_block(&self, block: Block) -> Result<()> {
+        self.with_chain(block.chain, |segments| segments.append_block(&block))
+    }
+
+    fn load_block(&self, chain: Chain, block_number: u64) -> Result<Option<Block>> {
+        self.with_chain(chain, |segments| {
+            match segments.blocks.get(&block_number).cloned() {
+                Some(location) => Ok(Some(segments.read_block(location)?)),
+                None => Ok(None),
+            }
+        })
+    }
+
+    fn load_blocks_in_range(&self, chain: Chain, from: u64, to: u64) -> Result<Vec<Block>> {
+        if from > to {
+            return Ok(vec![]);
+        }
+        self.with_chain(chain, |segments| {
+            let locations: Vec<Location> =
+                segments.blocks.range(from..=to).map(|(_, l)| *l).collect();
+            segments.read_blocks(&locations)
+        })
+    }
+
+    // Block timestamps increase with block numbers, so this is a binary
+    // search over the index.
+    fn find_block_at_or_before_timestamp(
+        &self,
+        chain: Chain,
+        timestamp: u64,
+    ) -> Result<Option<Block>> {
+        self.with_chain(chain, |segments| {
+            let locations: Vec<Location> = segments.blocks.values().cloned().collect();
+
+            // Blocks before `low` are at or before `timestamp`; blocks from
+            // `high` on are after it.
+            let (mut low, mut high) = (0, locations.len());
+            while low < high {
+                let mid = low + (high - low) / 2;
+                if segments.read_block(locations[mid])?.timestamp <= timestamp {
+                    low = mid + 1;
+                } else {
+                    high = mid;
+                }
+            }
+
+            match low {
+                0 => Ok(None),
+                low => Ok(Some(segments.read_block(locations[low - 1])?)),
+            }
+        })
+    }
+
+    fn store_highest_block_number(&self, chain: Chain, block_number: u64) -> Result<()> {
+        self.meta.store_highest_block_number(chain, block_number)
+    }
+
+    fn load_highest_block_number(&self, chain: Chain) -> Result<Option<u64>> {
+        self.meta.load_highest_block_number(chain)
+    }
+
+    fn store_finalized_block_number(&self, chain: Chain, block_number: u64) -> Result<()> {
+        self.meta.store_finalized_block_number(chain, block_number)
+    }
+
+    fn load_finalized_block_number(&self, chain: Chain) -> Result<Option<u64>> {
+        self.meta.load_finalized_block_number(chain)
+    }
+
+    fn store_tps(&self, chain: Chain, tps: f64) -> Result<()> {
+        self.meta.store_tps(chain, tps)
+    }
+
+    fn load_tps(&self, chain: Chain) -> Result<Option<f64>> {
+        self.meta.load_tps(chain)
+    }
+
+    fn append_tps_record(&self, chain: Chain, record: &TpsRecord) -> Result<()> {
+        self.meta.append_tps_record(chain, record)
+    }
+
+    fn load_tps_history(
+        &self,
+        chain: Chain,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Result<Vec<TpsRecord>> {
+        self.meta.load_tps_history(chain, from, to)
+    }
+
+    fn append_reorg_event(&self, event: &ReorgEvent) -> Result<()> {
+        self.meta.append_reorg_event(event)
+    }
+
+    fn load_reorg_events(
+        &self,
+        chain: Chain,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Result<Vec<ReorgEvent>> {
+        self.meta.load_reorg_events(chain, from, to)
+    }
+
+    fn remove_block(&self, chain: Chain, block: u64) -> Result<()> {
+        self.with_chain(chain, |segments| segments.remove_block(block))
+    }
+
+    fn remove_blocks_before(&self, chain: Chain, block_number: u64) -> Result<u64> {
+        self.with_chain(chain, |segments| {
+            segments.remove_blocks_before(block_number)
+        })
+    }
+
+    fn list_block_numbers(&self, chain: Chain) -> Result<Vec<u64>> {
+        self.with_chain(chain, |segments| {
+            Ok(segments.blocks.keys().cloned().collect())
+        })
+    }
+
+    fn store_calculation_log(&self, chain: Chain, log: &CalculationLog) -> Result<()> {
+        self.meta.store_calculation_log(chain, log)
+    }
+
+    fn load_calculation_log(&self, chain: Chain) -> Result<Option<CalculationLog>> {
+        self.meta.load_calculation_log(chain)
+    }
+
+    fn store_tps_and_calculation_log(
+        &self,
+        chain: Chain,
+        tps: f64,
+        log: &CalculationLog,
+    ) -> Result<()> {
+        self.meta.store_tps_and_calculation_log(chain, tps, log)
+    }
+
+    fn load_tps_and_calculation_log(
+        &self,
+        chain: Chain,
+    ) -> Result<(Option<f64>, Option<CalculationLog>)> {
+        self.meta.load_tps_and_calculation_log(chain)
+    }
+
+    fn upgrade_records(&self, chain: Chain) -> Result<u64> {
+        let mut num_upgraded = self.meta.upgrade_records(chain)?;
+
+        self.with_chain(chain, |segments| {
+            let locations: Vec<Location> = segments.blocks.values().cloned().collect();
+            for locations in locations.chunks(UPGRADE_BATCH_SIZE) {
+                for decoded in segments.read_blocks_decoded(locations)? {
+                    if decoded.is_outdated() {
+                        segments.append_block(&decoded.record)?;
+                        num_upgraded += 1;
+                    }
+                }
+            }
+            Ok(())
+        })?;
+
+        Ok(num_upgraded)
+    }
+
+    fn list_temp_files(&self, chain: Chain) -> Result<Vec<PathBuf>> {
+        self.meta.list_temp_files(chain)
+    }
+
+    fn remove_temp_file(&self, chain: Chain, path: &Path) -> Result<()> {
+        self.meta.remove_temp_file(chain, path)
+    }
+}
+
+impl ChainSegments {
+    /// Reads every segment index in `dir`.
+    fn load(dir: PathBuf) -> Result<ChainSegments> {
+        let mut segments = ChainSegments {
+            dir,
+            ..Default::default()
+        };
+
+        let entries = match fs::read_dir(&segments.dir) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(segments),
+            entries => entries?,
+        };
+
+        let mut latest: BTreeMap<u64, Location> = BTreeMap::new();
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension() != Some("idx".as_ref()) {
+                continue;
+            }
+            let segment = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse().ok())
+                .ok_or_else(|| anyhow!("unexpected segment index {}", path.display()))?;
+            segments.segments.entry(segment).or_default();
+
+            for (block_number, location) in segments.read_index(segment)?.entries {
+                segments.next_seq = segments.next_seq.max(location.seq + 1);
+                match latest.get(&block_number) {
+                    Some(prev) if prev.seq > location.seq => {}
+                    _ => {
+                        latest.insert(block_number, location);
+                    }
+                }
+            }
+        }
+
+        for (block_number, location) in latest {
+            if location.len != 0 {
+                segments.insert(block_number, location);
+            }
+        }
+
+        Ok(segments)
+    }
+
+    /// Reads a segment's index, up to the first entry torn, or pointing past
+    /// the end of the log, by a write interrupted by a crash.
+    fn read_index(&self, segment: u64) -> Result<SegmentIndex> {
+        let log_len = match fs::metadata(self.log_path(segment)) {
+            Ok(metadata) => metadata.len(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e.into()),
+        };
+        let index = match fs::read(self.idx_path(segment)) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => vec![],
+            index => index?,
+        };
+
+        let mut segment_index = SegmentIndex::default();
+        for index_entry in index.chunks_exact(INDEX_ENTRY_LEN) {
+            let (block_number, location) = decode_index_entry(segment, index_entry);
+            let record_end = location.offset + u64::from(location.len);
+            if record_end > log_len {
+                break;
+            }
+            segment_index.entries.push((block_number, location));
+            segment_index.idx_len += INDEX_ENTRY_LEN as u64;
+            segment_index.log_len = segment_index.log_len.max(record_end);
+        }
+
+        Ok(segment_index)
+    }
+
+    fn log_path(&self, segment: u64) -> PathBuf {
+        self.dir.join(format!("{}.log", segment))
+    }
+
+    fn idx_path(&self, segment: u64) -> PathBuf {
+        self.dir.join(format!("{}.idx", segment))
+    }
+
+    fn insert(&mut self, block_number: u64, location: Location) {
+        self.forget(block_number);
+        self.segments
+            .entry(location.segment)
+            .or_default()
+            .insert(block_number);
+        self.blocks.insert(block_number, location);
+    }
+
+    fn forget(&mut self, block_number: u64) -> Option<Location> {
+        let location = self.blocks.remove(&block_number)?;
+        if let Some(blocks) = self.segments.get_mut(&location.segment) {
+            blocks.remove(&block_number);
+        }
+        Some(location)
+    }
+
+    fn writer(&mut self, segment: u64) -> Result<&mut SegmentWriter> {
+        let is_open = matches!(self.writer, Some(ref writer) if writer.segment == segment);
+        if !is_open {
+            fs::create_dir_all(&self.dir)?;
+
+            // Cut off whatever a crash left after the last complete write, so
+            // new entries and records start on a boundary
+            let index = self.read_index(segment)?;
+            let open = |path: PathBuf, len: u64| -> Result<File> {
+                let mut file = OpenOptions::new()
+                    .create(true)
+                    .write(true)
+                    .truncate(false)
+                    .open(path)?;
+                file.set_len(len)?;
+                file.seek(SeekFrom::Start(len))?;
+                Ok(file)
+            };
+            let log = open(self.log_path(segment), index.log_len)?;
+            let idx = open(self.idx_path(segment), index.idx_len)?;
+            self.writer = Some(SegmentWriter {
+                segment,
+                log,
+                log_len: index.log_len,
+                idx,
+            });
+        }
+        Ok(self.writer.as_mut().expect("writer"))
+    }
+
+    fn append_block(&mut self, block: &Block) -> Result<()> {
+        let mut record = serde_json::to_vec(&encode_record(block))?;
+        record.push(b'\n');
+        let len = u32::try_from(record.len())?;
+
+        let segment = block.timestamp / SEGMENT_SECONDS * SEGMENT_SECONDS;
+
+        // A block moving to another segment, e.g. after a reorg, leaves a
+        // tombstone behind, so the old write can't come back if the new
+        // segment is deleted first
+        let moved_from = match self.blocks.get(&block.block_number) {
+            Some(prev) if prev.segment != segment => Some(prev.segment),
+            _ => None,
+        };
+        if let Some(prev_segment) = moved_from {
+            self.append_tombstone(block.block_number, prev_segment)?;
+        }
+
+        let seq = self.next_seq;
+
+        // The record goes in the log before its index entry, so the index
+        // never points at a record that wasn't written
+        let writer = self.writer(segment)?;
+        let offset = writer.log_len;
+        writer.log.write_all(&record)?;
+        writer.log_len += u64::from(len);
+
+        let location = Location {
+            segment,
+            seq,
+            offset,
+            len,
+        };
+        writer
+            .idx
+            .write_all(&encode_index_entry(block.block_number, location))?;
+
+        self.next_seq += 1;
+        self.insert(block.block_number, location);
+
+        if let Some(prev_segment) = moved_from {
+            self.delete_segment_if_empty(prev_segment)?;
+        }
+
+        Ok(())
+    }
+
+    fn remove_block(&mut self, block_number: u64) -> Result<()> {
+        let location = self
+            .forget(block_number)
+            .ok_or_else(|| anyhow!("no block {}", block_number))?;
+
+        self.append_tombstone(block_number, location.segment)?;
+        self.delete_segment_if_empty(location.segment)
+    }
+
+    fn append_tombstone(&mut self, block_number: u64, segment: u64) -> Result<()> {
+        let tombstone = Location {
+            segment,
+            seq: self.next_seq,
+            offset: 0,
+            len: 0,
+        };
+        self.writer(segment)?
+            .idx
+            .write_all(&encode_index_entry(block_number, tombstone))?;
+        self.next_seq += 1;
+
+        Ok(())
+    }
+
+    /// Deletes segments holding only blocks below `block_number`, and
+    /// removes the rest of those blocks one at a time.
+    fn remove_blocks_before(&mut self, block_number: u64) -> Result<u64> {
+        let old_blocks: Vec<u64> = self
+            .blocks
+            .range(..block_number)
+            .map(|(block_number, _)| *block_number)
+            .collect();
+
+        let old_segments: Vec<u64> = self
+            .segments
+            .iter()
+            .filter(|(_, blocks)| blocks.iter().all(|n| *n < block_number))
+            .map(|(segment, _)| *segment)
+            .collect();
+
+        let mut num_removed = 0;
+
+        for segment in old_segments {
+            num_removed += self.delete_segment(segment)?;
+        }
+
+        for block in old_blocks {
+            if self.blocks.contains_key(&block) {
+                self.remove_block(block)?;
+                num_removed += 1;
+            }
+        }
+
+        Ok(num_removed)
+    }
+
+    /// Deletes a segment's files, forgetting the blocks in it, and returns
+    /// how many there were.
+    ///
+    /// Tombstones are written to the segment holding the write they cover,
+    /// so deleting a whole segment can't bring back blocks from another.
+    fn delete_segment(&mut self, segment: u64) -> Result<u64> {
+        if matches!(self.writer, Some(ref writer) if writer.segment == segment) {
+            self.writer = None;
+        }
+        let blocks = self.segments.remove(&segment).unwrap_or_default();
+        for block in &blocks {
+            self.blocks.remove(block);
+        }
+
+        // The index goes first, so a crash can't leave it pointing at a
+        // missing log
+        remove_file_if_exists(&self.idx_path(segment))?;
+        remove_file_if_exists(&self.log_path(segment))?;
+
+        Ok(blocks.len() as u64)
+    }
+
+    /// Deletes the segment once the last of its blocks is removed or moved,
+    /// rather than keeping its files until `remove_blocks_before` reaches it.
+    fn delete_segment_if_empty(&mut self, segment: u64) -> Result<()> {
+        if matches!(self.segments.get(&segment), Some(blocks) if blocks.is_empty()) {
+            self.delete_segment(segment)?;
+        }
+        Ok(())
+    }
+
+    fn read_block(&self, location: Location) -> Result<Block> {
+        Ok(self.read_block_decoded(location)?.record)
+    }
+
+    fn read_block_decoded(&self, location: Location) -> Result<Decoded<Block>> {
+        let mut log = File::open(self.log_path(location.segment))?;
+        read_record(&mut log, location)
+    }
+
+    fn read_blocks(&self, locations: &[Location]) -> Result<Vec<Block>> {
+        let blocks = self.read_blocks_decoded(locations)?;
+        Ok(blocks.into_iter().map(|decoded| decoded.record).collect())
+    }
+
+    /// Reads the blocks at `locations`, opening each segment's log once.
+    fn read_blocks_decoded(&self, locations: &[Location]) -> Result<Vec<Decoded<Block>>> {
+        let mut log: Option<(u64, File)> = None;
+        let mut blocks = Vec::with_capacity(locations.len());
+        for location in locations {
+            let file = match log {
+                Some((segment, ref mut file)) if segment == location.segment => file,
+                _ => {
+                    let file = File::open(self.log_path(location.segment))?;
+                    &mut log.insert((location.segment, file)).1
+                }
+            };
+            blocks.push(read_record(file, *location)?);
+        }
+        Ok(blocks)
+    }
+}
+
+fn read_record(log: &mut File, location: Location) -> Result<Decoded<Block>> {
+    log.seek(SeekFrom::Start(location.offset))?;
+    let mut record = vec![0; location.len as usize];
+    log.read_exact(&mut record)?;
+    decode_record(serde_json::from_slice(&record)?)
+}
+
+fn encode_index_entry(block_number: u64, location: Location) -> [u8; INDEX_ENTRY_LEN] {
+    let mut entry = [0; INDEX_ENTRY_LEN];
+    entry[0..8].copy_from_slice(&block_number.to_be_bytes());
+    entry[8..16].copy_from_slice(&location.seq.to_be_bytes());
+    entry[16..24].copy_from_slice(&location.offset.to_be_bytes());
+    entry[24..28].copy_from_slice(&location.len.to_be_bytes());
+    entry
+}
+
+fn decode_index_entry(segment: u64, entry: &[u8]) -> (u64, Location) {
+    let u64_at = |i: usize| u64::from_be_bytes(entry[i..i + 8].try_into().expect("u64"));
+    let len = u32::from_be_bytes(entry[24..28].try_into().expect("u32"));
+    let location = Location {
+        segment,
+        seq: u64_at(8),
+        offset: u64_at(16),
+        len,
+    };
+    (u64_at(0), location)
+}
+
+fn remove_file_if_exists(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        r => Ok(r?),
+    }
+}
+
+fn lock(segments: &Mutex<ChainSegments>) -> Result<MutexGuard<'_, ChainSegments>> {
+    segments.lock().map_err(|_| anyhow!("segment db poisoned"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn block(block_number: u64, timestamp: u64, hash: &str) -> Block {
+        Block {
+            chain: Chain::try_from("solana").expect("chain"),
+            block_number,
+            prev_block_number: block_number.checked_sub(1),
+            timestamp,
+            num_txs: 1,
+            hash: hash.to_string(),
+            parent_hash: String::new(),
+        }
+    }
+
+    fn hashes(db: &SegmentDb, chain: Chain) -> Result<Vec<String>> {
+        let blocks = db.load_blocks_in_range(chain, 0, u64::MAX)?;
+        Ok(blocks.into_iter().map(|block| block.hash).collect())
+    }
+
+    #[test]
+    fn append_after_torn_writes() -> Result<()> {
+        let root = std::env::temp_dir().join(format!("realtps-segment-{}", std::process::id()));
+        let chain = Chain::try_from("solana")?;
+        let segments = root.join(chain.to_string()).join(DB_DIR_SEGMENTS);
+
+        {
+            let db = SegmentDb::new(&root);
+            db.store_block(block(1, 10, "a1"))?;
+            db.store_block(block(2, 20, "a2"))?;
+        }
+
+        // A crash tore the next record and its index entry
+        let append = |file: &str, bytes: &[u8]| -> Result<()> {
+            let mut file = OpenOptions::new().append(true).open(segments.join(file))?;
+            Ok(file.write_all(bytes)?)
+        };
+        append("0.log", b"{\"version\":1,\"da")?;
+        append("0.idx", &[0, 0, 0, 3])?;
+
+        {
+            let db = SegmentDb::new(&root);
+            db.store_block(block(3, 30, "a3"))?;
+        }
+
+        let db = SegmentDb::new(&root);
+        let result = hashes(&db, chain);
+        fs::remove_dir_all(&root)?;
+        assert_eq!(result?, vec!["a1", "a2", "a3"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn deleted_segment_does_not_bring_back_blocks() -> Result<()> {
+        let root = std::env::temp_dir().join(format!("realtps-segment-del-{}", std::process::id()));
+        let chain = Chain::try_from("solana")?;
+
+        {
+            let db = SegmentDb::new(&root);
+            db.store_block(block(1, 10, "a1"))?;
+            db.store_block(block(2, SEGMENT_SECONDS + 10, "a2"))?;
+            db.store_block(block(3, SEGMENT_SECONDS + 20, "a3"))?;
+            // Block 2 moves to the first segment, which is then deleted,
+            // while its old write stays in the second
+            db.store_block(block(2, 20, "b2"))?;
+            assert_eq!(db.remove_blocks_before(chain, 3)?, 2);
+        }
+
+        let db = SegmentDb::new(&root);
+        let result = hashes(&db, chain);
+        fs::remove_dir_all(&root)?;
+        assert_eq!(result?, vec!["a3"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn emptied_segment_is_deleted() -> Result<()> {
+        let root =
+            std::env::temp_dir().join(format!("realtps-segment-empty-{}", std::process::id()));
+        let chain = Chain::try_from("solana")?;
+        let segments = root.join(chain.to_string()).join(DB_DIR_SEGMENTS);
+
+        let db = SegmentDb::new(&root);
+        db.store_block(block(1, 10, "a1"))?;
+        db.store_block(block(2, SEGMENT_SECONDS + 10, "a2"))?;
+        // Block 1 moves out of the first segment, and block 2 is removed
+        // from the second
+        db.store_block(block(1, 2 * SEGMENT_SECONDS + 10, "b1"))?;
+        db.remove_block(chain, 2)?;
+
+        let result = (
+            segments.join("0.idx").exists() || segments.join("0.log").exists(),
+            segments.join(format!("{}.idx", SEGMENT_SECONDS)).exists(),
+            hashes(&SegmentDb::new(&root), chain),
+        );
+        fs::remove_dir_all(&root)?;
+        assert_eq!((result.0, result.1), (false, false));
+        assert_eq!(result.2?, vec!["b1"]);
+
+        Ok(())
+    }
+}
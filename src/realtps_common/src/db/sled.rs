@@ -0,0 +1,432 @@
+use super::*;
+use ::sled::{transaction::TransactionResult, Batch, Transactional, Tree};
+use anyhow::{anyhow, Context};
+use std::path::Path;
+
+/// A `Db` stored in an embedded [sled] key-value store.
+///
+/// Blocks are keyed by `<chain>/<big-endian block number>`, so all of a
+/// chain's blocks are contiguous and ordered by block number. Meta values are
+/// stored in a separate tree, keyed by `<chain>/<name>`, TPS history in
+/// another, keyed by `<chain>/<big-endian calculation time in milliseconds>`,
+/// and reorg events in another, keyed by `<chain>/<big-endian detection time
+/// in milliseconds><big-endian block number>`. All are stored as versioned
+/// JSON records.
+///
+/// sled takes an exclusive lock on its directory, so only one process at a
+/// time can open a `SledDb`.
+///
+/// [sled]: https://sled.rs
+pub struct SledDb {
+    db: ::sled::Db,
+    blocks: Tree,
+    meta: Tree,
+    tps_history: Tree,
+    reorgs: Tree,
+}
+
+pub static SLED_DB_PATH: &str = "realtps.sled";
+
+static TREE_BLOCKS: &str = "blocks";
+static TREE_META: &str = "meta";
+static TREE_TPS_HISTORY: &str = "tps_history";
+static TREE_REORGS: &str = "reorgs";
+
+impl SledDb {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<SledDb> {
+        let path = path.as_ref();
+        let db = ::sled::open(path).with_context(|| {
+            format!(
+                "unable to open sled db {}; is another process using it?",
+                path.display()
+            )
+        })?;
+        let blocks = db.open_tree(TREE_BLOCKS)?;
+        let meta = db.open_tree(TREE_META)?;
+        let tps_history = db.open_tree(TREE_TPS_HISTORY)?;
+        let reorgs = db.open_tree(TREE_REORGS)?;
+
+        Ok(SledDb {
+            db,
+            blocks,
+            meta,
+            tps_history,
+            reorgs,
+        })
+    }
+
+    fn store_meta<T>(&self, chain: Chain, key: &str, value: &T) -> Result<()>
+    where
+        T: Record,
+    {
+        self.meta.insert(meta_key(chain, key), encode(value)?)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn load_meta<T>(&self, chain: Chain, key: &str) -> Result<Option<T>>
+    where
+        T: Record,
+    {
+        match self.meta.get(meta_key(chain, key))? {
+            Some(value) => Ok(Some(decode::<T>(&value)?.record)),
+            None => Ok(None),
+        }
+    }
+
+    /// The stored block with the highest number at or before `block_number`.
+    fn load_block_at_or_before(&self, chain: Chain, block_number: u64) -> Result<Option<Block>> {
+        let range = chain_prefix(chain)..=block_key(chain, block_number);
+        match self.blocks.range(range).values().next_back() {
+            Some(value) => Ok(Some(decode::<Block>(&value?)?.record)),
+            None => Ok(None),
+        }
+    }
+
+    /// Rewrites the value at `key` in the current record version if it is outdated.
+    fn upgrade_value<T>(tree: &Tree, key: &[u8]) -> Result<bool>
+    where
+        T: Record,
+    {
+        match tree.get(key)? {
+            Some(value) => {
+                let decoded = decode::<T>(&value)?;
+                if decoded.is_outdated() {
+                    tree.insert(key, encode(&decoded.record)?)?;
+                    Ok(true)
+                } else {
+                    Ok(false)
+                }
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+impl Db for SledDb {
+    fn store_block(&self, block: Block) -> Result<()> {
+        self.blocks
+            .insert(block_key(block.chain, block.block_number), encode(&block)?)?;
+        Ok(())
+    }
+
+    fn load_block(&self, chain: Chain, block_number: u64) -> Result<Option<Block>> {
+        match self.blocks.get(block_key(chain, block_number))? {
+            Some(value) => Ok(Some(decode::<Block>(&value)?.record)),
+            None => Ok(None),
+        }
+    }
+
+    fn load_blocks_in_range(&self, chain: Chain, from: u64, to: u64) -> Result<Vec<Block>> {
+        if from > to {
+            return Ok(vec![]);
+        }
+        let range = block_key(chain, from)..=block_key(chain, to);
+        let mut blocks = vec![];
+        for value in self.blocks.range(range).values() {
+            blocks.push(decode::<Block>(&value?)?.record);
+        }
+        Ok(blocks)
+    }
+
+    // Block timestamps increase with block numbers, so this is a binary
+    // search over the block numbers between the oldest and newest block.
+    fn find_block_at_or_before_timestamp(
+        &self,
+        chain: Chain,
+        timestamp: u64,
+    ) -> Result<Option<Block>> {
+        let mut blocks = self.blocks.scan_prefix(chain_prefix(chain)).values();
+        let oldest_block = match blocks.next() {
+            Some(value) => decode::<Block>(&value?)?.record,
+            None => return Ok(None),
+        };
+        if oldest_block.timestamp > timestamp {
+            return Ok(None);
+        }
+        let newest_block = match blocks.next_back() {
+            Some(value) => decode::<Block>(&value?)?.record,
+            None => return Ok(Some(oldest_block)),
+        };
+        if newest_block.timestamp <= timestamp {
+            return Ok(Some(newest_block));
+        }
+
+        // The newest block at or before `low` is at or before `timestamp`;
+        // the one at or before `high` is after it.
+        let (mut low, mut high) = (oldest_block.block_number, newest_block.block_number);
+        while high - low > 1 {
+            let mid = low + (high - low) / 2;
+            let block = self
+                .load_block_at_or_before(chain, mid)?
+                .ok_or_else(|| anyhow!("block {} for {} disappeared", mid, chain))?;
+            if block.timestamp <= timestamp {
+                low = mid;
+            } else {
+                high = mid;
+            }
+        }
+
+        self.load_block_at_or_before(chain, low)
+    }
+
+    fn store_highest_block_number(&self, chain: Chain, block_number: u64) -> Result<()> {
+        self.store_meta(chain, HIGHEST_BLOCK_NUMBER, &block_number)
+    }
+
+    fn load_highest_block_number(&self, chain: Chain) -> Result<Option<u64>> {
+        self.load_meta(chain, HIGHEST_BLOCK_NUMBER)
+    }
+
+    fn store_finalized_block_number(&self, chain: Chain, block_number: u64) -> Result<()> {
+        self.store_meta(chain, FINALIZED_BLOCK_NUMBER, &block_number)
+    }
+
+    fn load_finalized_block_number(&self, chain: Chain) -> Result<Option<u64>> {
+        self.load_meta(chain, FINALIZED_BLOCK_NUMBER)
+    }
+
+    fn store_tps(&self, chain: Chain, tps: f64) -> Result<()> {
+        self.store_meta(chain, TRANSACTIONS_PER_SECOND, &tps)
+    }
+
+    fn load_tps(&self, chain: Chain) -> Result<Option<f64>> {
+        self.load_meta(chain, TRANSACTIONS_PER_SECOND)
+    }
+
+    fn append_tps_record(&self, chain: Chain, record: &TpsRecord) -> Result<()> {
+        self.tps_history.insert(
+            tps_history_key(chain, record.calculated_at)?,
+            encode(record)?,
+        )?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn load_tps_history(
+        &self,
+        chain: Chain,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Result<Vec<TpsRecord>> {
+        let range = tps_history_key(chain, from)?..=tps_history_key(chain, to)?;
+        let mut records = vec![];
+        for value in self.tps_history.range(range).values() {
+            records.push(decode::<TpsRecord>(&value?)?.record);
+        }
+        Ok(records)
+    }
+
+    fn append_reorg_event(&self, event: &ReorgEvent) -> Result<()> {
+        let key = reorg_key(event.chain, event.detected_at, event.block_number)?;
+        self.reorgs.insert(key, encode(event)?)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn load_reorg_events(
+        &self,
+        chain: Chain,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Result<Vec<ReorgEvent>> {
+        let range = reorg_key(chain, from, 0)?..=reorg_key(chain, to, u64::MAX)?;
+        let mut events = vec![];
+        for value in self.reorgs.range(range).values() {
+            events.push(decode::<ReorgEvent>(&value?)?.record);
+        }
+        Ok(events)
+    }
+
+    fn remove_block(&self, chain: Chain, block: u64) -> Result<()> {
+        self.blocks.remove(block_key(chain, block))?;
+        Ok(())
+    }
+
+    fn remove_blocks_before(&self, chain: Chain, block_number: u64) -> Result<u64> {
+        let mut batch = Batch::default();
+        let mut num_removed = 0;
+        for key in self
+            .blocks
+            .range(block_key(chain, 0)..block_key(chain, block_number))
+            .keys()
+        {
+            batch.remove(key?);
+            num_removed += 1;
+        }
+        self.blocks.apply_batch(batch)?;
+        self.db.flush()?;
+        Ok(num_removed)
+    }
+
+    fn list_block_numbers(&self, chain: Chain) -> Result<Vec<u64>> {
+        let prefix_len = chain_prefix(chain).len();
+        let mut block_numbers = vec![];
+        for key in self.blocks.scan_prefix(chain_prefix(chain)).keys() {
+            let key = key?;
+            let block_number = key[prefix_len..]
+                .try_into()
+                .map_err(|_| anyhow!("malformed block key for {}", chain))?;
+            block_numbers.push(u64::from_be_bytes(block_number));
+        }
+        // Big-endian keys are already in ascending order
+        Ok(block_numbers)
+    }
+
+    fn store_calculation_log(&self, chain: Chain, log: &CalculationLog) -> Result<()> {
+        self.store_meta(chain, CALCULATION_LOG, log)
+    }
+
+    fn load_calculation_log(&self, chain: Chain) -> Result<Option<CalculationLog>> {
+        self.load_meta(chain, CALCULATION_LOG)
+    }
+
+    fn store_tps_and_calculation_log(
+        &self,
+        chain: Chain,
+        tps: f64,
+        log: &CalculationLog,
+    ) -> Result<()> {
+        let mut batch = Batch::default();
+        batch.insert(meta_key(chain, CALCULATION_LOG), encode(log)?);
+        batch.insert(meta_key(chain, TRANSACTIONS_PER_SECOND), encode(&tps)?);
+        self.meta.apply_batch(batch)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn load_tps_and_calculation_log(
+        &self,
+        chain: Chain,
+    ) -> Result<(Option<f64>, Option<CalculationLog>)> {
+        let tps_key = meta_key(chain, TRANSACTIONS_PER_SECOND);
+        let log_key = meta_key(chain, CALCULATION_LOG);
+        let r: TransactionResult<_> = self
+            .meta
+            .transaction(|meta| Ok((meta.get(&tps_key)?, meta.get(&log_key)?)));
+        let (tps, log) = r.map_err(|e| anyhow!("sled transaction failed: {:?}", e))?;
+
+        Ok((
+            tps.map(|tps| decode::<f64>(&tps))
+                .transpose()?
+                .map(|decoded| decoded.record),
+            log.map(|log| decode::<CalculationLog>(&log))
+                .transpose()?
+                .map(|decoded| decoded.record),
+        ))
+    }
+
+    fn store_blocks(&self, blocks: Vec<Block>) -> Result<()> {
+        let mut batch = Batch::default();
+        for block in blocks {
+            batch.insert(block_key(block.chain, block.block_number), encode(&block)?);
+        }
+        self.blocks.apply_batch(batch)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn store_blocks_and_highest_block_number(
+        &self,
+        chain: Chain,
+        blocks: Vec<Block>,
+        highest_block_number: u64,
+    ) -> Result<()> {
+        let mut block_batch = Batch::default();
+        for block in blocks {
+            block_batch.insert(block_key(block.chain, block.block_number), encode(&block)?);
+        }
+
+        let mut meta_batch = Batch::default();
+        meta_batch.insert(
+            meta_key(chain, HIGHEST_BLOCK_NUMBER),
+            encode(&highest_block_number)?,
+        );
+
+        let r: TransactionResult<()> = (&self.blocks, &self.meta).transaction(|(blocks, meta)| {
+            blocks.apply_batch(&block_batch)?;
+            meta.apply_batch(&meta_batch)?;
+            Ok(())
+        });
+        r.map_err(|e| anyhow!("sled transaction failed: {:?}", e))?;
+        self.db.flush()?;
+
+        Ok(())
+    }
+
+    fn upgrade_records(&self, chain: Chain) -> Result<u64> {
+        let mut num_upgraded = 0;
+
+        for entry in self.blocks.scan_prefix(chain_prefix(chain)) {
+            let (key, _) = entry?;
+            if SledDb::upgrade_value::<Block>(&self.blocks, &key)? {
+                num_upgraded += 1;
+            }
+        }
+
+        for entry in self.tps_history.scan_prefix(chain_prefix(chain)) {
+            let (key, _) = entry?;
+            if SledDb::upgrade_value::<TpsRecord>(&self.tps_history, &key)? {
+                num_upgraded += 1;
+            }
+        }
+
+        for entry in self.reorgs.scan_prefix(chain_prefix(chain)) {
+            let (key, _) = entry?;
+            if SledDb::upgrade_value::<ReorgEvent>(&self.reorgs, &key)? {
+                num_upgraded += 1;
+            }
+        }
+
+        let meta = &self.meta;
+        let upgraded = [
+            SledDb::upgrade_value::<u64>(meta, &meta_key(chain, HIGHEST_BLOCK_NUMBER))?,
+            SledDb::upgrade_value::<u64>(meta, &meta_key(chain, FINALIZED_BLOCK_NUMBER))?,
+            SledDb::upgrade_value::<f64>(meta, &meta_key(chain, TRANSACTIONS_PER_SECOND))?,
+            SledDb::upgrade_value::<CalculationLog>(meta, &meta_key(chain, CALCULATION_LOG))?,
+        ];
+        num_upgraded += upgraded.iter().filter(|upgraded| **upgraded).count() as u64;
+
+        self.db.flush()?;
+
+        Ok(num_upgraded)
+    }
+}
+
+fn encode<T: Record>(record: &T) -> Result<Vec<u8>> {
+    Ok(serde_json::to_vec(&encode_record(record))?)
+}
+
+fn decode<T: Record>(value: &[u8]) -> Result<Decoded<T>> {
+    decode_record(serde_json::from_slice(value)?)
+}
+
+fn chain_prefix(chain: Chain) -> Vec<u8> {
+    format!("{}/", chain).into_bytes()
+}
+
+fn block_key(chain: Chain, block_number: u64) -> Vec<u8> {
+    let mut key = chain_prefix(chain);
+    key.extend_from_slice(&block_number.to_be_bytes());
+    key
+}
+
+fn meta_key(chain: Chain, name: &str) -> Vec<u8> {
+    let mut key = chain_prefix(chain);
+    key.extend_from_slice(name.as_bytes());
+    key
+}
+
+/// Times before the epoch, e.g. the start of a query range, sort as the epoch.
+fn tps_history_key(chain: Chain, calculated_at: DateTime<Utc>) -> Result<Vec<u8>> {
+    let mut key = chain_prefix(chain);
+    let millis = u64::try_from(calculated_at.timestamp_millis().max(0))?;
+    key.extend_from_slice(&millis.to_be_bytes());
+    Ok(key)
+}
+
+fn reorg_key(chain: Chain, detected_at: DateTime<Utc>, block_number: u64) -> Result<Vec<u8>> {
+    let mut key = tps_history_key(chain, detected_at)?;
+    key.extend_from_slice(&block_number.to_be_bytes());
+    Ok(key)
+}
@@ -0,0 +1,580 @@
+use super::*;
+use anyhow::{anyhow, bail};
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// A `Db` stored in a single SQLite file.
+///
+/// Blocks are stored one row each, keyed by `(chain, block_number)` and
+/// indexed by `(chain, timestamp)`. Meta values are stored as versioned JSON
+/// records, under the same names `JsonDb` uses for its meta files. TPS
+/// history records are stored the same way, keyed by their calculation time
+/// in milliseconds, as are reorg events, keyed by their detection time in
+/// milliseconds and block number.
+///
+/// The version of the table layout is tracked in SQLite's `user_version`,
+/// and older databases are migrated when opened.
+pub struct SqliteDb {
+    conn: Mutex<Connection>,
+}
+
+pub static SQLITE_DB_PATH: &str = "realtps.sqlite";
+
+/// Statements upgrading the schema from each version to the next, starting
+/// from an empty database at version 0.
+static MIGRATIONS: &[&str] = &[SCHEMA_V1, SCHEMA_V2, SCHEMA_V3];
+
+static SCHEMA_V1: &str = "
+CREATE TABLE IF NOT EXISTS blocks (
+    chain TEXT NOT NULL,
+    block_number INTEGER NOT NULL,
+    prev_block_number INTEGER,
+    timestamp INTEGER NOT NULL,
+    num_txs INTEGER NOT NULL,
+    hash TEXT NOT NULL,
+    parent_hash TEXT NOT NULL,
+    PRIMARY KEY (chain, block_number)
+) WITHOUT ROWID;
+
+CREATE INDEX IF NOT EXISTS blocks_chain_timestamp ON blocks (chain, timestamp);
+
+CREATE TABLE IF NOT EXISTS meta (
+    chain TEXT NOT NULL,
+    key TEXT NOT NULL,
+    value TEXT NOT NULL,
+    PRIMARY KEY (chain, key)
+) WITHOUT ROWID;
+";
+
+static SCHEMA_V2: &str = "
+CREATE TABLE tps_history (
+    chain TEXT NOT NULL,
+    calculated_at INTEGER NOT NULL,
+    value TEXT NOT NULL,
+    PRIMARY KEY (chain, calculated_at)
+) WITHOUT ROWID;
+";
+
+static SCHEMA_V3: &str = "
+CREATE TABLE reorgs (
+    chain TEXT NOT NULL,
+    detected_at INTEGER NOT NULL,
+    block_number INTEGER NOT NULL,
+    value TEXT NOT NULL,
+    PRIMARY KEY (chain, detected_at, block_number)
+) WITHOUT ROWID;
+";
+
+impl SqliteDb {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<SqliteDb> {
+        let conn = Connection::open(path)?;
+
+        // WAL lets `realtps_web` read while `realtps_import` is writing.
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.busy_timeout(Duration::from_secs(10))?;
+        migrate(&conn)?;
+
+        Ok(SqliteDb {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    fn with_conn<T>(&self, f: impl FnOnce(&Connection) -> Result<T>) -> Result<T> {
+        let conn = self
+            .conn
+            .lock()
+            .map_err(|_| anyhow!("sqlite connection poisoned"))?;
+        f(&conn)
+    }
+
+    fn store_meta<T>(&self, chain: Chain, key: &str, value: &T) -> Result<()>
+    where
+        T: Record,
+    {
+        self.with_conn(|conn| insert_meta(conn, chain, key, value))
+    }
+
+    fn load_meta<T>(&self, chain: Chain, key: &str) -> Result<Option<T>>
+    where
+        T: Record,
+    {
+        let decoded = self.with_conn(|conn| select_meta(conn, chain, key))?;
+        Ok(decoded.map(|decoded| decoded.record))
+    }
+
+    /// Rewrites the meta value `key` in the current record version if it is outdated.
+    fn upgrade_meta<T>(&self, chain: Chain, key: &str) -> Result<bool>
+    where
+        T: Record,
+    {
+        self.with_conn(|conn| match select_meta::<T>(conn, chain, key)? {
+            Some(decoded) if decoded.is_outdated() => {
+                insert_meta(conn, chain, key, &decoded.record)?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        })
+    }
+}
+
+impl Db for SqliteDb {
+    fn store_block(&self, block: Block) -> Result<()> {
+        self.with_conn(|conn| insert_block(conn, &block))
+    }
+
+    fn load_block(&self, chain: Chain, block_number: u64) -> Result<Option<Block>> {
+        let block_number = i64::try_from(block_number)?;
+        let row = self.with_conn(|conn| {
+            Ok(conn
+                .query_row(
+                    "SELECT block_number, prev_block_number, timestamp, num_txs, hash, parent_hash
+                     FROM blocks WHERE chain = ?1 AND block_number = ?2",
+                    params![chain.to_string(), block_number],
+                    BlockRow::from_row,
+                )
+                .optional()?)
+        })?;
+
+        row.map(|row| row.into_block(chain)).transpose()
+    }
+
+    fn load_blocks_in_range(&self, chain: Chain, from: u64, to: u64) -> Result<Vec<Block>> {
+        // Beyond what SQLite can store, so nothing to load
+        let from = match i64::try_from(from) {
+            Ok(from) => from,
+            Err(_) => return Ok(vec![]),
+        };
+        let to = i64::try_from(to).unwrap_or(i64::MAX);
+        let rows: Vec<BlockRow> = self.with_conn(|conn| {
+            let mut stmt = conn.prepare(
+                "SELECT block_number, prev_block_number, timestamp, num_txs, hash, parent_hash
+                 FROM blocks WHERE chain = ?1 AND block_number >= ?2 AND block_number <= ?3
+                 ORDER BY block_number",
+            )?;
+            let rows = stmt.query_map(params![chain.to_string(), from, to], BlockRow::from_row)?;
+            Ok(rows.collect::<rusqlite::Result<_>>()?)
+        })?;
+
+        rows.into_iter().map(|row| row.into_block(chain)).collect()
+    }
+
+    fn find_block_at_or_before_timestamp(
+        &self,
+        chain: Chain,
+        timestamp: u64,
+    ) -> Result<Option<Block>> {
+        let timestamp = i64::try_from(timestamp).unwrap_or(i64::MAX);
+        let row = self.with_conn(|conn| {
+            Ok(conn
+                .query_row(
+                    "SELECT block_number, prev_block_number, timestamp, num_txs, hash, parent_hash
+                     FROM blocks WHERE chain = ?1 AND timestamp <= ?2
+                     ORDER BY timestamp DESC, block_number DESC LIMIT 1",
+                    params![chain.to_string(), timestamp],
+                    BlockRow::from_row,
+                )
+                .optional()?)
+        })?;
+
+        row.map(|row| row.into_block(chain)).transpose()
+    }
+
+    fn store_highest_block_number(&self, chain: Chain, block_number: u64) -> Result<()> {
+        self.store_meta(chain, HIGHEST_BLOCK_NUMBER, &block_number)
+    }
+
+    fn load_highest_block_number(&self, chain: Chain) -> Result<Option<u64>> {
+        self.load_meta(chain, HIGHEST_BLOCK_NUMBER)
+    }
+
+    fn store_finalized_block_number(&self, chain: Chain, block_number: u64) -> Result<()> {
+        self.store_meta(chain, FINALIZED_BLOCK_NUMBER, &block_number)
+    }
+
+    fn load_finalized_block_number(&self, chain: Chain) -> Result<Option<u64>> {
+        self.load_meta(chain, FINALIZED_BLOCK_NUMBER)
+    }
+
+    fn store_tps(&self, chain: Chain, tps: f64) -> Result<()> {
+        self.store_meta(chain, TRANSACTIONS_PER_SECOND, &tps)
+    }
+
+    fn load_tps(&self, chain: Chain) -> Result<Option<f64>> {
+        self.load_meta(chain, TRANSACTIONS_PER_SECOND)
+    }
+
+    fn append_tps_record(&self, chain: Chain, record: &TpsRecord) -> Result<()> {
+        let value = serde_json::to_string(&encode_record(record))?;
+        self.with_conn(|conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO tps_history (chain, calculated_at, value)
+                 VALUES (?1, ?2, ?3)",
+                params![
+                    chain.to_string(),
+                    record.calculated_at.timestamp_millis(),
+                    value
+                ],
+            )?;
+            Ok(())
+        })
+    }
+
+    fn load_tps_history(
+        &self,
+        chain: Chain,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Result<Vec<TpsRecord>> {
+        let values: Vec<String> = self.with_conn(|conn| {
+            let mut stmt = conn.prepare(
+                "SELECT value FROM tps_history
+                 WHERE chain = ?1 AND calculated_at >= ?2 AND calculated_at <= ?3
+                 ORDER BY calculated_at",
+            )?;
+            let rows = stmt.query_map(
+                params![
+                    chain.to_string(),
+                    from.timestamp_millis(),
+                    to.timestamp_millis()
+                ],
+                |row| row.get(0),
+            )?;
+            Ok(rows.collect::<rusqlite::Result<_>>()?)
+        })?;
+
+        values
+            .iter()
+            .map(|value| Ok(decode_record(serde_json::from_str(value)?)?.record))
+            .collect()
+    }
+
+    fn append_reorg_event(&self, event: &ReorgEvent) -> Result<()> {
+        let value = serde_json::to_string(&encode_record(event))?;
+        self.with_conn(|conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO reorgs (chain, detected_at, block_number, value)
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![
+                    event.chain.to_string(),
+                    event.detected_at.timestamp_millis(),
+                    i64::try_from(event.block_number)?,
+                    value
+                ],
+            )?;
+            Ok(())
+        })
+    }
+
+    fn load_reorg_events(
+        &self,
+        chain: Chain,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Result<Vec<ReorgEvent>> {
+        let values: Vec<String> = self.with_conn(|conn| {
+            let mut stmt = conn.prepare(
+                "SELECT value FROM reorgs
+                 WHERE chain = ?1 AND detected_at >= ?2 AND detected_at <= ?3
+                 ORDER BY detected_at, block_number",
+            )?;
+            let rows = stmt.query_map(
+                params![
+                    chain.to_string(),
+                    from.timestamp_millis(),
+                    to.timestamp_millis()
+                ],
+                |row| row.get(0),
+            )?;
+            Ok(rows.collect::<rusqlite::Result<_>>()?)
+        })?;
+
+        values
+            .iter()
+            .map(|value| Ok(decode_record(serde_json::from_str(value)?)?.record))
+            .collect()
+    }
+
+    fn remove_block(&self, chain: Chain, block: u64) -> Result<()> {
+        let block = i64::try_from(block)?;
+        self.with_conn(|conn| {
+            conn.execute(
+                "DELETE FROM blocks WHERE chain = ?1 AND block_number = ?2",
+                params![chain.to_string(), block],
+            )?;
+            Ok(())
+        })
+    }
+
+    fn remove_blocks_before(&self, chain: Chain, block_number: u64) -> Result<u64> {
+        let block_number = i64::try_from(block_number).unwrap_or(i64::MAX);
+        let num_removed = self.with_conn(|conn| {
+            Ok(conn.execute(
+                "DELETE FROM blocks WHERE chain = ?1 AND block_number < ?2",
+                params![chain.to_string(), block_number],
+            )?)
+        })?;
+        Ok(u64::try_from(num_removed)?)
+    }
+
+    fn list_block_numbers(&self, chain: Chain) -> Result<Vec<u64>> {
+        let block_numbers: Vec<i64> = self.with_conn(|conn| {
+            let mut stmt = conn.prepare(
+                "SELECT block_number FROM blocks WHERE chain = ?1 ORDER BY block_number",
+            )?;
+            let rows = stmt.query_map(params![chain.to_string()], |row| row.get(0))?;
+            Ok(rows.collect::<rusqlite::Result<_>>()?)
+        })?;
+
+        block_numbers
+            .into_iter()
+            .map(|block_number| Ok(u64::try_from(block_number)?))
+            .collect()
+    }
+
+    fn store_calculation_log(&self, chain: Chain, log: &CalculationLog) -> Result<()> {
+        self.store_meta(chain, CALCULATION_LOG, log)
+    }
+
+    fn load_calculation_log(&self, chain: Chain) -> Result<Option<CalculationLog>> {
+        self.load_meta(chain, CALCULATION_LOG)
+    }
+
+    fn store_tps_and_calculation_log(
+        &self,
+        chain: Chain,
+        tps: f64,
+        log: &CalculationLog,
+    ) -> Result<()> {
+        self.with_conn(|conn| {
+            let tx = conn.unchecked_transaction()?;
+            insert_meta(&tx, chain, CALCULATION_LOG, log)?;
+            insert_meta(&tx, chain, TRANSACTIONS_PER_SECOND, &tps)?;
+            tx.commit()?;
+            Ok(())
+        })
+    }
+
+    fn load_tps_and_calculation_log(
+        &self,
+        chain: Chain,
+    ) -> Result<(Option<f64>, Option<CalculationLog>)> {
+        self.with_conn(|conn| {
+            // Both reads see the same snapshot of the db
+            let tx = conn.unchecked_transaction()?;
+            let tps = select_meta(&tx, chain, TRANSACTIONS_PER_SECOND)?;
+            let log = select_meta(&tx, chain, CALCULATION_LOG)?;
+            tx.commit()?;
+            Ok((
+                tps.map(|decoded| decoded.record),
+                log.map(|decoded| decoded.record),
+            ))
+        })
+    }
+
+    fn store_blocks(&self, blocks: Vec<Block>) -> Result<()> {
+        self.with_conn(|conn| {
+            let tx = conn.unchecked_transaction()?;
+            for block in &blocks {
+                insert_block(&tx, block)?;
+            }
+            tx.commit()?;
+            Ok(())
+        })
+    }
+
+    fn store_blocks_and_highest_block_number(
+        &self,
+        chain: Chain,
+        blocks: Vec<Block>,
+        highest_block_number: u64,
+    ) -> Result<()> {
+        self.with_conn(|conn| {
+            let tx = conn.unchecked_transaction()?;
+            for block in &blocks {
+                insert_block(&tx, block)?;
+            }
+            insert_meta(&tx, chain, HIGHEST_BLOCK_NUMBER, &highest_block_number)?;
+            tx.commit()?;
+            Ok(())
+        })
+    }
+
+    // Blocks are stored as columns, and are upgraded by the schema
+    // migrations when the db is opened. Only meta values, TPS history and
+    // reorg events have record versions.
+    fn upgrade_records(&self, chain: Chain) -> Result<u64> {
+        let upgraded = [
+            self.upgrade_meta::<u64>(chain, HIGHEST_BLOCK_NUMBER)?,
+            self.upgrade_meta::<u64>(chain, FINALIZED_BLOCK_NUMBER)?,
+            self.upgrade_meta::<f64>(chain, TRANSACTIONS_PER_SECOND)?,
+            self.upgrade_meta::<CalculationLog>(chain, CALCULATION_LOG)?,
+        ];
+        let num_upgraded = upgraded.iter().filter(|upgraded| **upgraded).count() as u64;
+
+        let num_history_upgraded = self.with_conn(|conn| {
+            let tx = conn.unchecked_transaction()?;
+            let history: Vec<(i64, String)> = {
+                let mut stmt =
+                    tx.prepare("SELECT calculated_at, value FROM tps_history WHERE chain = ?1")?;
+                let rows = stmt.query_map(params![chain.to_string()], |row| {
+                    Ok((row.get(0)?, row.get(1)?))
+                })?;
+                rows.collect::<rusqlite::Result<_>>()?
+            };
+
+            let mut num_upgraded = 0;
+            for (calculated_at, value) in history {
+                let decoded: Decoded<TpsRecord> = decode_record(serde_json::from_str(&value)?)?;
+                if decoded.is_outdated() {
+                    let value = serde_json::to_string(&encode_record(&decoded.record))?;
+                    tx.execute(
+                        "UPDATE tps_history SET value = ?3 WHERE chain = ?1 AND calculated_at = ?2",
+                        params![chain.to_string(), calculated_at, value],
+                    )?;
+                    num_upgraded += 1;
+                }
+            }
+            tx.commit()?;
+
+            Ok(num_upgraded)
+        })?;
+
+        let num_reorgs_upgraded = self.with_conn(|conn| {
+            let tx = conn.unchecked_transaction()?;
+            let events: Vec<(i64, i64, String)> = {
+                let mut stmt = tx.prepare(
+                    "SELECT detected_at, block_number, value FROM reorgs WHERE chain = ?1",
+                )?;
+                let rows = stmt.query_map(params![chain.to_string()], |row| {
+                    Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+                })?;
+                rows.collect::<rusqlite::Result<_>>()?
+            };
+
+            let mut num_upgraded = 0;
+            for (detected_at, block_number, value) in events {
+                let decoded: Decoded<ReorgEvent> = decode_record(serde_json::from_str(&value)?)?;
+                if decoded.is_outdated() {
+                    let value = serde_json::to_string(&encode_record(&decoded.record))?;
+                    tx.execute(
+                        "UPDATE reorgs SET value = ?4
+                         WHERE chain = ?1 AND detected_at = ?2 AND block_number = ?3",
+                        params![chain.to_string(), detected_at, block_number, value],
+                    )?;
+                    num_upgraded += 1;
+                }
+            }
+            tx.commit()?;
+
+            Ok(num_upgraded)
+        })?;
+
+        Ok(num_upgraded + num_history_upgraded + num_reorgs_upgraded)
+    }
+}
+
+fn migrate(conn: &Connection) -> Result<()> {
+    let version: usize = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+
+    if version > MIGRATIONS.len() {
+        bail!(
+            "sqlite schema version {} is newer than supported version {}",
+            version,
+            MIGRATIONS.len()
+        );
+    }
+
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = conn.unchecked_transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", i + 1)?;
+        tx.commit()?;
+    }
+
+    Ok(())
+}
+
+fn insert_block(conn: &Connection, block: &Block) -> Result<()> {
+    conn.execute(
+        "INSERT OR REPLACE INTO blocks
+         (chain, block_number, prev_block_number, timestamp, num_txs, hash, parent_hash)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![
+            block.chain.to_string(),
+            i64::try_from(block.block_number)?,
+            block.prev_block_number.map(i64::try_from).transpose()?,
+            i64::try_from(block.timestamp)?,
+            i64::try_from(block.num_txs)?,
+            block.hash,
+            block.parent_hash,
+        ],
+    )?;
+    Ok(())
+}
+
+fn insert_meta<T>(conn: &Connection, chain: Chain, key: &str, value: &T) -> Result<()>
+where
+    T: Record,
+{
+    let value = serde_json::to_string(&encode_record(value))?;
+    conn.execute(
+        "INSERT OR REPLACE INTO meta (chain, key, value) VALUES (?1, ?2, ?3)",
+        params![chain.to_string(), key, value],
+    )?;
+    Ok(())
+}
+
+fn select_meta<T>(conn: &Connection, chain: Chain, key: &str) -> Result<Option<Decoded<T>>>
+where
+    T: Record,
+{
+    let value: Option<String> = conn
+        .query_row(
+            "SELECT value FROM meta WHERE chain = ?1 AND key = ?2",
+            params![chain.to_string(), key],
+            |row| row.get(0),
+        )
+        .optional()?;
+
+    match value {
+        Some(value) => Ok(Some(decode_record(serde_json::from_str(&value)?)?)),
+        None => Ok(None),
+    }
+}
+
+/// The columns of the `blocks` table, minus the chain, as SQLite stores them.
+struct BlockRow {
+    block_number: i64,
+    prev_block_number: Option<i64>,
+    timestamp: i64,
+    num_txs: i64,
+    hash: String,
+    parent_hash: String,
+}
+
+impl BlockRow {
+    fn from_row(row: &Row) -> rusqlite::Result<BlockRow> {
+        Ok(BlockRow {
+            block_number: row.get(0)?,
+            prev_block_number: row.get(1)?,
+            timestamp: row.get(2)?,
+            num_txs: row.get(3)?,
+            hash: row.get(4)?,
+            parent_hash: row.get(5)?,
+        })
+    }
+
+    fn into_block(self, chain: Chain) -> Result<Block> {
+        Ok(Block {
+            chain,
+            block_number: u64::try_from(self.block_number)?,
+            prev_block_number: self.prev_block_number.map(u64::try_from).transpose()?,
+            timestamp: u64::try_from(self.timestamp)?,
+            num_txs: u64::try_from(self.num_txs)?,
+            hash: self.hash,
+            parent_hash: self.parent_hash,
+        })
+    }
+}
@@ -1,9 +1,16 @@
 use crate::helpers::*;
 use anyhow::{anyhow, Result};
 use chrono::{TimeZone, Utc};
-use realtps_common::{chain::Chain, db::CalculationLog, db::Db};
+use realtps_common::{
+    chain::Chain,
+    db::{Block, CalculationLog, Db, TpsRecord},
+};
+use std::collections::HashMap;
 use std::sync::Arc;
 
+/// How many blocks to load at a time while walking the chain.
+const CHUNK_SIZE: u64 = 1000;
+
 pub struct ChainCalcs {
     pub chain: Chain,
     pub tps: f64,
@@ -16,54 +23,80 @@ pub async fn calculate_for_chain(chain: Chain, db: Arc<dyn Db>) -> Result<ChainC
     let highest_block_number =
         highest_block_number.ok_or_else(|| anyhow!("no data for chain {}", chain))?;
 
-    let load_block = |number| load_block(chain, &db, number);
+    let newest_block = load_block(chain, &db, highest_block_number)
+        .await?
+        .expect("first block");
+    let latest_timestamp = newest_block.timestamp;
 
-    let latest_timestamp = load_block(highest_block_number)
+    // Blocks above the finalized one were imported with unfinalized blocks
+    // included, and are counted as such in the calculation log. Chains
+    // imported before finality was tracked have none.
+    let finalized_block_number = load_finalized_block_number(chain, &db)
         .await?
-        .expect("first block")
-        .timestamp;
+        .unwrap_or(u64::MAX);
 
     let seconds_per_week = 60 * 60 * 24 * 7;
     let min_timestamp = latest_timestamp
         .checked_sub(seconds_per_week)
         .expect("underflow");
 
-    let mut current_block = load_block(highest_block_number)
+    // The oldest block we need is the newest one from at least a week ago.
+    // If there isn't one, all our blocks are from the last week.
+    let oldest_block_number = find_block_at_or_before_timestamp(chain, &db, min_timestamp)
         .await?
-        .expect("first_block");
+        .map(|block| block.block_number)
+        .unwrap_or(0);
 
-    let mut num_txs: u64 = 0;
+    // Walk the chain back from the newest block, loading the blocks it
+    // passes through a chunk at a time, and skipping any that aren't on it.
+    let mut chunk: HashMap<u64, Block> = HashMap::new();
+    let mut current_block = newest_block;
 
-    let init_timestamp = loop {
-        let prev_block_number = current_block.prev_block_number;
+    let mut num_txs: u64 = 0;
+    let mut unfinalized_blocks: u64 = 0;
 
-        if prev_block_number.is_none() {
-            break current_block.timestamp;
+    let init_block = loop {
+        if current_block.block_number > finalized_block_number {
+            unfinalized_blocks += 1;
         }
 
-        let prev_block_number = prev_block_number.unwrap();
-
-        let prev_block = load_block(prev_block_number).await?;
-
-        if prev_block.is_none() {
-            break current_block.timestamp;
+        let prev_block_number = match current_block.prev_block_number {
+            Some(prev_block_number) if prev_block_number >= oldest_block_number => {
+                prev_block_number
+            }
+            _ => break current_block,
+        };
+
+        if !chunk.contains_key(&prev_block_number) {
+            let chunk_start = prev_block_number
+                .saturating_sub(CHUNK_SIZE - 1)
+                .max(oldest_block_number);
+            chunk = load_blocks_in_range(chain, &db, chunk_start, prev_block_number)
+                .await?
+                .into_iter()
+                .map(|block| (block.block_number, block))
+                .collect();
         }
 
-        let prev_block = prev_block.unwrap();
+        let prev_block = match chunk.remove(&prev_block_number) {
+            Some(prev_block) => prev_block,
+            None => break current_block,
+        };
 
         num_txs = num_txs
             .checked_add(current_block.num_txs)
             .expect("overflow");
 
         if prev_block.timestamp <= min_timestamp {
-            break prev_block.timestamp;
+            break prev_block;
         }
         if prev_block.block_number == 0 {
-            break prev_block.timestamp;
+            break prev_block;
         }
 
         current_block = prev_block;
     };
+    let init_timestamp = init_block.timestamp;
 
     let tps = calculate_tps(init_timestamp, latest_timestamp, num_txs)?;
 
@@ -77,6 +110,7 @@ pub async fn calculate_for_chain(chain: Chain, db: Arc<dyn Db>) -> Result<ChainC
         calculating_end,
         newest_block_timestamp,
         oldest_block_timestamp,
+        unfinalized_blocks,
     };
 
     log::debug!(
@@ -85,7 +119,18 @@ pub async fn calculate_for_chain(chain: Chain, db: Arc<dyn Db>) -> Result<ChainC
         calculation_log
     );
 
-    write_log(chain, &db, calculation_log).await?;
+    let tps_record = TpsRecord {
+        calculated_at: calculating_end,
+        tps,
+        oldest_block_number: init_block.block_number,
+        newest_block_number: highest_block_number,
+        oldest_block_timestamp,
+        newest_block_timestamp,
+        num_txs,
+    };
+
+    store_tps_and_calculation_log(chain, &db, tps, calculation_log).await?;
+    append_tps_record(chain, &db, tps_record).await?;
 
     Ok(ChainCalcs { chain, tps })
 }
@@ -106,3 +151,43 @@ fn calculate_tps(init_timestamp: u64, latest_timestamp: u64, num_txs: u64) -> Re
 
     Ok(tps)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::client::mock::MockClient;
+    use crate::client::Client;
+    use realtps_common::db::MemoryDb;
+
+    #[tokio::test]
+    async fn one_week_of_blocks() -> Result<()> {
+        let chain = Chain::try_from("ethereum")?;
+        let seconds_per_day = 60 * 60 * 24;
+        let client = MockClient::new(chain, seconds_per_day);
+        client.set_blocks(0..=10, "a");
+
+        let memory_db = Arc::new(MemoryDb::new());
+        for block_number in 0..=10 {
+            let block = client.get_block(block_number).await?.expect("block");
+            memory_db.store_block(block)?;
+        }
+        memory_db.store_highest_block_number(chain, 10)?;
+        memory_db.store_finalized_block_number(chain, 8)?;
+
+        let calcs = calculate_for_chain(chain, memory_db.clone()).await?;
+
+        // Blocks 4 to 10 were produced in the week since block 3
+        assert_eq!(calcs.tps, 7.0 / (7 * seconds_per_day) as f64);
+        assert_eq!(memory_db.load_tps(chain)?, Some(calcs.tps));
+        let log = memory_db.load_calculation_log(chain)?.expect("log");
+        assert_eq!(log.unfinalized_blocks, 2);
+
+        let history = memory_db.load_tps_history(chain, Utc.timestamp(0, 0), Utc::now())?;
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].tps, calcs.tps);
+        assert_eq!(history[0].oldest_block_number, 3);
+        assert_eq!(history[0].num_txs, 7);
+
+        Ok(())
+    }
+}
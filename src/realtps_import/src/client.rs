@@ -1,11 +1,141 @@
 use anyhow::Result;
 use async_trait::async_trait;
 use realtps_common::db::Block;
+use std::ops::RangeInclusive;
 
 #[async_trait]
 pub trait Client: Send + Sync + 'static {
     async fn client_version(&self) -> Result<String>;
+    /// The newest block, which a reorg may yet replace.
     async fn get_latest_block_number(&self) -> Result<u64>;
+    /// The newest block that no reorg can replace.
+    ///
+    /// By default the latest block, for chains whose blocks are final as
+    /// soon as they are produced.
+    async fn get_finalized_block_number(&self) -> Result<u64> {
+        self.get_latest_block_number().await
+    }
     /// Returns `None` if the network thinks the block doesn't exist
     async fn get_block(&self, block_number: u64) -> Result<Option<Block>>;
+    /// Returns the blocks in `block_numbers`, in order, with `None` for each
+    /// block the network thinks doesn't exist.
+    ///
+    /// By default fetches the blocks one at a time. Clients that can fetch
+    /// several blocks in one round trip should do so, but none should have
+    /// more than one request in flight, as the import already makes up to
+    /// the chain's `max_concurrency` calls at once.
+    async fn get_blocks(&self, block_numbers: RangeInclusive<u64>) -> Result<Vec<Option<Block>>> {
+        let mut blocks = vec![];
+        for block_number in block_numbers {
+            blocks.push(self.get_block(block_number).await?);
+        }
+        Ok(blocks)
+    }
+    /// Tells the client that blocks it returned don't form a hash chain with
+    /// each other, so one of them is from another fork. By default does
+    /// nothing.
+    fn report_hash_mismatch(&self, _block_numbers: &[u64]) {}
+}
+
+#[cfg(test)]
+pub mod mock {
+    use super::Client;
+    use anyhow::Result;
+    use async_trait::async_trait;
+    use realtps_common::{chain::Chain, db::Block};
+    use std::collections::BTreeMap;
+    use std::ops::RangeInclusive;
+    use std::sync::Mutex;
+
+    /// A `Client` serving a chain of blocks kept in memory.
+    ///
+    /// Block `n` has timestamp `n * block_time` and one transaction.
+    pub struct MockClient {
+        chain: Chain,
+        block_time: u64,
+        blocks: Mutex<BTreeMap<u64, Block>>,
+        /// Blocks to serve once, in place of the ones in `blocks`
+        forked_blocks: Mutex<BTreeMap<u64, Block>>,
+        /// How many of the newest blocks aren't final yet
+        unfinalized_blocks: Mutex<u64>,
+    }
+
+    impl MockClient {
+        pub fn new(chain: Chain, block_time: u64) -> MockClient {
+            MockClient {
+                chain,
+                block_time,
+                blocks: Mutex::new(BTreeMap::new()),
+                forked_blocks: Mutex::new(BTreeMap::new()),
+                unfinalized_blocks: Mutex::new(0),
+            }
+        }
+
+        /// Makes the newest `unfinalized_blocks` blocks not final yet.
+        pub fn set_unfinalized_blocks(&self, unfinalized_blocks: u64) {
+            *self.unfinalized_blocks.lock().unwrap() = unfinalized_blocks;
+        }
+
+        /// Creates (or replaces) the blocks in `range`, building on the
+        /// existing block before it. Hashes are `fork` followed by the block
+        /// number, so replacing blocks with a new `fork` makes a reorg.
+        pub fn set_blocks(&self, range: RangeInclusive<u64>, fork: &str) {
+            let mut blocks = self.blocks.lock().unwrap();
+            for block_number in range {
+                let prev_block_number = block_number.checked_sub(1);
+                let parent_hash = prev_block_number
+                    .and_then(|n| blocks.get(&n))
+                    .map(|b| b.hash.clone())
+                    .unwrap_or_default();
+                let block = Block {
+                    chain: self.chain,
+                    block_number,
+                    prev_block_number,
+                    timestamp: block_number * self.block_time,
+                    num_txs: 1,
+                    hash: format!("{}{}", fork, block_number),
+                    parent_hash,
+                };
+                blocks.insert(block_number, block);
+            }
+        }
+
+        /// Serves the existing block `block_number` once more with a hash of
+        /// `fork`, as though from a node on another fork.
+        pub fn serve_fork_once(&self, block_number: u64, fork: &str) {
+            let blocks = self.blocks.lock().unwrap();
+            let mut block = blocks[&block_number].clone();
+            block.hash = format!("{}{}", fork, block_number);
+            self.forked_blocks
+                .lock()
+                .unwrap()
+                .insert(block_number, block);
+        }
+    }
+
+    #[async_trait]
+    impl Client for MockClient {
+        async fn client_version(&self) -> Result<String> {
+            Ok("mock".to_string())
+        }
+
+        async fn get_latest_block_number(&self) -> Result<u64> {
+            let blocks = self.blocks.lock().unwrap();
+            Ok(blocks.keys().next_back().cloned().unwrap_or_default())
+        }
+
+        async fn get_finalized_block_number(&self) -> Result<u64> {
+            let latest_block_number = self.get_latest_block_number().await?;
+            let unfinalized_blocks = *self.unfinalized_blocks.lock().unwrap();
+            Ok(latest_block_number.saturating_sub(unfinalized_blocks))
+        }
+
+        async fn get_block(&self, block_number: u64) -> Result<Option<Block>> {
+            if let Some(block) = self.forked_blocks.lock().unwrap().remove(&block_number) {
+                return Ok(Some(block));
+            }
+            let blocks = self.blocks.lock().unwrap();
+            Ok(blocks.get(&block_number).cloned())
+        }
+    }
 }
@@ -1,20 +1,42 @@
 use crate::client::Client;
+use crate::rpc_config::Endpoint;
 use anyhow::{anyhow, Result};
 use async_trait::async_trait;
 use realtps_common::{chain::Chain, db::Block};
 
 pub struct ElrondClient {
+    chain: Chain,
     client: reqwest::Client,
     url: String,
 }
 
 impl ElrondClient {
-    pub fn new(url: &str) -> Result<Self> {
+    pub fn new(chain: Chain, endpoint: &Endpoint) -> Result<Self> {
         Ok(ElrondClient {
-            client: reqwest::Client::new(),
-            url: url.to_string(),
+            chain,
+            client: endpoint.http_client()?,
+            url: endpoint.url.clone(),
         })
     }
+
+    /// Gets a nonce from the metachain's network status.
+    async fn get_metachain_nonce(&self, key: &str) -> Result<u64> {
+        let metablock_shard = 4294967295_u32;
+        let url = format!("{}/network/status/{}", self.url, metablock_shard);
+        let resp = self.client.get(url).send().await?;
+        let resp: ElrondResponse = resp.json().await?;
+        match (resp.data, resp.error) {
+            (serde_json::Value::Null, Some(err)) => Err(anyhow!("{}", err)),
+            (serde_json::Value::Null, None) => Err(anyhow!("missing error response")),
+            (data, _) => Ok(data
+                .get("status")
+                .ok_or_else(|| anyhow!("no status key"))?
+                .get(key)
+                .ok_or_else(|| anyhow!("no {} key", key))?
+                .as_u64()
+                .ok_or_else(|| anyhow!("not a u64"))?),
+        }
+    }
 }
 
 #[derive(serde::Deserialize, Debug)]
@@ -46,21 +68,11 @@ impl Client for ElrondClient {
     }
 
     async fn get_latest_block_number(&self) -> Result<u64> {
-        let metablock_shard = 4294967295_u32;
-        let url = format!("{}/network/status/{}", self.url, metablock_shard);
-        let resp = self.client.get(url).send().await?;
-        let resp: ElrondResponse = resp.json().await?;
-        match (resp.data, resp.error) {
-            (serde_json::Value::Null, Some(err)) => Err(anyhow!("{}", err)),
-            (serde_json::Value::Null, None) => Err(anyhow!("missing error response")),
-            (data, _) => Ok(data
-                .get("status")
-                .ok_or_else(|| anyhow!("no status key"))?
-                .get("erd_highest_final_nonce")
-                .ok_or_else(|| anyhow!("no erd_highest_final_nonce key"))?
-                .as_u64()
-                .ok_or_else(|| anyhow!("not a u64"))?),
-        }
+        self.get_metachain_nonce("erd_nonce").await
+    }
+
+    async fn get_finalized_block_number(&self) -> Result<u64> {
+        self.get_metachain_nonce("erd_highest_final_nonce").await
     }
 
     async fn get_block(&self, block_number: u64) -> Result<Option<Block>> {
@@ -123,7 +135,7 @@ impl Client for ElrondClient {
                 });
 
                 Ok(Some(Block {
-                    chain: Chain::Elrond,
+                    chain: self.chain,
                     block_number,
                     prev_block_number,
                     timestamp,
@@ -1,23 +1,52 @@
 use crate::client::Client;
+use crate::rpc_config::Endpoint;
 use anyhow::{anyhow, Result};
 use async_trait::async_trait;
 use ethers::prelude::*;
 use ethers::utils::hex::ToHex;
+use log::debug;
 use realtps_common::{chain::Chain, db::Block};
+use reqwest::StatusCode;
+use serde_json::json;
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
 
 pub struct EthersClient {
     chain: Chain,
     provider: Provider<Http>,
+    // For batch requests, which the provider can't make
+    client: reqwest::Client,
+    url: reqwest::Url,
 }
 
 impl EthersClient {
-    pub fn new(chain: Chain, url: &str) -> Result<Self> {
-        let provider = Provider::<Http>::try_from(url)?;
+    pub fn new(chain: Chain, endpoint: &Endpoint) -> Result<Self> {
+        let url = reqwest::Url::parse(&endpoint.url)?;
+        let client = endpoint.http_client()?;
+        let provider = Provider::new(Http::new_with_client(url.clone(), client.clone()));
 
-        Ok(EthersClient { chain, provider })
+        Ok(EthersClient {
+            chain,
+            provider,
+            client,
+            url,
+        })
     }
 }
 
+#[derive(serde::Deserialize)]
+struct JsonRpcResponse {
+    id: u64,
+    result: Option<ethers::prelude::Block<H256>>,
+    error: Option<JsonRpcError>,
+}
+
+#[derive(serde::Deserialize)]
+struct JsonRpcError {
+    code: i64,
+    message: String,
+}
+
 #[async_trait]
 impl Client for EthersClient {
     async fn client_version(&self) -> Result<String> {
@@ -28,6 +57,43 @@ impl Client for EthersClient {
         Ok(self.provider.get_block_number().await?.as_u64())
     }
 
+    /// Gets the block tagged `finalized`, which nodes of chains without
+    /// finality, or from before the tag existed, reject as unsupported. Those
+    /// chains' latest block is taken as final.
+    async fn get_finalized_block_number(&self) -> Result<u64> {
+        let request = json!({
+            "jsonrpc": "2.0",
+            "id": 0,
+            "method": "eth_getBlockByNumber",
+            "params": ["finalized", false],
+        });
+
+        let resp = self
+            .client
+            .post(self.url.clone())
+            .json(&request)
+            .send()
+            .await?;
+        let response: JsonRpcResponse = resp.error_for_status()?.json().await?;
+
+        match (response.result, response.error) {
+            (Some(block), _) => Ok(block_number(&block)?),
+            (None, Some(err)) if finalized_tag_unsupported(&err) => {
+                debug!(
+                    "no finalized block for {}: {} ({})",
+                    self.chain, err.message, err.code
+                );
+                self.get_latest_block_number().await
+            }
+            (None, Some(err)) => Err(anyhow!(
+                "finalized block request failed: {} ({})",
+                err.message,
+                err.code
+            )),
+            (None, None) => Err(anyhow!("no finalized block for {}", self.chain)),
+        }
+    }
+
     async fn get_block(&self, block_number: u64) -> Result<Option<Block>> {
         if let Some(block) = self.provider.get_block(block_number).await? {
             // I like this `map` <3
@@ -36,17 +102,148 @@ impl Client for EthersClient {
             Ok(None)
         }
     }
+
+    /// Gets the blocks with one JSON-RPC batch request, or one by one from
+    /// endpoints that reject batch requests.
+    async fn get_blocks(&self, block_numbers: RangeInclusive<u64>) -> Result<Vec<Option<Block>>> {
+        if let Some(blocks) = self.get_blocks_in_batch(block_numbers.clone()).await? {
+            return Ok(blocks);
+        }
+
+        let mut blocks = vec![];
+        for block_number in block_numbers {
+            blocks.push(self.get_block(block_number).await?);
+        }
+        Ok(blocks)
+    }
+}
+
+impl EthersClient {
+    /// Gets the blocks with one JSON-RPC batch request, or `None` if the
+    /// endpoint rejects it, either with an HTTP error or with a single error
+    /// response rather than one for each block.
+    async fn get_blocks_in_batch(
+        &self,
+        block_numbers: RangeInclusive<u64>,
+    ) -> Result<Option<Vec<Option<Block>>>> {
+        // The request ids are the block numbers
+        let requests: Vec<_> = block_numbers
+            .clone()
+            .map(|block_number| {
+                json!({
+                    "jsonrpc": "2.0",
+                    "id": block_number,
+                    "method": "eth_getBlockByNumber",
+                    "params": [U64::from(block_number), false],
+                })
+            })
+            .collect();
+
+        let resp = self
+            .client
+            .post(self.url.clone())
+            .json(&requests)
+            .send()
+            .await?;
+        let status = resp.status();
+        // Being rate limited isn't a rejection of batches
+        if status.is_client_error() && status != StatusCode::TOO_MANY_REQUESTS {
+            debug!(
+                "batch request for blocks {:?} of {} rejected with {}",
+                block_numbers, self.chain, status
+            );
+            return Ok(None);
+        }
+        let responses: serde_json::Value = resp.error_for_status()?.json().await?;
+        if !responses.is_array() {
+            debug!(
+                "batch request for blocks {:?} of {} rejected: {}",
+                block_numbers, self.chain, responses
+            );
+            return Ok(None);
+        }
+        let responses: Vec<JsonRpcResponse> = serde_json::from_value(responses)?;
+
+        let mut responses: HashMap<u64, JsonRpcResponse> = responses
+            .into_iter()
+            .map(|response| (response.id, response))
+            .collect();
+
+        block_numbers
+            .map(|block_number| {
+                let response = responses
+                    .remove(&block_number)
+                    .ok_or_else(|| anyhow!("no response for block {}", block_number))?;
+                if let Some(err) = response.error {
+                    return Err(anyhow!(
+                        "eth_getBlockByNumber failed for block {}: {} ({})",
+                        block_number,
+                        err.message,
+                        err.code
+                    ));
+                }
+                response
+                    .result
+                    .map(|block| ethers_block_to_block(self.chain, block))
+                    .transpose()
+            })
+            .collect::<Result<_>>()
+            .map(Some)
+    }
+}
+
+/// Whether the error is a node rejecting the `finalized` tag itself, as
+/// invalid params or an unknown or unsupported block tag, rather than e.g. a
+/// syncing node not having a finalized block yet.
+fn finalized_tag_unsupported(err: &JsonRpcError) -> bool {
+    let message = err.message.to_lowercase();
+    err.code == -32602
+        || (message.contains("tag")
+            && (message.contains("unknown") || message.contains("unsupported")))
+}
+
+fn block_number(block: &ethers::prelude::Block<H256>) -> Result<u64> {
+    block
+        .number
+        .map(|number| number.as_u64())
+        .ok_or_else(|| anyhow!("block has no number"))
 }
 
 fn ethers_block_to_block(chain: Chain, block: ethers::prelude::Block<H256>) -> Result<Block> {
-    let block_number = block.number.expect("block number").as_u64();
+    let block_number = block_number(&block)?;
     Ok(Block {
         chain,
         block_number,
         prev_block_number: block_number.checked_sub(1),
         timestamp: u64::try_from(block.timestamp).map_err(|e| anyhow!("{}", e))?,
         num_txs: u64::try_from(block.transactions.len())?,
-        hash: block.hash.expect("hash").encode_hex(),
+        hash: block
+            .hash
+            .ok_or_else(|| anyhow!("block {} has no hash", block_number))?
+            .encode_hex(),
         parent_hash: block.parent_hash.encode_hex(),
     })
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn unsupported(code: i64, message: &str) -> bool {
+        finalized_tag_unsupported(&JsonRpcError {
+            code,
+            message: message.to_string(),
+        })
+    }
+
+    #[test]
+    fn only_unsupported_finalized_tag_falls_back() {
+        assert!(unsupported(-32602, "invalid argument 0: hex string"));
+        assert!(unsupported(-32000, "Unknown block tag"));
+        assert!(unsupported(-32000, "unsupported block tag finalized"));
+
+        // A node without a finalized block yet, e.g. while syncing
+        assert!(!unsupported(-39001, "finalized block not found"));
+        assert!(!unsupported(-32000, "header not found"));
+    }
+}
@@ -1,24 +1,26 @@
 use crate::client::Client;
+use crate::rpc_config::Endpoint;
 use anyhow::Result;
 use async_trait::async_trait;
 use near_jsonrpc_client::{auth::Unauthenticated, methods, JsonRpcClient};
 use near_jsonrpc_primitives::types::chunks::ChunkReference;
 use near_primitives::{
-    types::{BlockId, BlockReference},
+    types::{BlockId, BlockReference, Finality},
     views::BlockView,
 };
 use realtps_common::{chain::Chain, db::Block};
 use std::time::Duration;
 
 pub struct NearClient {
+    chain: Chain,
     client: JsonRpcClient<Unauthenticated>,
 }
 
 impl NearClient {
-    pub fn new(url: &str) -> Result<Self> {
-        let client = JsonRpcClient::connect(url);
+    pub fn new(chain: Chain, endpoint: &Endpoint) -> Result<Self> {
+        let client = JsonRpcClient::with(endpoint.http_client()?).connect(&endpoint.url);
 
-        Ok(NearClient { client })
+        Ok(NearClient { chain, client })
     }
 }
 
@@ -36,6 +38,17 @@ impl Client for NearClient {
         Ok(status.sync_info.latest_block_height)
     }
 
+    async fn get_finalized_block_number(&self) -> Result<u64> {
+        let block = self
+            .client
+            .call(methods::block::RpcBlockRequest {
+                block_reference: BlockReference::Finality(Finality::Final),
+            })
+            .await?;
+
+        Ok(block.header.height)
+    }
+
     async fn get_block(&self, block_number: u64) -> Result<Option<Block>> {
         let block = self
             .client
@@ -61,13 +74,18 @@ impl Client for NearClient {
         }
 
         let num_txs = u64::try_from(num_txs)?;
-        near_block_to_block(block, block_number, num_txs).map(Some)
+        near_block_to_block(self.chain, block, block_number, num_txs).map(Some)
     }
 }
 
-fn near_block_to_block(block: BlockView, block_number: u64, num_txs: u64) -> Result<Block> {
+fn near_block_to_block(
+    chain: Chain,
+    block: BlockView,
+    block_number: u64,
+    num_txs: u64,
+) -> Result<Block> {
     Ok(Block {
-        chain: Chain::Near,
+        chain,
         block_number,
         prev_block_number: block.header.prev_height,
         timestamp: Duration::from_nanos(block.header.timestamp_nanosec).as_secs(),
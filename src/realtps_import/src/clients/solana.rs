@@ -1,22 +1,29 @@
 use crate::client::Client;
+use crate::rpc_config::Endpoint;
 use anyhow::{anyhow, Result};
 use async_trait::async_trait;
 use log::{debug, trace};
 use realtps_common::{chain::Chain, db::Block};
 use solana_client::rpc_client::RpcClient;
-use solana_transaction_status::UiTransactionEncoding;
+use solana_client::rpc_config::RpcBlockConfig;
+use solana_sdk::commitment_config::CommitmentConfig;
+use solana_transaction_status::{EncodedTransactionWithStatusMeta, UiTransactionEncoding};
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
 use std::sync::Arc;
 use tokio::task;
 
 pub struct SolanaClient {
+    chain: Chain,
     client: Arc<RpcClient>,
 }
 
 impl SolanaClient {
-    pub fn new(url: &str) -> Result<Self> {
-        let client = Arc::new(RpcClient::new(url.to_string()));
+    pub fn new(chain: Chain, endpoint: &Endpoint) -> Result<Self> {
+        // RpcClient can't be given headers, but sends basic auth from the url
+        let client = Arc::new(RpcClient::new(endpoint.url_with_credentials()?));
 
-        Ok(SolanaClient { client })
+        Ok(SolanaClient { chain, client })
     }
 }
 
@@ -31,32 +38,76 @@ impl Client for SolanaClient {
 
     async fn get_latest_block_number(&self) -> Result<u64> {
         let client = self.client.clone();
-        let slot = task::spawn_blocking(move || client.get_slot()).await??;
+        let slot = task::spawn_blocking(move || {
+            client.get_slot_with_commitment(CommitmentConfig::confirmed())
+        })
+        .await??;
+
+        Ok(slot)
+    }
+
+    async fn get_finalized_block_number(&self) -> Result<u64> {
+        let client = self.client.clone();
+        let slot = task::spawn_blocking(move || {
+            client.get_slot_with_commitment(CommitmentConfig::finalized())
+        })
+        .await??;
 
         Ok(slot)
     }
 
     async fn get_block(&self, block_number: u64) -> Result<Option<Block>> {
         // todo: error handling with return missing block
-        // `ClientResult<EncodedConfirmedBlock>`
+        // `ClientResult<UiConfirmedBlock>`
+
+        let client = self.client.clone();
+        // Confirmed, so that blocks newer than the finalized one can be read
+        let config = RpcBlockConfig {
+            encoding: Some(UiTransactionEncoding::Base64),
+            commitment: Some(CommitmentConfig::confirmed()),
+            ..RpcBlockConfig::default()
+        };
+        let block =
+            task::spawn_blocking(move || client.get_block_with_config(block_number, config))
+                .await??;
+
+        solana_block_to_block(self.chain, block, block_number).map(Some)
+    }
 
+    /// Asks which slots in the range have blocks, then gets those blocks one
+    /// at a time, so that each batch has only one request in flight.
+    async fn get_blocks(&self, block_numbers: RangeInclusive<u64>) -> Result<Vec<Option<Block>>> {
         let client = self.client.clone();
-        let block = task::spawn_blocking(move || {
-            client.get_block_with_encoding(block_number, UiTransactionEncoding::Base64)
+        let (start_slot, end_slot) = (*block_numbers.start(), *block_numbers.end());
+        let slots = task::spawn_blocking(move || {
+            client.get_blocks_with_commitment(
+                start_slot,
+                Some(end_slot),
+                CommitmentConfig::confirmed(),
+            )
         })
         .await??;
 
-        solana_block_to_block(block, block_number).map(Some)
+        let mut blocks = HashMap::new();
+        for slot in slots {
+            if let Some(block) = self.get_block(slot).await? {
+                blocks.insert(slot, block);
+            }
+        }
+
+        // Skipped slots have no block
+        Ok(block_numbers.map(|slot| blocks.remove(&slot)).collect())
     }
 }
 
 fn solana_block_to_block(
-    block: solana_transaction_status::EncodedConfirmedBlock,
+    chain: Chain,
+    block: solana_transaction_status::UiConfirmedBlock,
     slot_number: u64,
 ) -> Result<Block> {
-    fn calc_user_txs(block: &solana_transaction_status::EncodedConfirmedBlock) -> u64 {
+    fn calc_user_txs(transactions: &[EncodedTransactionWithStatusMeta]) -> u64 {
         let mut num_user_txs = 0;
-        for tx_status in &block.transactions {
+        for tx_status in transactions {
             let tx = tx_status.transaction.decode().unwrap();
             trace!("tx_meta: {:#?}", tx_status.meta.as_ref().unwrap());
             trace!("tx: {:#?}", tx);
@@ -82,12 +133,11 @@ fn solana_block_to_block(
             }
         }
 
-        let vote_txs = block
-            .transactions
+        let vote_txs = transactions
             .len()
             .checked_sub(num_user_txs)
             .expect("underflow");
-        debug!("solana total txs: {}", block.transactions.len());
+        debug!("solana total txs: {}", transactions.len());
         debug!("solana user txs: {}", num_user_txs);
         debug!("solana vote txs: {}", vote_txs);
 
@@ -95,7 +145,7 @@ fn solana_block_to_block(
     }
 
     Ok(Block {
-        chain: Chain::Solana,
+        chain,
         block_number: slot_number,
         prev_block_number: Some(block.parent_slot),
         timestamp: u64::try_from(
@@ -103,7 +153,7 @@ fn solana_block_to_block(
                 .block_time
                 .ok_or_else(|| anyhow!("block time unavailable for solana slot {}", slot_number))?,
         )?,
-        num_txs: calc_user_txs(&block),
+        num_txs: calc_user_txs(block.transactions.as_deref().unwrap_or_default()),
         hash: block.blockhash,
         parent_hash: block.previous_blockhash,
     })
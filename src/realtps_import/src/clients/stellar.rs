@@ -1,18 +1,26 @@
 use crate::client::Client;
+use crate::rpc_config::Endpoint;
 use anyhow::Result;
 use async_trait::async_trait;
 use realtps_common::{chain::Chain, db::Block};
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+
+/// The most ledgers Horizon returns in one page.
+const LEDGERS_PAGE_LIMIT: u64 = 200;
 
 pub struct StellarClient {
+    chain: Chain,
     client: reqwest::Client,
     url: String,
 }
 
 impl StellarClient {
-    pub fn new(url: &str) -> Result<Self> {
+    pub fn new(chain: Chain, endpoint: &Endpoint) -> Result<Self> {
         Ok(Self {
-            client: reqwest::Client::new(),
-            url: url.to_string(),
+            chain,
+            client: endpoint.http_client()?,
+            url: endpoint.url.clone(),
         })
     }
 }
@@ -23,8 +31,21 @@ struct StellarNetworkDetailsResponse {
     history_latest_ledger: u32,
 }
 
+#[derive(serde::Deserialize)]
+struct StellarLedgersResponse {
+    #[serde(rename = "_embedded")]
+    embedded: StellarLedgerRecords,
+}
+
+#[derive(serde::Deserialize)]
+struct StellarLedgerRecords {
+    records: Vec<StellarLedgerResponse>,
+}
+
 #[derive(serde::Deserialize)]
 struct StellarLedgerResponse {
+    sequence: u32,
+    paging_token: String,
     hash: String,
     prev_hash: String,
     closed_at: chrono::DateTime<chrono::Utc>,
@@ -47,38 +68,99 @@ impl Client for StellarClient {
         let url = format!("{}/ledgers/{}", &self.url, block_number);
         let resp = self.client.get(url).send().await?;
         let ledger: StellarLedgerResponse = resp.json().await?;
-        Ok(Some(Block {
-            chain: Chain::Stellar,
+        Ok(Some(stellar_ledger_to_block(
+            self.chain,
+            ledger,
             block_number,
-            prev_block_number: if block_number > 0 {
-                Some(block_number - 1)
-            } else {
-                None
-            },
-            timestamp: ledger.closed_at.timestamp() as u64,
-            num_txs: ledger.operation_count as u64,
-            // NB: operation_count corresponds most-closely to what is usually
-            // meant by a "transaction" -- a payment, a trade, etc. Stellar's
-            // transaction format is structured such that users can bundle
-            // together multiple operations into a composite unit for purposes
-            // of atomicity which, since it's the outermost atomic unit, is the
-            // unit in the protocol called a "transaction": operations are
-            // sub-transactions, within the outer transaction object.
-            hash: ledger.hash,
-            parent_hash: ledger.prev_hash,
-        }))
+        )))
+    }
+
+    /// Pages through the ledgers in the range, oldest first.
+    async fn get_blocks(&self, block_numbers: RangeInclusive<u64>) -> Result<Vec<Option<Block>>> {
+        let (start, end) = (*block_numbers.start(), *block_numbers.end());
+        let mut blocks = HashMap::new();
+        // Paging tokens of ledgers are their sequence number shifted left
+        // 32 bits, and the cursor is the paging token to start after.
+        let mut cursor = (start.saturating_sub(1) << 32).to_string();
+
+        loop {
+            let url = format!(
+                "{}/ledgers?cursor={}&order=asc&limit={}",
+                &self.url, cursor, LEDGERS_PAGE_LIMIT
+            );
+            let resp = self.client.get(url).send().await?;
+            let page: StellarLedgersResponse = resp.error_for_status()?.json().await?;
+
+            let ledgers = page.embedded.records;
+            let (last_cursor, last_block_number) = match ledgers.last() {
+                Some(ledger) => (ledger.paging_token.clone(), u64::from(ledger.sequence)),
+                // No more ledgers
+                None => break,
+            };
+            for ledger in ledgers {
+                let block_number = u64::from(ledger.sequence);
+                if block_number > end {
+                    break;
+                }
+                let block = stellar_ledger_to_block(self.chain, ledger, block_number);
+                blocks.insert(block_number, block);
+            }
+            // Any ledgers missing from the range aren't on a later page
+            if last_block_number >= end {
+                break;
+            }
+            cursor = last_cursor;
+        }
+
+        Ok(block_numbers
+            .map(|block_number| blocks.remove(&block_number))
+            .collect())
+    }
+}
+
+fn stellar_ledger_to_block(
+    chain: Chain,
+    ledger: StellarLedgerResponse,
+    block_number: u64,
+) -> Block {
+    Block {
+        chain,
+        block_number,
+        prev_block_number: if block_number > 0 {
+            Some(block_number - 1)
+        } else {
+            None
+        },
+        timestamp: ledger.closed_at.timestamp() as u64,
+        num_txs: ledger.operation_count as u64,
+        // NB: operation_count corresponds most-closely to what is usually
+        // meant by a "transaction" -- a payment, a trade, etc. Stellar's
+        // transaction format is structured such that users can bundle
+        // together multiple operations into a composite unit for purposes
+        // of atomicity which, since it's the outermost atomic unit, is the
+        // unit in the protocol called a "transaction": operations are
+        // sub-transactions, within the outer transaction object.
+        hash: ledger.hash,
+        parent_hash: ledger.prev_hash,
     }
 }
 
 #[cfg(test)]
 mod test_stellar {
     use super::{Client, StellarClient};
+    use crate::rpc_config::Endpoint;
+    use realtps_common::chain::Chain;
 
     const RPC_URL: &str = "https://horizon.stellar.org";
 
+    fn client() -> Result<StellarClient, anyhow::Error> {
+        let chain = Chain::try_from("stellar")?;
+        StellarClient::new(chain, &Endpoint::new(RPC_URL))
+    }
+
     #[tokio::test]
     async fn client_version() -> Result<(), anyhow::Error> {
-        let client = StellarClient::new(RPC_URL)?;
+        let client = client()?;
         let ver = client.client_version().await?;
         println!("client_version: {}", ver);
         assert!(ver.len() > 0);
@@ -87,7 +169,7 @@ mod test_stellar {
 
     #[tokio::test]
     async fn get_latest_block_number() -> Result<(), anyhow::Error> {
-        let client = StellarClient::new(RPC_URL)?;
+        let client = client()?;
         let latest_block_number = client.get_latest_block_number().await?;
         println!("latest_block_number: {}", latest_block_number);
         assert!(latest_block_number > 0);
@@ -96,11 +178,23 @@ mod test_stellar {
 
     #[tokio::test]
     async fn get_block() -> Result<(), anyhow::Error> {
-        let client = StellarClient::new(RPC_URL)?;
+        let client = client()?;
         let latest_block_number = client.get_latest_block_number().await?;
         println!("latest_block_number: {}", latest_block_number);
         let block = client.get_block(latest_block_number).await?;
         println!("block: {:?}", block);
         Ok(())
     }
+
+    #[tokio::test]
+    async fn get_blocks() -> Result<(), anyhow::Error> {
+        let client = client()?;
+        let latest_block_number = client.get_latest_block_number().await?;
+        let blocks = client
+            .get_blocks(latest_block_number - 4..=latest_block_number)
+            .await?;
+        assert_eq!(blocks.len(), 5);
+        assert!(blocks.iter().all(Option::is_some));
+        Ok(())
+    }
 }
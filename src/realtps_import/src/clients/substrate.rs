@@ -1,35 +1,78 @@
 use crate::client::Client;
+use crate::rpc_config::Endpoint;
 use anyhow::{anyhow, bail, Result};
 use async_trait::async_trait;
 use hex::FromHex;
-use jsonrpc_core::types::{Params, Value};
-use jsonrpc_core_client::transports::http;
-use jsonrpc_core_client::RawClient;
 use log::trace;
 use realtps_common::{chain::Chain, db::Block};
+use serde_json::{json, Value};
 use sp_storage::StorageKey;
 
 pub struct SubstrateClient {
     chain: Chain,
-    client: RawClient,
+    client: reqwest::Client,
+    url: String,
 }
 
 impl SubstrateClient {
-    pub async fn new(chain: Chain, url: &str) -> Result<Self> {
-        let client = http::connect(url).await.map_err(|e| anyhow!("{}", e))?;
+    pub fn new(chain: Chain, endpoint: &Endpoint) -> Result<Self> {
+        Ok(SubstrateClient {
+            chain,
+            client: endpoint.http_client()?,
+            url: endpoint.url.clone(),
+        })
+    }
+
+    async fn call_method(&self, method: &str, params: Value) -> Result<Value> {
+        let request = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": method,
+            "params": params,
+        });
+        let resp = self.client.post(&self.url).json(&request).send().await?;
+        let resp: JsonRpcResponse = resp.error_for_status()?.json().await?;
+
+        match resp.error {
+            Some(err) => Err(anyhow!("{} failed: {} ({})", method, err.message, err.code)),
+            None => Ok(resp.result),
+        }
+    }
+
+    /// Gets the number of the block `chain_getHeader` returns for `params`:
+    /// the head, or the block with the given hash.
+    async fn get_header_number(&self, params: Value) -> Result<u64> {
+        let header = self.call_method("chain_getHeader", params).await?;
+
+        trace!("header: {:#?}", header);
+
+        let number_hex = header.get("number").expect("number").as_str().expect("str");
+        let number = hex_be_to_u32(number_hex)?;
+        let number = u64::from(number);
 
-        Ok(SubstrateClient { chain, client })
+        Ok(number)
     }
 }
 
+#[derive(serde::Deserialize)]
+struct JsonRpcResponse {
+    #[serde(default)]
+    result: Value,
+    error: Option<JsonRpcError>,
+}
+
+#[derive(serde::Deserialize)]
+struct JsonRpcError {
+    code: i64,
+    message: String,
+}
+
 #[async_trait]
 impl Client for SubstrateClient {
     async fn client_version(&self) -> Result<String> {
         let runtime_version = self
-            .client
-            .call_method("state_getRuntimeVersion", Params::None)
-            .await
-            .map_err(|e| anyhow!("{}", e))?;
+            .call_method("state_getRuntimeVersion", json!([]))
+            .await?;
 
         trace!("runtime_version: {:#?}", runtime_version);
         let impl_name = runtime_version
@@ -42,31 +85,24 @@ impl Client for SubstrateClient {
     }
 
     async fn get_latest_block_number(&self) -> Result<u64> {
-        let header = self
-            .client
-            .call_method("chain_getHeader", Params::None)
-            .await
-            .map_err(|e| anyhow!("{}", e))?;
+        self.get_header_number(json!([])).await
+    }
 
-        trace!("header: {:#?}", header);
+    async fn get_finalized_block_number(&self) -> Result<u64> {
+        let hash = self
+            .call_method("chain_getFinalizedHead", json!([]))
+            .await?;
 
-        let number_hex = header.get("number").expect("number").as_str().expect("str");
-        let number = hex_be_to_u32(number_hex)?;
-        let number = u64::from(number);
+        trace!("finalized hash: {:#?}", hash);
 
-        Ok(number)
+        self.get_header_number(json!([hash])).await
     }
 
     async fn get_block(&self, block_number: u64) -> Result<Option<Block>> {
         let block_number = u32::try_from(block_number)?;
         let hash = self
-            .client
-            .call_method(
-                "chain_getBlockHash",
-                Params::Array(vec![Value::from(block_number)]),
-            )
-            .await
-            .map_err(|e| anyhow!("{}", e))?;
+            .call_method("chain_getBlockHash", json!([block_number]))
+            .await?;
 
         trace!("hash: {:#?}", hash);
 
@@ -78,11 +114,7 @@ impl Client for SubstrateClient {
             }
         };
 
-        let block = self
-            .client
-            .call_method("chain_getBlock", Params::Array(vec![Value::from(hash)]))
-            .await
-            .map_err(|e| anyhow!("{}", e))?;
+        let block = self.call_method("chain_getBlock", json!([hash])).await?;
 
         trace!("block: {:#?}", block);
 
@@ -94,13 +126,8 @@ impl Client for SubstrateClient {
         let timestamp_storage_key = StorageKey(timestamp_storage_key);
         let timestamp_storage_key = serde_json::to_value(timestamp_storage_key)?;
         let timestamp = self
-            .client
-            .call_method(
-                "state_getStorage",
-                Params::Array(vec![timestamp_storage_key, Value::from(hash)]),
-            )
-            .await
-            .map_err(|e| anyhow!("{}", e))?;
+            .call_method("state_getStorage", json!([timestamp_storage_key, hash]))
+            .await?;
 
         trace!("timestamp: {:#?}", timestamp);
 
@@ -1,17 +1,24 @@
 use crate::client::Client;
+use crate::rpc_config::Endpoint;
 use anyhow::{anyhow, Result};
 use async_trait::async_trait;
 use realtps_common::{chain::Chain, db::Block};
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
 use tendermint_rpc::{Client as TendermintClientTrait, HttpClient};
 
+/// The most block headers the `blockchain` method returns at once.
+const BLOCKCHAIN_MAX_BLOCKS: u64 = 20;
+
 pub struct TendermintClient {
     chain: Chain,
     client: HttpClient,
 }
 
 impl TendermintClient {
-    pub fn new(chain: Chain, url: &str) -> Result<Self> {
-        let client = HttpClient::new(url)?;
+    pub fn new(chain: Chain, endpoint: &Endpoint) -> Result<Self> {
+        // HttpClient can't be given headers, but sends basic auth from the url
+        let client = HttpClient::new(endpoint.url_with_credentials()?.as_str())?;
 
         Ok(TendermintClient { chain, client })
     }
@@ -37,6 +44,34 @@ impl Client for TendermintClient {
 
         tendermint_block_to_block(self.chain, block_response, block_number).map(Some)
     }
+
+    /// Gets the blocks' headers with `blockchain` queries, which cover up to
+    /// 20 blocks each.
+    async fn get_blocks(&self, block_numbers: RangeInclusive<u64>) -> Result<Vec<Option<Block>>> {
+        let (start, end) = (*block_numbers.start(), *block_numbers.end());
+        let mut blocks = HashMap::new();
+
+        let mut min = start;
+        while min <= end {
+            let max = end.min(min + BLOCKCHAIN_MAX_BLOCKS - 1);
+            let response = self
+                .client
+                .blockchain(
+                    tendermint::block::Height::try_from(min)?,
+                    tendermint::block::Height::try_from(max)?,
+                )
+                .await?;
+            for block_meta in response.block_metas {
+                let block = tendermint_block_meta_to_block(self.chain, block_meta)?;
+                blocks.insert(block.block_number, block);
+            }
+            min = max + 1;
+        }
+
+        Ok(block_numbers
+            .map(|block_number| blocks.remove(&block_number))
+            .collect())
+    }
 }
 
 fn tendermint_block_to_block(
@@ -63,3 +98,26 @@ fn tendermint_block_to_block(
             .to_string(),
     })
 }
+
+fn tendermint_block_meta_to_block(
+    chain: Chain,
+    block_meta: tendermint::block::Meta,
+) -> Result<Block> {
+    let block_number = block_meta.header.height.value();
+    Ok(Block {
+        chain,
+        block_number,
+        prev_block_number: block_number.checked_sub(1),
+        timestamp: u64::try_from(
+            tendermint_proto::google::protobuf::Timestamp::from(block_meta.header.time).seconds,
+        )?,
+        num_txs: u64::try_from(block_meta.num_txs)?,
+        hash: block_meta.block_id.hash.to_string(),
+        parent_hash: block_meta
+            .header
+            .last_block_id
+            .ok_or_else(|| anyhow!("no previous block id"))?
+            .hash
+            .to_string(),
+    })
+}
@@ -1,14 +1,68 @@
-use crate::Chain;
 use anyhow::Result;
 use log::{debug, warn};
 use rand::{
     self,
     distributions::{Distribution, Uniform},
 };
+use realtps_common::chain::Chain;
 use std::future::Future;
 use std::pin::Pin;
 use tokio::time::{self, Duration};
 
+/// How hard to work a chain's RPC endpoints.
+///
+/// The defaults are derived from the chain's block time, and can be
+/// overridden per chain in `rpc_config.toml`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Tuning {
+    /// The wait between fetching windows of blocks.
+    pub courtesy_delay: Duration,
+    /// The wait before looking for new blocks.
+    pub rescan_delay: Duration,
+    /// How many times a failing request is tried.
+    pub tries: u32,
+    /// The wait after the first failed try, growing with each try.
+    pub retry_delay: Duration,
+    /// How long a request to an endpoint can take before it fails.
+    pub request_timeout: Duration,
+    /// How many requests can be in flight to each of the chain's endpoints
+    /// at once. Blocks are fetched in as many batches at once, each making
+    /// one request at a time.
+    pub max_concurrency: usize,
+}
+
+impl Tuning {
+    pub fn for_chain(chain: Chain) -> Tuning {
+        let block_time_ms = chain.block_time().as_millis() as u64;
+
+        // Wait an eighth of the block time between windows of blocks so that we can
+        // keep up, but at most a second. Chains faster than a second need to
+        // go as fast as they can; Solana's RpcClient will use its built in
+        // rate limiter when connecting to public nodes.
+        let courtesy_delay_ms = if block_time_ms < 1000 {
+            0
+        } else {
+            (block_time_ms / 8).min(1000)
+        };
+        // Wait for about two new blocks before looking for them, between one
+        // and thirty seconds.
+        let rescan_delay_ms = (block_time_ms * 2).clamp(1000, 30 * 1000);
+        let retry_delay_ms = (block_time_ms / 20).clamp(100, 1000);
+        let request_timeout_ms = (block_time_ms * 4).clamp(10 * 1000, 60 * 1000);
+        // Enough requests at once to fetch a few blocks a second
+        let max_concurrency = (8000 / block_time_ms).clamp(1, 8) as usize;
+
+        Tuning {
+            courtesy_delay: Duration::from_millis(courtesy_delay_ms),
+            rescan_delay: Duration::from_millis(rescan_delay_ms),
+            tries: 3,
+            retry_delay: Duration::from_millis(retry_delay_ms),
+            request_timeout: Duration::from_millis(request_timeout_ms),
+            max_concurrency,
+        }
+    }
+}
+
 async fn delay(base_ms: u64) {
     let jitter = Uniform::from(0..10);
     let delay_msecs = base_ms + jitter.sample(&mut rand::thread_rng());
@@ -16,24 +70,14 @@ async fn delay(base_ms: u64) {
     time::sleep(delay_time).await;
 }
 
-pub async fn courtesy_delay(chain: Chain) {
-    let msecs = match chain {
-        Chain::Elrond => 1000, // 6s block time
-        // Need to go fast to keep up.
-        // Solana's RpcClient will use its built in rate limiter when connecting to public nodes.
-        Chain::Solana => 0,
-        _ => 250,
-    };
-    debug!("delaying {} ms to retrieve next block", msecs);
+pub async fn courtesy_delay(tuning: &Tuning) {
+    let msecs = tuning.courtesy_delay.as_millis() as u64;
+    debug!("delaying {} ms to retrieve next blocks", msecs);
     delay(msecs).await
 }
 
-pub async fn rescan_delay(chain: Chain) {
-    let delay_secs = match chain {
-        Chain::Solana => 1, // Need to go fast to keep up
-        _ => 30,
-    };
-    let msecs = 1000 * delay_secs;
+pub async fn rescan_delay(tuning: &Tuning) {
+    let msecs = tuning.rescan_delay.as_millis() as u64;
     debug!("delaying {} ms to rescan", msecs);
     delay(msecs).await
 }
@@ -56,22 +100,22 @@ pub async fn remove_data_delay() {
     delay(msecs).await;
 }
 
-pub async fn retry_if_err<'caller, F, T>(chain: Chain, f: F) -> Result<T>
+pub async fn retry_if_err<'caller, F, T>(chain: Chain, tuning: &Tuning, f: F) -> Result<T>
 where
     F: Fn() -> Pin<Box<dyn Future<Output = Result<T>> + Send + 'caller>>,
 {
-    let tries = 3;
-    let base_delay_ms = 100;
+    let tries = tuning.tries;
+    let base_delay_ms = tuning.retry_delay.as_millis() as u64;
     let mut try_num = 1;
     let r = loop {
         let r = f().await;
         match r {
             Ok(r) => break Ok(r),
             Err(e) => {
-                if try_num == tries {
+                if try_num >= tries {
                     break Err(e);
                 } else {
-                    let delay_ms = base_delay_ms * try_num;
+                    let delay_ms = base_delay_ms * u64::from(try_num);
                     warn!(
                         "for {} received err {}. retrying in {} ms",
                         chain, e, delay_ms
@@ -85,22 +129,22 @@ where
     r
 }
 
-pub async fn retry_if_none<'caller, F, T>(chain: Chain, f: F) -> Result<Option<T>>
+pub async fn retry_if_none<'caller, F, T>(chain: Chain, tuning: &Tuning, f: F) -> Result<Option<T>>
 where
     F: Fn() -> Pin<Box<dyn Future<Output = Result<Option<T>>> + Send + 'caller>>,
 {
-    let tries = 3;
-    let base_delay_ms = 100;
+    let tries = tuning.tries;
+    let base_delay_ms = tuning.retry_delay.as_millis() as u64;
     let mut try_num = 1;
     let r = loop {
         let r = f().await?;
         match r {
             Some(r) => break Ok(Some(r)),
             None => {
-                if try_num == tries {
+                if try_num >= tries {
                     break Ok(None);
                 } else {
-                    let delay_ms = base_delay_ms * try_num;
+                    let delay_ms = base_delay_ms * u64::from(try_num);
                     warn!("for {} received None. retrying in {} ms", chain, delay_ms);
                     delay(delay_ms).await;
                 }
@@ -0,0 +1,737 @@
+use crate::client::Client;
+use crate::delay::Tuning;
+use crate::rpc_config::Endpoint;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use futures::future;
+use log::{debug, warn};
+use realtps_common::{chain::Chain, db::Block};
+use std::cmp::Reverse;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::ops::RangeInclusive;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+use tokio::time;
+
+/// The best health score, which endpoints start with.
+const MAX_SCORE: u32 = 10;
+/// Endpoints below this score are only used when no healthier endpoint is.
+const HEALTHY_SCORE: u32 = 5;
+/// How much an error or falling behind costs an endpoint's score.
+const FAILURE_PENALTY: u32 = 3;
+/// How long an unhealthy endpoint is avoided before it gets another chance.
+const UNHEALTHY_RETRY_TIME: Duration = Duration::from_secs(60);
+/// How far behind the highest head seen an endpoint can fall before it is
+/// considered stale.
+const STALE_TIME: Duration = Duration::from_secs(60);
+/// How often every endpoint is asked for its head, to find the stale ones.
+/// In between, only the preferred endpoint is asked.
+const HEAD_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+/// How many of the latest blocks fetched are remembered, with which endpoint
+/// served them, to find the endpoints that serve blocks from other forks.
+const SERVED_BLOCKS: usize = 1000;
+
+/// A `Client` for a chain with several RPC endpoints.
+///
+/// Requests go to the preferred endpoint among the healthy ones, failing over
+/// to the next endpoint on errors. Every success raises an endpoint's health
+/// score, and every error lowers it, as does being stale: having a head that
+/// is far behind that of the other endpoints.
+///
+/// Requests time out, and only so many are made to each endpoint at once, as
+/// set by the chain's `Tuning`, so asking every endpoint for its head isn't
+/// held up by the others.
+///
+/// An endpoint that served a block from another fork is counted as failing.
+pub struct FailoverClient {
+    chain: Chain,
+    /// In order of priority
+    endpoints: Vec<EndpointClient>,
+    request_timeout: Duration,
+    /// The latest block numbers fetched, with the index of the endpoint that
+    /// served them, oldest first
+    served_blocks: Mutex<VecDeque<(u64, usize)>>,
+    /// When every endpoint was last asked for its head
+    last_head_check: Mutex<Option<Instant>>,
+}
+
+struct EndpointClient {
+    /// Index in `FailoverClient::endpoints`
+    index: usize,
+    endpoint: Endpoint,
+    client: Box<dyn Client>,
+    /// Limits the requests made to the endpoint at once
+    permits: Semaphore,
+    health: Mutex<Health>,
+}
+
+struct Health {
+    score: u32,
+    last_failure: Option<Instant>,
+    /// The latest block number this endpoint reported
+    head_block_number: Option<u64>,
+    /// How many times a block from this endpoint didn't match the blocks
+    /// around it
+    hash_mismatches: u64,
+}
+
+impl FailoverClient {
+    pub fn new(
+        chain: Chain,
+        mut endpoints: Vec<(Endpoint, Box<dyn Client>)>,
+        tuning: Tuning,
+    ) -> FailoverClient {
+        endpoints.sort_by_key(|(endpoint, _)| endpoint.priority);
+        let endpoints = endpoints
+            .into_iter()
+            .enumerate()
+            .map(|(index, (endpoint, client))| EndpointClient {
+                index,
+                endpoint,
+                client,
+                permits: Semaphore::new(tuning.max_concurrency),
+                health: Mutex::new(Health {
+                    score: MAX_SCORE,
+                    last_failure: None,
+                    head_block_number: None,
+                    hash_mismatches: 0,
+                }),
+            })
+            .collect();
+
+        FailoverClient {
+            chain,
+            endpoints,
+            request_timeout: tuning.request_timeout,
+            served_blocks: Mutex::new(VecDeque::new()),
+            last_head_check: Mutex::new(None),
+        }
+    }
+
+    fn record_served_blocks(&self, endpoint: &EndpointClient, block_numbers: RangeInclusive<u64>) {
+        let mut served_blocks = self.served_blocks.lock().unwrap();
+        for block_number in block_numbers {
+            served_blocks.push_back((block_number, endpoint.index));
+        }
+        while served_blocks.len() > SERVED_BLOCKS {
+            served_blocks.pop_front();
+        }
+    }
+
+    /// Makes a request to an endpoint, within the limits of the tuning.
+    ///
+    /// The endpoint's secrets are hidden in the errors, which are logged.
+    async fn request<T>(
+        &self,
+        endpoint: &EndpointClient,
+        request: impl Future<Output = Result<T>>,
+    ) -> Result<T> {
+        let _permit = endpoint.permits.acquire().await?;
+        match time::timeout(self.request_timeout, request).await {
+            Ok(result) => result.map_err(|e| endpoint.endpoint.redact_error(e)),
+            Err(_) => Err(anyhow!(
+                "request timed out after {} ms",
+                self.request_timeout.as_millis()
+            )),
+        }
+    }
+
+    /// The endpoints in the order to try them: healthy before unhealthy,
+    /// then those known to have the block before those that may not, then by
+    /// priority.
+    fn candidates(&self, block_number: Option<u64>) -> Vec<&EndpointClient> {
+        let mut candidates: Vec<_> = self.endpoints.iter().collect();
+        // Stable, so ties stay in priority order
+        candidates.sort_by_cached_key(|endpoint| {
+            let health = endpoint.health.lock().unwrap();
+            let has_block = match (block_number, health.head_block_number) {
+                (Some(block_number), Some(head)) => head >= block_number,
+                _ => true,
+            };
+            (Reverse(health.is_usable()), Reverse(has_block))
+        });
+        candidates
+    }
+
+    /// Counts against the endpoints that answered without blocks that
+    /// another endpoint then served, as they are lagging behind.
+    fn record_missing_blocks(
+        &self,
+        endpoints: &[&EndpointClient],
+        block_numbers: &RangeInclusive<u64>,
+    ) {
+        for endpoint in endpoints {
+            warn!(
+                "endpoint {} for {} didn't have blocks {:?}",
+                endpoint.display_url(),
+                self.chain,
+                block_numbers
+            );
+            endpoint.record_failure();
+        }
+    }
+
+    /// Whether it is time to ask every endpoint for its head.
+    fn head_check_due(&self) -> bool {
+        let mut last_head_check = self.last_head_check.lock().unwrap();
+        match *last_head_check {
+            Some(t) if t.elapsed() < HEAD_CHECK_INTERVAL => false,
+            _ => {
+                *last_head_check = Some(Instant::now());
+                true
+            }
+        }
+    }
+
+    /// Asks every endpoint for its head, so that stale endpoints are found,
+    /// and returns the highest.
+    async fn check_heads(&self) -> Result<u64> {
+        let heads = self
+            .endpoints
+            .iter()
+            .map(|endpoint| self.request(endpoint, endpoint.client.get_latest_block_number()));
+        let heads = future::join_all(heads).await;
+
+        let mut last_error = None;
+        for (endpoint, head) in self.endpoints.iter().zip(&heads) {
+            match head {
+                Ok(head) => endpoint.record_head(*head),
+                Err(e) => {
+                    warn!(
+                        "endpoint {} for {} failed getting latest block number: {}",
+                        endpoint.display_url(),
+                        self.chain,
+                        e
+                    );
+                    endpoint.record_failure();
+                    last_error.get_or_insert(e);
+                }
+            }
+        }
+
+        let highest_head = heads.iter().filter_map(|head| head.as_ref().ok()).max();
+        let highest_head = match highest_head {
+            Some(highest_head) => *highest_head,
+            None => {
+                let e = last_error.expect("error");
+                return Err(anyhow!(
+                    "all RPC endpoints for {} failed: {}",
+                    self.chain,
+                    e
+                ));
+            }
+        };
+
+        let stale_blocks = self.stale_blocks();
+        for (endpoint, head) in self.endpoints.iter().zip(&heads) {
+            if let Ok(head) = head {
+                if head + stale_blocks < highest_head {
+                    warn!(
+                        "endpoint {} for {} is stale at block {}; highest: {}",
+                        endpoint.display_url(),
+                        self.chain,
+                        head,
+                        highest_head
+                    );
+                    endpoint.record_failure();
+                } else {
+                    endpoint.record_success();
+                }
+            }
+        }
+
+        Ok(highest_head)
+    }
+
+    /// The number of blocks an endpoint can be behind before it is stale.
+    fn stale_blocks(&self) -> u64 {
+        let block_time_ms = self.chain.block_time().as_millis() as u64;
+        (STALE_TIME.as_millis() as u64 / block_time_ms).max(1)
+    }
+}
+
+impl Health {
+    fn is_usable(&self) -> bool {
+        self.score >= HEALTHY_SCORE
+            || matches!(self.last_failure, Some(t) if t.elapsed() >= UNHEALTHY_RETRY_TIME)
+    }
+}
+
+impl EndpointClient {
+    fn display_url(&self) -> &str {
+        &self.endpoint.display_url
+    }
+
+    fn record_success(&self) {
+        let mut health = self.health.lock().unwrap();
+        health.score = (health.score + 1).min(MAX_SCORE);
+    }
+
+    fn record_failure(&self) {
+        let mut health = self.health.lock().unwrap();
+        health.score = health.score.saturating_sub(FAILURE_PENALTY);
+        health.last_failure = Some(Instant::now());
+    }
+
+    fn record_head(&self, head_block_number: u64) {
+        let mut health = self.health.lock().unwrap();
+        health.head_block_number = Some(head_block_number);
+    }
+}
+
+#[async_trait]
+impl Client for FailoverClient {
+    async fn client_version(&self) -> Result<String> {
+        let mut last_error = None;
+        for endpoint in self.candidates(None) {
+            match self
+                .request(endpoint, endpoint.client.client_version())
+                .await
+            {
+                Ok(version) => {
+                    endpoint.record_success();
+                    return Ok(version);
+                }
+                Err(e) => {
+                    warn!(
+                        "endpoint {} for {} failed getting client version: {}",
+                        endpoint.display_url(),
+                        self.chain,
+                        e
+                    );
+                    endpoint.record_failure();
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        Err(last_error.unwrap_or_else(|| anyhow!("no RPC endpoints for {}", self.chain)))
+    }
+
+    /// Asks the preferred endpoint for its head, failing over to the next
+    /// when it errors or is stale. Every `HEAD_CHECK_INTERVAL` all endpoints
+    /// are asked instead, and the highest head is returned.
+    async fn get_latest_block_number(&self) -> Result<u64> {
+        if self.head_check_due() {
+            return self.check_heads().await;
+        }
+
+        let highest_known = self
+            .endpoints
+            .iter()
+            .filter_map(|endpoint| endpoint.health.lock().unwrap().head_block_number)
+            .max();
+        let stale_blocks = self.stale_blocks();
+        let mut stale_head = None;
+        let mut last_error = None;
+        for endpoint in self.candidates(None) {
+            match self
+                .request(endpoint, endpoint.client.get_latest_block_number())
+                .await
+            {
+                Ok(head) => {
+                    endpoint.record_head(head);
+                    match highest_known {
+                        Some(highest) if head + stale_blocks < highest => {
+                            warn!(
+                                "endpoint {} for {} is stale at block {}; highest: {}",
+                                endpoint.display_url(),
+                                self.chain,
+                                head,
+                                highest
+                            );
+                            endpoint.record_failure();
+                            stale_head = stale_head.max(Some(head));
+                        }
+                        _ => {
+                            endpoint.record_success();
+                            return Ok(head);
+                        }
+                    }
+                }
+                Err(e) => {
+                    warn!(
+                        "endpoint {} for {} failed getting latest block number: {}",
+                        endpoint.display_url(),
+                        self.chain,
+                        e
+                    );
+                    endpoint.record_failure();
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        // Every endpoint that answered is stale, but it's the best we have
+        if let Some(head) = stale_head {
+            return Ok(head);
+        }
+        Err(last_error.unwrap_or_else(|| anyhow!("no RPC endpoints for {}", self.chain)))
+    }
+
+    async fn get_finalized_block_number(&self) -> Result<u64> {
+        let mut last_error = None;
+        for endpoint in self.candidates(None) {
+            match self
+                .request(endpoint, endpoint.client.get_finalized_block_number())
+                .await
+            {
+                Ok(block_number) => {
+                    endpoint.record_success();
+                    return Ok(block_number);
+                }
+                Err(e) => {
+                    warn!(
+                        "endpoint {} for {} failed getting finalized block number: {}",
+                        endpoint.display_url(),
+                        self.chain,
+                        e
+                    );
+                    endpoint.record_failure();
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        Err(last_error.unwrap_or_else(|| anyhow!("no RPC endpoints for {}", self.chain)))
+    }
+
+    /// An endpoint that doesn't have the block may be lagging behind, so the
+    /// next one is asked. The block is only missing, e.g. a skipped slot,
+    /// if no endpoint has it.
+    async fn get_block(&self, block_number: u64) -> Result<Option<Block>> {
+        let block_numbers = block_number..=block_number;
+        let mut last_error = None;
+        let mut missing = vec![];
+        for endpoint in self.candidates(Some(block_number)) {
+            match self
+                .request(endpoint, endpoint.client.get_block(block_number))
+                .await
+            {
+                Ok(Some(block)) => {
+                    endpoint.record_success();
+                    self.record_missing_blocks(&missing, &block_numbers);
+                    self.record_served_blocks(endpoint, block_numbers);
+                    debug!(
+                        "block {} for {} served by {}",
+                        block_number,
+                        self.chain,
+                        endpoint.display_url()
+                    );
+                    return Ok(Some(block));
+                }
+                Ok(None) => {
+                    debug!(
+                        "endpoint {} for {} doesn't have block {}",
+                        endpoint.display_url(),
+                        self.chain,
+                        block_number
+                    );
+                    missing.push(endpoint);
+                }
+                Err(e) => {
+                    warn!(
+                        "endpoint {} for {} failed getting block {}: {}",
+                        endpoint.display_url(),
+                        self.chain,
+                        block_number,
+                        e
+                    );
+                    endpoint.record_failure();
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        if !missing.is_empty() {
+            for endpoint in missing {
+                endpoint.record_success();
+            }
+            return Ok(None);
+        }
+        Err(last_error.unwrap_or_else(|| anyhow!("no RPC endpoints for {}", self.chain)))
+    }
+
+    /// An endpoint without the last of the blocks may be lagging behind, so
+    /// the next one is asked, as for `get_block`.
+    async fn get_blocks(&self, block_numbers: RangeInclusive<u64>) -> Result<Vec<Option<Block>>> {
+        let mut last_error = None;
+        let mut missing = vec![];
+        let mut missing_blocks = None;
+        for endpoint in self.candidates(Some(*block_numbers.end())) {
+            match self
+                .request(endpoint, endpoint.client.get_blocks(block_numbers.clone()))
+                .await
+            {
+                Ok(blocks) if matches!(blocks.last(), Some(None)) => {
+                    debug!(
+                        "endpoint {} for {} doesn't have all of blocks {:?}",
+                        endpoint.display_url(),
+                        self.chain,
+                        block_numbers
+                    );
+                    missing.push(endpoint);
+                    missing_blocks.get_or_insert((endpoint, blocks));
+                }
+                Ok(blocks) => {
+                    endpoint.record_success();
+                    self.record_missing_blocks(&missing, &block_numbers);
+                    self.record_served_blocks(endpoint, block_numbers.clone());
+                    debug!(
+                        "blocks {:?} for {} served by {}",
+                        block_numbers,
+                        self.chain,
+                        endpoint.display_url()
+                    );
+                    return Ok(blocks);
+                }
+                Err(e) => {
+                    warn!(
+                        "endpoint {} for {} failed getting blocks {:?}: {}",
+                        endpoint.display_url(),
+                        self.chain,
+                        block_numbers,
+                        e
+                    );
+                    endpoint.record_failure();
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        if let Some((endpoint, blocks)) = missing_blocks {
+            for endpoint in missing {
+                endpoint.record_success();
+            }
+            self.record_served_blocks(endpoint, block_numbers);
+            return Ok(blocks);
+        }
+        Err(last_error.unwrap_or_else(|| anyhow!("no RPC endpoints for {}", self.chain)))
+    }
+
+    /// Counts a hash mismatch against each endpoint that served one of the
+    /// blocks, as we can't tell which of them is on the wrong fork.
+    fn report_hash_mismatch(&self, block_numbers: &[u64]) {
+        let mut indexes: Vec<usize> = {
+            let served_blocks = self.served_blocks.lock().unwrap();
+            block_numbers
+                .iter()
+                .filter_map(|block_number| {
+                    // The latest fetch of the block
+                    served_blocks
+                        .iter()
+                        .rev()
+                        .find(|(served_block_number, _)| served_block_number == block_number)
+                        .map(|(_, index)| *index)
+                })
+                .collect()
+        };
+        indexes.sort_unstable();
+        indexes.dedup();
+
+        for index in indexes {
+            let endpoint = &self.endpoints[index];
+            let hash_mismatches = {
+                let mut health = endpoint.health.lock().unwrap();
+                health.hash_mismatches += 1;
+                health.hash_mismatches
+            };
+            warn!(
+                "endpoint {} for {} served blocks {:?} from another fork; {} hash mismatches so far",
+                endpoint.display_url(),
+                self.chain,
+                block_numbers,
+                hash_mismatches
+            );
+            endpoint.record_failure();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::client::mock::MockClient;
+    use anyhow::bail;
+
+    struct FailingClient;
+
+    #[async_trait]
+    impl Client for FailingClient {
+        async fn client_version(&self) -> Result<String> {
+            bail!("failing")
+        }
+
+        async fn get_latest_block_number(&self) -> Result<u64> {
+            bail!("failing")
+        }
+
+        async fn get_block(&self, _block_number: u64) -> Result<Option<Block>> {
+            bail!("failing")
+        }
+    }
+
+    /// Never answers.
+    struct SlowClient;
+
+    #[async_trait]
+    impl Client for SlowClient {
+        async fn client_version(&self) -> Result<String> {
+            future::pending().await
+        }
+
+        async fn get_latest_block_number(&self) -> Result<u64> {
+            future::pending().await
+        }
+
+        async fn get_block(&self, _block_number: u64) -> Result<Option<Block>> {
+            future::pending().await
+        }
+    }
+
+    fn chain() -> Chain {
+        Chain::try_from("ethereum").expect("chain")
+    }
+
+    fn endpoint(url: &str, priority: u32) -> Endpoint {
+        Endpoint {
+            priority,
+            ..Endpoint::new(url)
+        }
+    }
+
+    fn mock_client(head: u64) -> Box<dyn Client> {
+        let client = MockClient::new(chain(), 13);
+        client.set_blocks(0..=head, "a");
+        Box::new(client)
+    }
+
+    fn urls(client: &FailoverClient, block_number: Option<u64>) -> Vec<&str> {
+        let candidates = client.candidates(block_number);
+        candidates.into_iter().map(|e| e.display_url()).collect()
+    }
+
+    #[tokio::test]
+    async fn fail_over_on_errors() -> Result<()> {
+        let client = FailoverClient::new(
+            chain(),
+            vec![
+                (endpoint("backup", 1), mock_client(10)),
+                (endpoint("failing", 0), Box::new(FailingClient)),
+            ],
+            Tuning::for_chain(chain()),
+        );
+        assert_eq!(urls(&client, None), ["failing", "backup"]);
+
+        let block = client.get_block(5).await?.expect("block");
+        assert_eq!(block.block_number, 5);
+
+        // The failing endpoint loses its place after a couple of errors
+        let blocks = client.get_blocks(6..=8).await?;
+        assert_eq!(blocks.len(), 3);
+        assert_eq!(urls(&client, None), ["backup", "failing"]);
+
+        assert_eq!(client.get_latest_block_number().await?, 10);
+        assert_eq!(client.get_finalized_block_number().await?, 10);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn avoid_stale_endpoints() -> Result<()> {
+        let client = FailoverClient::new(
+            chain(),
+            vec![
+                (endpoint("stale", 0), mock_client(10)),
+                (endpoint("fresh", 1), mock_client(100)),
+            ],
+            Tuning::for_chain(chain()),
+        );
+
+        assert_eq!(client.get_latest_block_number().await?, 100);
+        // Only the fresh endpoint has the new blocks
+        assert_eq!(urls(&client, Some(50)), ["fresh", "stale"]);
+        assert_eq!(urls(&client, Some(5)), ["stale", "fresh"]);
+
+        // Staying stale makes the endpoint unhealthy
+        client.get_latest_block_number().await?;
+        assert_eq!(urls(&client, Some(5)), ["fresh", "stale"]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fail_over_on_missing_blocks() -> Result<()> {
+        let client = FailoverClient::new(
+            chain(),
+            vec![
+                (endpoint("lagging", 0), mock_client(10)),
+                (endpoint("fresh", 1), mock_client(20)),
+            ],
+            Tuning::for_chain(chain()),
+        );
+
+        let block = client.get_block(15).await?.expect("block");
+        assert_eq!(block.block_number, 15);
+        let blocks = client.get_blocks(8..=12).await?;
+        assert!(blocks.iter().all(Option::is_some));
+        // Not having the blocks another endpoint has is a failure
+        assert_eq!(urls(&client, None), ["fresh", "lagging"]);
+
+        // Blocks no endpoint has are missing
+        assert!(client.get_block(50).await?.is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn count_hash_mismatches() -> Result<()> {
+        let client = FailoverClient::new(
+            chain(),
+            vec![
+                (endpoint("primary", 0), mock_client(10)),
+                (endpoint("backup", 1), mock_client(10)),
+            ],
+            Tuning::for_chain(chain()),
+        );
+
+        client.get_blocks(1..=5).await?;
+        client.report_hash_mismatch(&[4, 5]);
+        client.report_hash_mismatch(&[5]);
+
+        let hash_mismatches = |index: usize| {
+            let health = client.endpoints[index].health.lock().unwrap();
+            health.hash_mismatches
+        };
+        assert_eq!(hash_mismatches(0), 2);
+        assert_eq!(hash_mismatches(1), 0);
+        // Serving another fork is a failure
+        assert_eq!(urls(&client, None), ["backup", "primary"]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn time_out_requests() -> Result<()> {
+        let tuning = Tuning {
+            request_timeout: Duration::from_millis(10),
+            ..Tuning::for_chain(chain())
+        };
+        let client = FailoverClient::new(
+            chain(),
+            vec![
+                (endpoint("slow", 0), Box::new(SlowClient)),
+                (endpoint("backup", 1), mock_client(10)),
+            ],
+            tuning,
+        );
+
+        let block = client.get_block(5).await?.expect("block");
+        assert_eq!(block.block_number, 5);
+        assert_eq!(client.get_latest_block_number().await?, 10);
+
+        Ok(())
+    }
+}
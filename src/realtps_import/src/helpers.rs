@@ -1,17 +1,22 @@
 use crate::client::Client;
-use crate::delay::{retry_if_err, retry_if_none};
-use anyhow::{anyhow, Result};
+use crate::delay::{retry_if_err, retry_if_none, Tuning};
+use anyhow::{anyhow, Context, Result};
 use log::debug;
 use realtps_common::{
     chain::Chain,
-    db::{Block, CalculationLog, Db},
+    db::{Block, CalculationLog, Db, ReorgEvent, TpsRecord},
 };
+use std::ops::RangeInclusive;
 use std::sync::Arc;
 use tokio::task;
 
-pub async fn fetch_live_head_block_number(chain: Chain, client: &dyn Client) -> Result<u64> {
+pub async fn fetch_live_head_block_number(
+    chain: Chain,
+    client: &dyn Client,
+    tuning: &Tuning,
+) -> Result<u64> {
     let live_head_block_number =
-        retry_if_err(chain, || Box::pin(client.get_latest_block_number())).await?;
+        retry_if_err(chain, tuning, || Box::pin(client.get_latest_block_number())).await?;
 
     debug!(
         "live head block number for {}: {}",
@@ -21,24 +26,69 @@ pub async fn fetch_live_head_block_number(chain: Chain, client: &dyn Client) ->
     Ok(live_head_block_number)
 }
 
-pub async fn fetch_block(chain: Chain, client: &dyn Client, block_number: u64) -> Result<Block> {
+pub async fn fetch_finalized_block_number(
+    chain: Chain,
+    client: &dyn Client,
+    tuning: &Tuning,
+) -> Result<u64> {
+    let finalized_block_number = retry_if_err(chain, tuning, || {
+        Box::pin(client.get_finalized_block_number())
+    })
+    .await?;
+
+    debug!(
+        "finalized block number for {}: {}",
+        chain, finalized_block_number
+    );
+
+    Ok(finalized_block_number)
+}
+
+pub async fn fetch_block(
+    chain: Chain,
+    client: &dyn Client,
+    tuning: &Tuning,
+    block_number: u64,
+) -> Result<Block> {
     debug!("fetching block {} for {}", block_number, chain);
 
-    let get_block = || retry_if_err(chain, || Box::pin(client.get_block(block_number)));
-    let maybe_block = retry_if_none(chain, || Box::pin(get_block())).await?;
+    let get_block = || retry_if_err(chain, tuning, || Box::pin(client.get_block(block_number)));
+    let maybe_block = retry_if_none(chain, tuning, || Box::pin(get_block())).await?;
     let block =
         maybe_block.ok_or_else(|| anyhow!("get block returned None for chain {}", chain))?;
 
     Ok(block)
 }
 
-pub async fn store_highest_known_block_number(
+/// Fetches the blocks in `block_numbers`, leaving out those the network
+/// doesn't have.
+pub async fn fetch_blocks(
+    chain: Chain,
+    client: &dyn Client,
+    tuning: &Tuning,
+    block_numbers: RangeInclusive<u64>,
+) -> Result<Vec<Block>> {
+    debug!("fetching blocks {:?} for {}", block_numbers, chain);
+
+    let blocks = retry_if_err(chain, tuning, || {
+        Box::pin(client.get_blocks(block_numbers.clone()))
+    })
+    .await?;
+
+    Ok(blocks.into_iter().flatten().collect())
+}
+
+pub async fn store_blocks_and_highest_known_block_number(
     chain: Chain,
     db: &Arc<dyn Db>,
+    blocks: Vec<Block>,
     block_number: u64,
 ) -> Result<()> {
     let db = db.clone();
-    task::spawn_blocking(move || db.store_highest_block_number(chain, block_number)).await??;
+    task::spawn_blocking(move || {
+        db.store_blocks_and_highest_block_number(chain, blocks, block_number)
+    })
+    .await??;
 
     debug!(
         "new highest known block number for {}: {}",
@@ -64,12 +114,44 @@ pub async fn load_highest_known_block_number(
     Ok(highest_known_block_number)
 }
 
+pub async fn load_finalized_block_number(chain: Chain, db: &Arc<dyn Db>) -> Result<Option<u64>> {
+    let db = db.clone();
+    let finalized_block_number =
+        task::spawn_blocking(move || db.load_finalized_block_number(chain)).await??;
+
+    debug!(
+        "finalized block number for {}: {:?}",
+        chain, finalized_block_number
+    );
+
+    Ok(finalized_block_number)
+}
+
+pub async fn store_finalized_block_number(
+    chain: Chain,
+    db: &Arc<dyn Db>,
+    block_number: u64,
+) -> Result<()> {
+    let db = db.clone();
+    task::spawn_blocking(move || db.store_finalized_block_number(chain, block_number)).await??;
+
+    debug!("new finalized block number for {}: {}", chain, block_number);
+
+    Ok(())
+}
+
 pub async fn store_block(db: &Arc<dyn Db>, block: Block) -> Result<()> {
     let db = db.clone();
     task::spawn_blocking(move || db.store_block(block)).await??;
     Ok(())
 }
 
+pub async fn store_blocks(db: &Arc<dyn Db>, blocks: Vec<Block>) -> Result<()> {
+    let db = db.clone();
+    task::spawn_blocking(move || db.store_blocks(blocks)).await??;
+    Ok(())
+}
+
 pub async fn load_block(
     chain: Chain,
     db: &Arc<dyn Db>,
@@ -80,25 +162,75 @@ pub async fn load_block(
     Ok(block)
 }
 
+pub async fn load_blocks_in_range(
+    chain: Chain,
+    db: &Arc<dyn Db>,
+    from: u64,
+    to: u64,
+) -> Result<Vec<Block>> {
+    let db = db.clone();
+    let blocks = task::spawn_blocking(move || db.load_blocks_in_range(chain, from, to)).await??;
+    Ok(blocks)
+}
+
+pub async fn find_block_at_or_before_timestamp(
+    chain: Chain,
+    db: &Arc<dyn Db>,
+    timestamp: u64,
+) -> Result<Option<Block>> {
+    let db = db.clone();
+    let block =
+        task::spawn_blocking(move || db.find_block_at_or_before_timestamp(chain, timestamp))
+            .await??;
+    Ok(block)
+}
+
 pub async fn remove_blocks(chain: Chain, db: &Arc<dyn Db>, blocks: Vec<u64>) -> Result<()> {
     let db = db.clone();
 
     task::spawn_blocking(move || {
         for block in blocks {
-            db.remove_block(chain, block).expect(&format!(
-                "error removing block {} for chain {}",
-                block, chain
-            ));
+            db.remove_block(chain, block)
+                .with_context(|| format!("error removing block {} for chain {}", block, chain))?;
         }
+        Ok(())
     })
-    .await?;
+    .await?
+}
+
+pub async fn remove_blocks_before(
+    chain: Chain,
+    db: &Arc<dyn Db>,
+    block_number: u64,
+) -> Result<u64> {
+    let db = db.clone();
+    let num_removed =
+        task::spawn_blocking(move || db.remove_blocks_before(chain, block_number)).await??;
+    Ok(num_removed)
+}
+
+pub async fn store_tps_and_calculation_log(
+    chain: Chain,
+    db: &Arc<dyn Db>,
+    tps: f64,
+    log: CalculationLog,
+) -> Result<()> {
+    let db = db.clone();
+    task::spawn_blocking(move || db.store_tps_and_calculation_log(chain, tps, &log)).await??;
+
+    Ok(())
+}
+
+pub async fn append_tps_record(chain: Chain, db: &Arc<dyn Db>, record: TpsRecord) -> Result<()> {
+    let db = db.clone();
+    task::spawn_blocking(move || db.append_tps_record(chain, &record)).await??;
 
     Ok(())
 }
 
-pub async fn write_log(chain: Chain, db: &Arc<dyn Db>, log: CalculationLog) -> Result<()> {
+pub async fn append_reorg_event(db: &Arc<dyn Db>, event: ReorgEvent) -> Result<()> {
     let db = db.clone();
-    task::spawn_blocking(move || db.store_calculation_log(chain, &log)).await??;
+    task::spawn_blocking(move || db.append_reorg_event(&event)).await??;
 
     Ok(())
 }
@@ -1,20 +1,36 @@
 use crate::client::Client;
-use crate::delay;
+use crate::delay::{self, Tuning};
 use crate::helpers::*;
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Result};
+use chrono::Utc;
+use futures::stream::{self, StreamExt, TryStreamExt};
 use log::{debug, info, warn};
 use realtps_common::{
     chain::Chain,
-    db::{Block, Db},
+    db::{Block, Db, ReorgEvent},
 };
+use std::collections::{hash_map::Entry, HashMap};
+use std::ops::RangeInclusive;
 use std::sync::Arc;
 
-pub async fn import(chain: Chain, client: &dyn Client, db: &Arc<dyn Db>) -> Result<()> {
-    let res = import_no_rescan_delay(chain, client, db).await;
+/// The most blocks `sync` fetches in one `get_blocks` call. Up to the chain's
+/// `max_concurrency` of these batches are fetched at once.
+const BLOCKS_PER_FETCH: u64 = 20;
+
+/// Imports the chain's new blocks up to its finalized block, or with
+/// `include_unfinalized`, up to its latest block.
+pub async fn import(
+    chain: Chain,
+    client: &dyn Client,
+    tuning: &Tuning,
+    db: &Arc<dyn Db>,
+    include_unfinalized: bool,
+) -> Result<()> {
+    let res = import_no_rescan_delay(chain, client, tuning, db, include_unfinalized).await;
 
     match res {
         Ok(res) => {
-            delay::rescan_delay(chain).await;
+            delay::rescan_delay(tuning).await;
             Ok(res)
         }
         Err(e) => {
@@ -24,18 +40,36 @@ pub async fn import(chain: Chain, client: &dyn Client, db: &Arc<dyn Db>) -> Resu
     }
 }
 
-async fn import_no_rescan_delay(chain: Chain, client: &dyn Client, db: &Arc<dyn Db>) -> Result<()> {
+async fn import_no_rescan_delay(
+    chain: Chain,
+    client: &dyn Client,
+    tuning: &Tuning,
+    db: &Arc<dyn Db>,
+    include_unfinalized: bool,
+) -> Result<()> {
     info!("beginning import for {}", chain);
 
     let highest_known_block_number = load_highest_known_block_number(chain, db).await?;
-    let live_head_block_number = fetch_live_head_block_number(chain, client).await?;
+    let live_head_block_number = fetch_live_head_block_number(chain, client, tuning).await?;
+    let finalized_block_number = fetch_finalized_block_number(chain, client, tuning)
+        .await?
+        .min(live_head_block_number);
+
+    // Finalized blocks can't be replaced by a reorg, so unless asked to, we
+    // stop at the finalized block, and have no reorgs to follow.
+    let target_block_number = if include_unfinalized {
+        live_head_block_number
+    } else {
+        finalized_block_number
+    };
 
     // If we've never synced this chain before, then just establish the first
     // few blocks, and the highest_known_block_number, and wait until next time.
     {
         let first_import = highest_known_block_number.is_none();
         if first_import {
-            import_first_blocks(chain, client, db, live_head_block_number).await?;
+            import_first_blocks(chain, client, tuning, db, target_block_number).await?;
+            store_finalized_block_number(chain, db, finalized_block_number).await?;
             return Ok(());
         }
     }
@@ -43,15 +77,19 @@ async fn import_no_rescan_delay(chain: Chain, client: &dyn Client, db: &Arc<dyn
     // todo: this and the above could be a let-else expr
     let highest_known_block_number = highest_known_block_number.unwrap();
 
-    if live_head_block_number == highest_known_block_number {
+    if target_block_number == highest_known_block_number {
         info!("no new blocks for chain {}", chain);
+        store_finalized_block_number_if_on_chain(chain, client, tuning, db, finalized_block_number)
+            .await?;
         return Ok(());
-    } else if live_head_block_number < highest_known_block_number {
-        warn!("live_head_block_number < highest_known_block_number for chain {}. head: {}; highest: {}",
-              chain, live_head_block_number, highest_known_block_number);
+    } else if target_block_number < highest_known_block_number {
+        warn!("target_block_number < highest_known_block_number for chain {}. target: {}; highest: {}",
+              chain, target_block_number, highest_known_block_number);
+        store_finalized_block_number_if_on_chain(chain, client, tuning, db, finalized_block_number)
+            .await?;
         return Ok(());
     } else {
-        let needed_blocks = live_head_block_number
+        let needed_blocks = target_block_number
             .checked_sub(highest_known_block_number)
             .expect("underflow");
         info!("importing at least {} blocks for {}", needed_blocks, chain);
@@ -60,42 +98,111 @@ async fn import_no_rescan_delay(chain: Chain, client: &dyn Client, db: &Arc<dyn
     sync(
         chain,
         client,
+        tuning,
         db,
         highest_known_block_number,
-        live_head_block_number,
+        target_block_number,
     )
     .await?;
 
+    // Every stored block up to it is now on the finalized chain
+    store_finalized_block_number(chain, db, finalized_block_number).await?;
+
     Ok(())
 }
 
-/// Fetches and stores blocks starting from `live_head_block_number`, working
+/// Stores the finalized block number when no blocks were synced, but only if
+/// the stored block at it is the client's, since with unfinalized blocks
+/// included, a reorg may have replaced it since it was stored.
+async fn store_finalized_block_number_if_on_chain(
+    chain: Chain,
+    client: &dyn Client,
+    tuning: &Tuning,
+    db: &Arc<dyn Db>,
+    finalized_block_number: u64,
+) -> Result<()> {
+    let stored_block = match load_block(chain, db, finalized_block_number).await? {
+        Some(block) => block,
+        None => {
+            debug!(
+                "no stored block {} for {} to check finality against",
+                finalized_block_number, chain
+            );
+            return Ok(());
+        }
+    };
+
+    let live_block = delay::retry_if_err(chain, tuning, || {
+        Box::pin(client.get_block(finalized_block_number))
+    })
+    .await?;
+
+    if live_block.map(|block| block.hash) == Some(stored_block.hash) {
+        store_finalized_block_number(chain, db, finalized_block_number).await?;
+    } else {
+        warn!(
+            "stored block {} for {} is not on the finalized chain; leaving finalized block number",
+            finalized_block_number, chain
+        );
+    }
+
+    Ok(())
+}
+
+/// Fetches and stores blocks starting from `target_block_number`, working
 /// backwards until it reaches `highest_known_block_number`, accounting for
 /// chain reorgs, and missing blocks from previous imports, and finally storing
 /// a new highest known block number to disk.
+///
+/// Blocks are fetched a window at a time, see `fetch_window`.
 async fn sync(
     chain: Chain,
     client: &dyn Client,
+    tuning: &Tuning,
     db: &Arc<dyn Db>,
     highest_known_block_number: u64,
-    live_head_block_number: u64,
+    target_block_number: u64,
 ) -> Result<()> {
-    // todo: this doesn't check whether the blocks we're receiving have hash
-    // chains that are consistent - we could be in the middle of a reorg, or get
-    // conflicting info from different nodes behind a load balancer. The latter
-    // case could leave us with blocks that aren't actually in the chain.
-
-    let mut block_number = live_head_block_number;
+    let mut block_number = target_block_number;
     let joined_chain_block_number;
     let joined_chain_block_hash;
 
-    loop {
-        let block = fetch_block(chain, client, block_number).await?;
+    let window_size = BLOCKS_PER_FETCH * tuning.max_concurrency as u64;
+    // The current window
+    let mut fetched_blocks = HashMap::new();
+    // The last block fetched, which the next one must be the parent of
+    let mut successor: Option<Block> = None;
+    // The reorg being followed down the chain, recorded once it ends
+    let mut reorg: Option<ReorgEvent> = None;
+    // The blocks replacing those of the reorg, only stored once it is
+    // recorded, so that no reorg replaces blocks without a record of it
+    let mut reorg_blocks = vec![];
+
+    let last_block = loop {
+        if !fetched_blocks.contains_key(&block_number) {
+            // Only blocks above the highest known block are fetched ahead, as
+            // below it we're following a reorg one block at a time.
+            let lowest_block_number = block_number
+                .saturating_sub(window_size - 1)
+                .max(highest_known_block_number + 1)
+                .min(block_number);
+            fetched_blocks = fetch_window(
+                chain,
+                client,
+                tuning,
+                lowest_block_number,
+                block_number,
+                successor.as_ref(),
+            )
+            .await?;
+
+            delay::courtesy_delay(tuning).await;
+        }
+
+        let block = fetched_blocks.remove(&block_number).expect("fetched block");
         let prev_block_number = block.prev_block_number.expect("not genesis block");
         let prev_block_hash = block.parent_hash.clone();
 
-        store_block(db, block).await?;
-
         let prev_stored_block = load_block(chain, db, prev_block_number).await?;
 
         // If we already have the block then we need to decide whether we have
@@ -107,11 +214,21 @@ async fn sync(
         let block_number_to_fetch_next = if let Some(prev_stored_block) = prev_stored_block {
             let chain_reorg = prev_stored_block.hash != prev_block_hash;
             if !chain_reorg {
+                if let Some(reorg) = reorg.take() {
+                    info!(
+                        "reorg of chain {} replaced {} blocks from block {}",
+                        chain, reorg.depth, reorg.block_number
+                    );
+                    append_reorg_event(db, reorg).await?;
+                    store_blocks(db, std::mem::take(&mut reorg_blocks)).await?;
+                }
+
                 if prev_block_number <= highest_known_block_number {
                     // We did it!
                     joined_chain_block_number = prev_block_number;
                     joined_chain_block_hash = prev_block_hash;
-                    break;
+                    // Stored together with the new highest known block number
+                    break block;
                 } else {
                     // This is a block we've seen before, but it has a higher block
                     // number than our highest_known_block. This indicates a previous
@@ -125,6 +242,19 @@ async fn sync(
                     "reorg of chain {} at block {}; old hash: {}; new hash: {}",
                     chain, prev_block_number, prev_stored_block.hash, prev_block_hash
                 );
+                match reorg {
+                    Some(ref mut reorg) => reorg.depth += 1,
+                    None => {
+                        reorg = Some(ReorgEvent {
+                            chain,
+                            block_number: prev_block_number,
+                            old_hash: prev_stored_block.hash,
+                            new_hash: prev_block_hash,
+                            depth: 1,
+                            detected_at: Utc::now(),
+                        })
+                    }
+                }
                 // continue - have wrong version of prev block
                 prev_block_number
             }
@@ -132,16 +262,22 @@ async fn sync(
             prev_block_number
         };
 
+        if reorg.is_some() {
+            reorg_blocks.push(block.clone());
+        } else {
+            store_block(db, block.clone()).await?;
+        }
+        successor = Some(block);
+
         debug!(
             "still need block {} for {}",
             block_number_to_fetch_next, chain
         );
         block_number = block_number_to_fetch_next;
+    };
 
-        delay::courtesy_delay(chain).await;
-    }
-
-    store_highest_known_block_number(chain, db, live_head_block_number).await?;
+    store_blocks_and_highest_known_block_number(chain, db, vec![last_block], target_block_number)
+        .await?;
 
     info!(
         "completed import of chain {} to block {} / {}",
@@ -154,30 +290,147 @@ async fn sync(
 async fn import_first_blocks(
     chain: Chain,
     client: &dyn Client,
+    tuning: &Tuning,
     db: &Arc<dyn Db>,
     head_block_number: u64,
 ) -> Result<()> {
     info!("importing first blocks for {}", chain);
 
-    let head_block = fetch_block(chain, client, head_block_number).await?;
+    let head_block = fetch_block(chain, client, tuning, head_block_number).await?;
     let prev_block_number = head_block.prev_block_number.expect("not genesis block");
     let prev_block_hash = head_block.parent_hash.clone();
-    let prev_block = fetch_block(chain, client, prev_block_number).await?;
+    let prev_block = fetch_block(chain, client, tuning, prev_block_number).await?;
 
     if prev_block_hash != prev_block.hash {
         // Immediate reorg. We'll just let the job scheduler try again.
         return Err(anyhow!("first blocks' hashes don't match for {}", chain));
     }
 
-    store_block(db, head_block).await?;
-    store_block(db, prev_block).await?;
-    store_highest_known_block_number(chain, db, head_block_number).await?;
+    store_blocks_and_highest_known_block_number(
+        chain,
+        db,
+        vec![head_block, prev_block],
+        head_block_number,
+    )
+    .await?;
 
     info!("completed first import for {}", chain);
 
     Ok(())
 }
 
+/// Fetches the blocks from `highest_block_number` down to
+/// `lowest_block_number`.
+///
+/// The blocks must form a hash chain with each other and with `successor`, the
+/// block already fetched after them. Where they don't, a node behind a load
+/// balancer may have served a block from another fork, so the conflicting
+/// blocks are fetched again, and the mismatch is reported to the client.
+async fn fetch_window(
+    chain: Chain,
+    client: &dyn Client,
+    tuning: &Tuning,
+    lowest_block_number: u64,
+    highest_block_number: u64,
+    successor: Option<&Block>,
+) -> Result<HashMap<u64, Block>> {
+    debug!(
+        "fetching window of blocks {} to {} for {}",
+        lowest_block_number, highest_block_number, chain
+    );
+
+    let tries = tuning.tries;
+    let mut try_num = 1;
+    let mut block_numbers = lowest_block_number..=highest_block_number;
+    let mut blocks = HashMap::new();
+
+    loop {
+        let fetched = fetch_blocks_concurrently(chain, client, tuning, block_numbers.clone());
+        for block in fetched.await? {
+            blocks.insert(block.block_number, block);
+        }
+        // The block needed next must be there, so try again, harder
+        if let Entry::Vacant(entry) = blocks.entry(highest_block_number) {
+            entry.insert(fetch_block(chain, client, tuning, highest_block_number).await?);
+        }
+
+        let (block_number, prev_block_number) = match find_hash_mismatch(&blocks, successor) {
+            Some((block, prev_block)) => {
+                warn!(
+                    "hash mismatch for {}: block {} has parent hash {}, but block {} has hash {}",
+                    chain,
+                    block.block_number,
+                    block.parent_hash,
+                    prev_block.block_number,
+                    prev_block.hash
+                );
+                (block.block_number, prev_block.block_number)
+            }
+            None => return Ok(blocks),
+        };
+        client.report_hash_mismatch(&[block_number, prev_block_number]);
+
+        if try_num >= tries {
+            // Probably a reorg while fetching. Let the job scheduler try again.
+            // If it's the successor that is from another fork, the next import
+            // will replace it as a reorg.
+            bail!(
+                "fetched blocks for {} don't form a hash chain after {} tries",
+                chain,
+                tries
+            );
+        }
+        try_num += 1;
+
+        // The successor is already stored, so only refetch the window's blocks
+        block_numbers = prev_block_number..=block_number.min(highest_block_number);
+        info!("refetching blocks {:?} for {}", block_numbers, chain);
+        for block_number in block_numbers.clone() {
+            blocks.remove(&block_number);
+        }
+    }
+}
+
+/// Fetches blocks in batches, of which up to the chain's `max_concurrency` are
+/// in flight at once.
+async fn fetch_blocks_concurrently(
+    chain: Chain,
+    client: &dyn Client,
+    tuning: &Tuning,
+    block_numbers: RangeInclusive<u64>,
+) -> Result<Vec<Block>> {
+    let max_concurrency = tuning.max_concurrency;
+    let lowest_block_number = *block_numbers.start();
+    let batches = block_numbers
+        .rev()
+        .step_by(BLOCKS_PER_FETCH as usize)
+        .map(|last| {
+            let first = last
+                .saturating_sub(BLOCKS_PER_FETCH - 1)
+                .max(lowest_block_number);
+            first..=last
+        });
+    let batches: Vec<Vec<Block>> = stream::iter(batches)
+        .map(|block_numbers| fetch_blocks(chain, client, tuning, block_numbers))
+        .buffered(max_concurrency)
+        .try_collect()
+        .await?;
+
+    Ok(batches.into_iter().flatten().collect())
+}
+
+/// Finds a block, among `blocks` and `successor`, whose parent in `blocks`
+/// doesn't have its parent hash. Returns the block and its parent.
+fn find_hash_mismatch<'a>(
+    blocks: &'a HashMap<u64, Block>,
+    successor: Option<&'a Block>,
+) -> Option<(&'a Block, &'a Block)> {
+    blocks.values().chain(successor).find_map(|block| {
+        let prev_block = blocks.get(&block.prev_block_number?)?;
+        (prev_block.hash != block.parent_hash).then_some((block, prev_block))
+    })
+}
+
 /// Starting from a known good block, fast-forward until we see a block with a
 /// hash mismatch, or that we don't have yet.
 async fn fast_forward(chain: Chain, db: &Arc<dyn Db>, known_block: Block) -> Result<u64> {
@@ -211,3 +464,333 @@ async fn fast_forward(chain: Chain, db: &Arc<dyn Db>, known_block: Block) -> Res
 
     Ok(next_block_number_to_sync)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::client::mock::MockClient;
+    use async_trait::async_trait;
+    use chrono::TimeZone;
+    use realtps_common::db::MemoryDb;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+    use std::time::Duration;
+    use tokio::time;
+
+    // Solana has no courtesy delay
+    fn chain() -> Chain {
+        Chain::try_from("solana").expect("chain")
+    }
+
+    fn tuning() -> Tuning {
+        Tuning::for_chain(chain())
+    }
+
+    /// Counts the most requests in flight at once.
+    struct CountingClient {
+        client: MockClient,
+        in_flight: AtomicUsize,
+        max_in_flight: AtomicUsize,
+    }
+
+    /// Fails to get one block.
+    struct FailingAtClient {
+        client: MockClient,
+        fail_at: Mutex<Option<u64>>,
+    }
+
+    #[async_trait]
+    impl Client for FailingAtClient {
+        async fn client_version(&self) -> Result<String> {
+            self.client.client_version().await
+        }
+
+        async fn get_latest_block_number(&self) -> Result<u64> {
+            self.client.get_latest_block_number().await
+        }
+
+        async fn get_block(&self, block_number: u64) -> Result<Option<Block>> {
+            if *self.fail_at.lock().unwrap() == Some(block_number) {
+                bail!("failing at block {}", block_number);
+            }
+            self.client.get_block(block_number).await
+        }
+    }
+
+    #[async_trait]
+    impl Client for CountingClient {
+        async fn client_version(&self) -> Result<String> {
+            self.client.client_version().await
+        }
+
+        async fn get_latest_block_number(&self) -> Result<u64> {
+            self.client.get_latest_block_number().await
+        }
+
+        async fn get_block(&self, block_number: u64) -> Result<Option<Block>> {
+            let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+            time::sleep(Duration::from_millis(1)).await;
+            let block = self.client.get_block(block_number).await;
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            block
+        }
+    }
+
+    #[tokio::test]
+    async fn import_then_sync() -> Result<()> {
+        let client = MockClient::new(chain(), 1);
+        let memory_db = Arc::new(MemoryDb::new());
+        let db: Arc<dyn Db> = memory_db.clone();
+
+        client.set_blocks(0..=10, "a");
+        import_no_rescan_delay(chain(), &client, &tuning(), &db, false).await?;
+        assert_eq!(db.load_highest_block_number(chain())?, Some(10));
+        assert_eq!(memory_db.block_numbers(chain()), vec![9, 10]);
+
+        client.set_blocks(11..=20, "a");
+        import_no_rescan_delay(chain(), &client, &tuning(), &db, false).await?;
+        assert_eq!(db.load_highest_block_number(chain())?, Some(20));
+        assert_eq!(
+            memory_db.block_numbers(chain()),
+            (9..=20).collect::<Vec<_>>()
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn import_finalized_blocks() -> Result<()> {
+        let client = MockClient::new(chain(), 1);
+        let memory_db = Arc::new(MemoryDb::new());
+        let db: Arc<dyn Db> = memory_db.clone();
+        client.set_unfinalized_blocks(3);
+
+        client.set_blocks(0..=10, "a");
+        import_no_rescan_delay(chain(), &client, &tuning(), &db, false).await?;
+        assert_eq!(db.load_highest_block_number(chain())?, Some(7));
+        assert_eq!(db.load_finalized_block_number(chain())?, Some(7));
+
+        client.set_blocks(11..=20, "a");
+        import_no_rescan_delay(chain(), &client, &tuning(), &db, false).await?;
+        assert_eq!(db.load_highest_block_number(chain())?, Some(17));
+        assert_eq!(db.load_finalized_block_number(chain())?, Some(17));
+        assert_eq!(
+            memory_db.block_numbers(chain()),
+            (6..=17).collect::<Vec<_>>()
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn import_unfinalized_blocks() -> Result<()> {
+        let client = MockClient::new(chain(), 1);
+        let memory_db = Arc::new(MemoryDb::new());
+        let db: Arc<dyn Db> = memory_db.clone();
+        client.set_unfinalized_blocks(3);
+
+        client.set_blocks(0..=10, "a");
+        import_no_rescan_delay(chain(), &client, &tuning(), &db, true).await?;
+        assert_eq!(db.load_highest_block_number(chain())?, Some(10));
+        assert_eq!(db.load_finalized_block_number(chain())?, Some(7));
+
+        client.set_blocks(11..=20, "a");
+        import_no_rescan_delay(chain(), &client, &tuning(), &db, true).await?;
+        assert_eq!(db.load_highest_block_number(chain())?, Some(20));
+        assert_eq!(db.load_finalized_block_number(chain())?, Some(17));
+
+        // Unfinalized blocks can be replaced
+        client.set_blocks(19..=22, "b");
+        import_no_rescan_delay(chain(), &client, &tuning(), &db, true).await?;
+        assert_eq!(db.load_highest_block_number(chain())?, Some(22));
+        assert_eq!(db.load_finalized_block_number(chain())?, Some(19));
+        assert_eq!(db.load_block(chain(), 19)?.expect("block").hash, "b19");
+        assert_eq!(db.load_block(chain(), 18)?.expect("block").hash, "a18");
+
+        // Finality advances without new blocks
+        client.set_unfinalized_blocks(0);
+        import_no_rescan_delay(chain(), &client, &tuning(), &db, true).await?;
+        assert_eq!(db.load_highest_block_number(chain())?, Some(22));
+        assert_eq!(db.load_finalized_block_number(chain())?, Some(22));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn finality_not_advanced_over_replaced_blocks() -> Result<()> {
+        let client = MockClient::new(chain(), 1);
+        let memory_db = Arc::new(MemoryDb::new());
+        let db: Arc<dyn Db> = memory_db.clone();
+        client.set_unfinalized_blocks(3);
+
+        client.set_blocks(0..=10, "a");
+        import_no_rescan_delay(chain(), &client, &tuning(), &db, true).await?;
+        assert_eq!(db.load_finalized_block_number(chain())?, Some(7));
+
+        // The stored head was replaced at the same height, then finalized
+        client.set_blocks(9..=10, "b");
+        client.set_unfinalized_blocks(0);
+        import_no_rescan_delay(chain(), &client, &tuning(), &db, true).await?;
+        assert_eq!(db.load_highest_block_number(chain())?, Some(10));
+        assert_eq!(db.load_finalized_block_number(chain())?, Some(7));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sync_through_reorg() -> Result<()> {
+        let client = MockClient::new(chain(), 1);
+        let memory_db = Arc::new(MemoryDb::new());
+        let db: Arc<dyn Db> = memory_db.clone();
+
+        client.set_blocks(0..=10, "a");
+        import_no_rescan_delay(chain(), &client, &tuning(), &db, false).await?;
+        client.set_blocks(11..=20, "a");
+        import_no_rescan_delay(chain(), &client, &tuning(), &db, false).await?;
+
+        client.set_blocks(15..=25, "b");
+        import_no_rescan_delay(chain(), &client, &tuning(), &db, false).await?;
+        assert_eq!(db.load_highest_block_number(chain())?, Some(25));
+
+        let blocks = memory_db.blocks(chain());
+        assert_eq!(blocks.len(), 17);
+        for pair in blocks.windows(2) {
+            assert_eq!(pair[0].hash, pair[1].parent_hash);
+        }
+        assert_eq!(blocks[6].hash, "b15");
+
+        let reorgs = db.load_reorg_events(chain(), Utc.timestamp(0, 0), Utc::now())?;
+        assert_eq!(reorgs.len(), 1);
+        assert_eq!(reorgs[0].block_number, 20);
+        assert_eq!(reorgs[0].old_hash, "a20");
+        assert_eq!(reorgs[0].new_hash, "b20");
+        assert_eq!(reorgs[0].depth, 6);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn record_reorg_before_replacing_blocks() -> Result<()> {
+        let client = FailingAtClient {
+            client: MockClient::new(chain(), 1),
+            fail_at: Mutex::new(None),
+        };
+        let memory_db = Arc::new(MemoryDb::new());
+        let db: Arc<dyn Db> = memory_db.clone();
+
+        client.client.set_blocks(0..=10, "a");
+        import_no_rescan_delay(chain(), &client, &tuning(), &db, false).await?;
+        client.client.set_blocks(11..=20, "a");
+        import_no_rescan_delay(chain(), &client, &tuning(), &db, false).await?;
+
+        // Failing while following the reorg leaves the old blocks
+        client.client.set_blocks(15..=25, "b");
+        *client.fail_at.lock().unwrap() = Some(17);
+        assert!(
+            import_no_rescan_delay(chain(), &client, &tuning(), &db, false)
+                .await
+                .is_err()
+        );
+        assert_eq!(db.load_block(chain(), 20)?.expect("block").hash, "a20");
+        assert_eq!(db.load_block(chain(), 18)?.expect("block").hash, "a18");
+        let reorgs = db.load_reorg_events(chain(), Utc.timestamp(0, 0), Utc::now())?;
+        assert!(reorgs.is_empty());
+
+        *client.fail_at.lock().unwrap() = None;
+        import_no_rescan_delay(chain(), &client, &tuning(), &db, false).await?;
+        assert_eq!(db.load_block(chain(), 20)?.expect("block").hash, "b20");
+        let reorgs = db.load_reorg_events(chain(), Utc.timestamp(0, 0), Utc::now())?;
+        assert_eq!(reorgs.len(), 1);
+        assert_eq!(reorgs[0].depth, 6);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sync_in_windows() -> Result<()> {
+        let client = MockClient::new(chain(), 1);
+        let memory_db = Arc::new(MemoryDb::new());
+        let db: Arc<dyn Db> = memory_db.clone();
+
+        client.set_blocks(0..=10, "a");
+        import_no_rescan_delay(chain(), &client, &tuning(), &db, false).await?;
+
+        // Several windows, the last a partial one
+        client.set_blocks(11..=500, "a");
+        import_no_rescan_delay(chain(), &client, &tuning(), &db, false).await?;
+        assert_eq!(db.load_highest_block_number(chain())?, Some(500));
+        assert_eq!(
+            memory_db.block_numbers(chain()),
+            (9..=500).collect::<Vec<_>>()
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reject_inconsistent_window() -> Result<()> {
+        let client = MockClient::new(chain(), 1);
+        let memory_db = Arc::new(MemoryDb::new());
+        let db: Arc<dyn Db> = memory_db.clone();
+
+        client.set_blocks(0..=10, "a");
+        import_no_rescan_delay(chain(), &client, &tuning(), &db, false).await?;
+
+        // Block 16 never follows block 15
+        client.set_blocks(11..=20, "a");
+        client.set_blocks(15..=15, "b");
+        assert!(
+            import_no_rescan_delay(chain(), &client, &tuning(), &db, false)
+                .await
+                .is_err()
+        );
+        assert_eq!(db.load_highest_block_number(chain())?, Some(10));
+        assert_eq!(memory_db.block_numbers(chain()), vec![9, 10]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn refetch_conflicting_blocks() -> Result<()> {
+        let client = MockClient::new(chain(), 1);
+        let memory_db = Arc::new(MemoryDb::new());
+        let db: Arc<dyn Db> = memory_db.clone();
+
+        client.set_blocks(0..=10, "a");
+        import_no_rescan_delay(chain(), &client, &tuning(), &db, false).await?;
+
+        client.set_blocks(11..=20, "a");
+        client.serve_fork_once(15, "b");
+        import_no_rescan_delay(chain(), &client, &tuning(), &db, false).await?;
+        assert_eq!(db.load_highest_block_number(chain())?, Some(20));
+
+        let blocks = memory_db.blocks(chain());
+        for pair in blocks.windows(2) {
+            assert_eq!(pair[0].hash, pair[1].parent_hash);
+        }
+        assert_eq!(blocks[6].hash, "a15");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn bound_requests_in_flight() -> Result<()> {
+        let client = CountingClient {
+            client: MockClient::new(chain(), 1),
+            in_flight: AtomicUsize::new(0),
+            max_in_flight: AtomicUsize::new(0),
+        };
+        client.client.set_blocks(0..=100, "a");
+        let tuning = Tuning {
+            max_concurrency: 3,
+            ..tuning()
+        };
+
+        let blocks = fetch_blocks_concurrently(chain(), &client, &tuning, 1..=100).await?;
+        assert_eq!(blocks.len(), 100);
+        assert_eq!(client.max_in_flight.load(Ordering::SeqCst), 3);
+
+        Ok(())
+    }
+}
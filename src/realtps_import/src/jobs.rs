@@ -1,16 +1,16 @@
 use crate::calculate;
 use crate::client::Client;
-use crate::delay;
+use crate::delay::{self, Tuning};
 use crate::import;
 use crate::remove;
-use anyhow::{Context, Result};
+use anyhow::Result;
 use futures::future::FutureExt;
 use futures::stream::{FuturesUnordered, StreamExt};
 use log::{error, info};
 use rand::prelude::*;
 use realtps_common::{chain::Chain, db::Db};
-use std::collections::HashMap;
-use std::sync::Arc;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::Instant;
 use tokio::task;
 
@@ -22,10 +22,76 @@ pub enum Job {
 
 pub struct JobRunner {
     pub db: Arc<dyn Db>,
-    pub clients: HashMap<Chain, Box<dyn Client>>,
+    /// Replaced when the RPC configuration is reloaded. Import jobs that are
+    /// running keep the client and tuning they started with.
+    clients: RwLock<HashMap<Chain, (Arc<dyn Client>, Tuning)>>,
+    /// The chains with an import job
+    importing: Mutex<HashSet<Chain>>,
+    /// Whether to import blocks that aren't final yet
+    include_unfinalized: bool,
 }
 
 impl JobRunner {
+    pub fn new(
+        db: Arc<dyn Db>,
+        clients: HashMap<Chain, (Box<dyn Client>, Tuning)>,
+        include_unfinalized: bool,
+    ) -> JobRunner {
+        let clients = clients
+            .into_iter()
+            .map(|(chain, (client, tuning))| (chain, (Arc::from(client), tuning)))
+            .collect();
+
+        JobRunner {
+            db,
+            clients: RwLock::new(clients),
+            importing: Mutex::new(HashSet::new()),
+            include_unfinalized,
+        }
+    }
+
+    pub fn has_client(&self, chain: Chain) -> bool {
+        self.clients.read().unwrap().contains_key(&chain)
+    }
+
+    /// The job to import a chain, unless it has no client or is already
+    /// being imported.
+    pub fn start_import(&self, chain: Chain) -> Option<Job> {
+        let mut importing = self.importing.lock().unwrap();
+        if self.has_client(chain) && importing.insert(chain) {
+            Some(Job::Import(chain))
+        } else {
+            None
+        }
+    }
+
+    /// Replaces the clients of some chains and removes the clients of
+    /// others, whose import jobs then stop. Returns the jobs to import the
+    /// chains that didn't have a client before.
+    pub fn update_clients(
+        &self,
+        clients: HashMap<Chain, (Box<dyn Client>, Tuning)>,
+        removed: &[Chain],
+    ) -> Vec<Job> {
+        // Held throughout so that import jobs don't stop in the meantime
+        let mut importing = self.importing.lock().unwrap();
+        let mut current_clients = self.clients.write().unwrap();
+
+        for chain in removed {
+            current_clients.remove(chain);
+        }
+
+        let mut jobs = vec![];
+        for (chain, (client, tuning)) in clients {
+            current_clients.insert(chain, (Arc::from(client), tuning));
+            if importing.insert(chain) {
+                jobs.push(Job::Import(chain));
+            }
+        }
+
+        jobs
+    }
+
     pub async fn do_job(&self, job: Job) -> Vec<Job> {
         let r = match job {
             Job::Import(chain) => self.import(chain).await,
@@ -45,15 +111,38 @@ impl JobRunner {
     }
 
     async fn import(&self, chain: Chain) -> Result<Vec<Job>> {
-        let client = self
-            .clients
-            .get(&chain)
-            .context(format!("no client for {}", chain))?;
-        import::import(chain, client.as_ref(), &self.db).await?;
+        let (client, tuning) = match self.client_or_stop_import(chain) {
+            Some(client) => client,
+            None => {
+                info!(
+                    "stopping import for {}, which no longer has a client",
+                    chain
+                );
+                return Ok(vec![]);
+            }
+        };
+        import::import(
+            chain,
+            client.as_ref(),
+            &tuning,
+            &self.db,
+            self.include_unfinalized,
+        )
+        .await?;
 
         Ok(vec![Job::Import(chain)])
     }
 
+    /// The chain's client and tuning, or if it has none, ends its import job.
+    fn client_or_stop_import(&self, chain: Chain) -> Option<(Arc<dyn Client>, Tuning)> {
+        let mut importing = self.importing.lock().unwrap();
+        let client = self.clients.read().unwrap().get(&chain).cloned();
+        if client.is_none() {
+            importing.remove(&chain);
+        }
+        client
+    }
+
     async fn calculate(&self, chains: Vec<Chain>) -> Result<Vec<Job>> {
         info!("beginning tps calculation");
 
@@ -74,8 +163,6 @@ impl JobRunner {
             match calcs {
                 Ok(calcs) => {
                     info!("calculated {} tps for chain {}", calcs.tps, calcs.chain);
-                    let db = self.db.clone();
-                    task::spawn_blocking(move || db.store_tps(calcs.chain, calcs.tps)).await??;
                 }
                 Err(e) => {
                     print_error(&e);
@@ -118,3 +205,41 @@ fn print_error(e: &anyhow::Error) {
         source = source_.source();
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::client::mock::MockClient;
+    use realtps_common::db::MemoryDb;
+
+    fn mock_clients(chain: Chain) -> HashMap<Chain, (Box<dyn Client>, Tuning)> {
+        let client: Box<dyn Client> = Box::new(MockClient::new(chain, 1));
+        vec![(chain, (client, Tuning::for_chain(chain)))]
+            .into_iter()
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn start_and_stop_imports() -> Result<()> {
+        let chain = Chain::try_from("ethereum")?;
+        let job_runner = JobRunner::new(Arc::new(MemoryDb::new()), HashMap::new(), false);
+        assert!(job_runner.start_import(chain).is_none());
+
+        // A new client starts an import, but only one
+        let jobs = job_runner.update_clients(mock_clients(chain), &[]);
+        assert!(matches!(jobs[..], [Job::Import(c)] if c == chain));
+        assert!(job_runner.start_import(chain).is_none());
+        let jobs = job_runner.update_clients(mock_clients(chain), &[]);
+        assert!(jobs.is_empty());
+
+        // Removing the client stops the import
+        job_runner.update_clients(HashMap::new(), &[chain]);
+        assert!(job_runner.import(chain).await?.is_empty());
+        assert!(job_runner.start_import(chain).is_none());
+
+        let jobs = job_runner.update_clients(mock_clients(chain), &[]);
+        assert_eq!(jobs.len(), 1);
+
+        Ok(())
+    }
+}
@@ -1,39 +1,73 @@
-use anyhow::{Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use clap::{Parser, Subcommand};
 use client::Client;
 use clients::*;
-use delay::retry_if_err;
-use futures::future::FutureExt;
+use delay::{retry_if_err, Tuning};
+use failover::FailoverClient;
+use futures::future::{self, FutureExt};
 use futures::stream::{FuturesUnordered, StreamExt};
 use jobs::{Job, JobRunner};
-use log::{error, info};
+use log::{error, info, warn};
 use realtps_common::{
-    chain::{Chain, ChainType},
-    db::JsonDb,
+    chain::{self, Chain, ChainType, CHAIN_REGISTRY_PATH},
+    db::{ChainLock, Db, DbConfig, MemoryDb},
+};
+use reload::{ConfigWatcher, Reloader};
+use rpc_config::{
+    load_rpc_config, ConfigProblem, Endpoint, RpcConfig, RPC_CONFIG_PATH, RPC_SECRETS_PATH,
 };
-use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::fs;
-use std::path::Path;
+use std::path::PathBuf;
 use std::sync::Arc;
-use tokio::task;
+use tokio::{task, time};
 
 mod calculate;
 mod client;
 mod clients;
 mod delay;
+mod failover;
 mod helpers;
 mod import;
 mod jobs;
+mod migrate;
+mod reload;
 mod remove;
+mod reorgs;
+mod rpc_config;
+mod snapshot;
+mod upgrade;
+mod verify;
 
 #[derive(Parser, Debug)]
 struct Opts {
     #[clap(subcommand)]
     cmd: Option<Command>,
 
-    #[clap(long, arg_enum, global = true)]
-    chain: Option<Chain>,
+    /// Only work on this chain, by id.
+    #[clap(long, global = true)]
+    chain: Option<String>,
+
+    /// Also work on test networks.
+    #[clap(long, global = true)]
+    testnets: bool,
+
+    /// The chain registry.
+    #[clap(long = "chains", global = true, env = "REALTPS_CHAINS", default_value = CHAIN_REGISTRY_PATH)]
+    chain_registry: PathBuf,
+
+    /// The db to use, as `<kind>[:<path>]`, e.g. `json:./db` or `sqlite:realtps.sqlite`.
+    #[clap(long, global = true, env = "REALTPS_DB", default_value = "json")]
+    db: DbConfig,
+
+    /// Read from the db but keep all changes in memory.
+    #[clap(long, global = true)]
+    dry_run: bool,
+
+    /// Import blocks up to each chain's latest block, rather than only up to
+    /// its finalized block. Blocks above the finalized block may yet be
+    /// replaced by a reorg.
+    #[clap(long, global = true)]
+    include_unfinalized: bool,
 }
 
 #[derive(Subcommand, Debug)]
@@ -42,67 +76,246 @@ enum Command {
     Import,
     Calculate,
     Remove,
+    /// Copy all data from one db to another.
+    Migrate {
+        /// The db to copy from, as `<kind>[:<path>]`.
+        #[clap(long)]
+        from: DbConfig,
+        /// The db to copy to, as `<kind>[:<path>]`.
+        #[clap(long)]
+        to: DbConfig,
+    },
+    /// Check stored blocks for gaps, broken parent links, orphans, and
+    /// leftover temp files.
+    Verify {
+        /// Refetch missing and mismatched blocks, and remove orphans and temp
+        /// files. Don't run while importing into the same db.
+        #[clap(long)]
+        repair: bool,
+    },
+    /// Summarize each chain's recorded reorgs: how often, and how deep.
+    Reorgs {
+        /// How many days back to look.
+        #[clap(long, default_value = "30")]
+        days: i64,
+    },
+    /// Check the RPC configuration.
+    Config {
+        #[clap(subcommand)]
+        cmd: ConfigCommand,
+    },
+    /// Maintain the db.
+    Db {
+        #[clap(subcommand)]
+        cmd: DbCommand,
+    },
+    /// Back up the db to an archive, or load it from one.
+    Snapshot {
+        #[clap(subcommand)]
+        cmd: SnapshotCommand,
+    },
 }
 
-#[derive(Deserialize, Serialize)]
-struct RpcConfig {
-    chains: HashMap<Chain, String>,
+#[derive(Subcommand, Debug)]
+enum DbCommand {
+    /// Rewrite records stored by older versions of realtps in the current format.
+    Upgrade,
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigCommand {
+    /// Report every chain whose RPC url is missing, malformed or unreachable.
+    Check,
 }
 
-static RPC_CONFIG_PATH: &str = "rpc_config.toml";
+#[derive(Subcommand, Debug)]
+enum SnapshotCommand {
+    /// Write all chains' blocks and meta data to a compressed archive.
+    Create {
+        /// The archive to write, e.g. `realtps.tar.gz`.
+        path: PathBuf,
+    },
+    /// Load chains from an archive written by `snapshot create` into an
+    /// empty db.
+    Restore {
+        /// The archive to read.
+        path: PathBuf,
+    },
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
     env_logger::init();
 
     let opts = Opts::parse();
-    let rpc_config = load_rpc_config(RPC_CONFIG_PATH)?;
 
-    Ok(run(opts, rpc_config).await?)
+    Ok(run(opts).await?)
 }
 
-async fn run(opts: Opts, rpc_config: RpcConfig) -> Result<()> {
+async fn run(opts: Opts) -> Result<()> {
     let cmd = opts.cmd.unwrap_or(Command::Run);
 
-    let chains = get_chains(opts.chain);
+    chain::load_registry(&opts.chain_registry)?;
+    let chains = get_chains(opts.chain.as_deref(), opts.testnets)?;
+
+    let cmd = match cmd {
+        Command::Migrate { from, to } => {
+            let chains = get_stored_chains(opts.chain.as_deref())?;
+            // Lock the source too, so that no importer changes it mid-copy
+            let _from_locks = lock_chains(&from, &chains)?;
+            let _to_locks = lock_chains(&to, &chains)?;
+            return migrate::migrate(&chains, &from, &to).await;
+        }
+        Command::Db {
+            cmd: DbCommand::Upgrade,
+        } => {
+            let _locks = lock_chains(&opts.db, &chains)?;
+            return upgrade::upgrade(&chains, &opts.db).await;
+        }
+        Command::Snapshot {
+            cmd: SnapshotCommand::Create { path },
+        } => {
+            let chains = get_stored_chains(opts.chain.as_deref())?;
+            return snapshot::create(&chains, &opts.db, &path).await;
+        }
+        Command::Snapshot {
+            cmd: SnapshotCommand::Restore { path },
+        } => {
+            let chains = get_stored_chains(opts.chain.as_deref())?;
+            let _locks = lock_chains(&opts.db, &chains)?;
+            return snapshot::restore(&chains, &opts.db, &path).await;
+        }
+        Command::Reorgs { days } => return reorgs::report(&chains, &opts.db, days).await,
+        Command::Config {
+            cmd: ConfigCommand::Check,
+        } => return check_config(&chains).await,
+        Command::Verify { repair } => {
+            return verify_db(&chains, &opts.db, opts.dry_run, repair).await
+        }
+        cmd => cmd,
+    };
+
+    // Dry runs don't write, so don't need to lock out other importers
+    let (chains, _locks) = if opts.dry_run {
+        (chains, vec![])
+    } else {
+        lock_available_chains(&opts.db, chains)?
+    };
+
+    let rpc_config = load_rpc_config(RPC_CONFIG_PATH, RPC_SECRETS_PATH)?;
     let init_jobs = init_jobs(&chains, cmd);
+    let imports = init_jobs.iter().any(|job| matches!(job, Job::Import(_)));
+
+    let job_runner = make_job_runner(
+        &chains,
+        &rpc_config,
+        &opts.db,
+        opts.dry_run,
+        opts.include_unfinalized,
+    )
+    .await?;
+
+    // Only imports use clients, so only they need the RPC configuration
+    // reloaded when it changes
+    let reloader = Reloader::new(&chains, &rpc_config);
+    let mut watcher = if imports {
+        Some(ConfigWatcher::new()?)
+    } else {
+        None
+    };
 
-    let job_runner = make_job_runner(&chains, &rpc_config).await?;
     let mut jobs: FuturesUnordered<_> = init_jobs
         .into_iter()
-        .map(|job| job_runner.do_job(job))
+        // Chains without a client can't be imported, but the data already
+        // stored for them is still calculated and removed
+        .filter_map(|job| match job {
+            Job::Import(chain) => job_runner.start_import(chain),
+            job => Some(job),
+        })
+        .map(|job| job_runner.do_job(job).boxed_local())
         .collect();
 
     loop {
-        let new_jobs = jobs.next().await;
-        if let Some(new_jobs) = new_jobs {
-            for new_job in new_jobs {
-                jobs.push(job_runner.do_job(new_job));
-            }
-        } else {
+        if jobs.is_empty() && watcher.is_none() {
             error!("no more jobs?!");
             break;
         }
+
+        tokio::select! {
+            Some(new_jobs) = jobs.next(), if !jobs.is_empty() => {
+                for new_job in new_jobs {
+                    jobs.push(job_runner.do_job(new_job).boxed_local());
+                }
+            }
+            _ = config_changed(&mut watcher) => {
+                // Runs alongside the other jobs, returning new import jobs
+                jobs.push(reloader.reload(&job_runner).boxed_local());
+            }
+        }
     }
 
     Ok(())
 }
 
-fn get_chains(maybe_chain: Option<Chain>) -> Vec<Chain> {
+async fn config_changed(watcher: &mut Option<ConfigWatcher>) {
+    match watcher {
+        Some(watcher) => watcher.changed().await,
+        None => future::pending().await,
+    }
+}
+
+fn get_chains(maybe_chain: Option<&str>, testnets: bool) -> Result<Vec<Chain>> {
     if let Some(chain) = maybe_chain {
-        vec![chain]
+        Ok(vec![Chain::try_from(chain)?])
+    } else if testnets {
+        Ok(Chain::all_chains()
+            .into_iter()
+            .chain(Chain::all_testnets())
+            .collect())
     } else {
-        Chain::all_chains()
+        Ok(Chain::all_chains())
     }
 }
 
-fn load_rpc_config<P: AsRef<Path>>(path: P) -> Result<RpcConfig> {
-    let rpc_config_file = fs::read_to_string(path).context("unable to load RPC configuration")?;
+/// The chains whose stored data is worked on as a whole: just `maybe_chain`,
+/// or every registered chain, since disabled chains and testnets may still
+/// have data.
+fn get_stored_chains(maybe_chain: Option<&str>) -> Result<Vec<Chain>> {
+    if let Some(chain) = maybe_chain {
+        Ok(vec![Chain::try_from(chain)?])
+    } else {
+        Ok(Chain::registered_chains())
+    }
+}
 
-    let rpc_config = toml::from_str::<RpcConfig>(&rpc_config_file)
-        .context("unable to parse RPC configuration")?;
+/// Takes the write lock for each chain, skipping chains that another
+/// process is already working on.
+fn lock_available_chains(
+    db_config: &DbConfig,
+    chains: Vec<Chain>,
+) -> Result<(Vec<Chain>, Vec<ChainLock>)> {
+    let mut locks = vec![];
+    for chain in chains {
+        match db_config.lock_chain(chain) {
+            Ok(lock) => locks.push(lock),
+            Err(e) => error!("skipping {}: {}", chain, e),
+        }
+    }
 
-    Ok(rpc_config)
+    if locks.is_empty() {
+        bail!("all chains are locked by other processes");
+    }
+
+    let chains = locks.iter().map(ChainLock::chain).collect();
+    Ok((chains, locks))
+}
+
+/// Takes the write lock for every chain, failing if any is already locked.
+fn lock_chains(db_config: &DbConfig, chains: &[Chain]) -> Result<Vec<ChainLock>> {
+    chains
+        .iter()
+        .map(|chain| db_config.lock_chain(*chain))
+        .collect()
 }
 
 fn init_jobs(chains: &[Chain], cmd: Command) -> Vec<Job> {
@@ -120,76 +333,201 @@ fn init_jobs(chains: &[Chain], cmd: Command) -> Vec<Job> {
         Command::Import => chains.iter().cloned().map(Job::Import).collect(),
         Command::Calculate => vec![Job::Calculate(chains.to_vec())],
         Command::Remove => vec![Job::Remove(chains.to_vec())],
+        Command::Migrate { .. }
+        | Command::Config { .. }
+        | Command::Verify { .. }
+        | Command::Reorgs { .. }
+        | Command::Db { .. }
+        | Command::Snapshot { .. } => unreachable!(),
     }
 }
 
-async fn make_job_runner(chains: &[Chain], rpc_config: &RpcConfig) -> Result<JobRunner> {
-    let clients = make_all_clients(chains, rpc_config).await?;
+async fn make_job_runner(
+    chains: &[Chain],
+    rpc_config: &RpcConfig,
+    db_config: &DbConfig,
+    dry_run: bool,
+    include_unfinalized: bool,
+) -> Result<JobRunner> {
+    let db = open_db(db_config, dry_run)?;
+    let clients = make_available_clients(chains, rpc_config).await?;
+
+    Ok(JobRunner::new(db, clients, include_unfinalized))
+}
+
+async fn verify_db(
+    chains: &[Chain],
+    db_config: &DbConfig,
+    dry_run: bool,
+    repair: bool,
+) -> Result<()> {
+    let _locks = if repair && !dry_run {
+        lock_chains(db_config, chains)?
+    } else {
+        vec![]
+    };
+
+    let db = open_db(db_config, dry_run)?;
+    let clients = if repair {
+        let rpc_config = load_rpc_config(RPC_CONFIG_PATH, RPC_SECRETS_PATH)?;
+        make_available_clients(chains, &rpc_config).await?
+    } else {
+        HashMap::new()
+    };
 
-    Ok(JobRunner {
-        db: Arc::new(JsonDb),
-        clients,
-    })
+    verify::verify(chains, &db, &clients, repair).await
 }
 
+fn open_db(db_config: &DbConfig, dry_run: bool) -> Result<Arc<dyn Db>> {
+    info!("using db {}", db_config);
+    let db = db_config.open()?;
+    if dry_run {
+        info!("dry run: changes will not be written to the db");
+        Ok(Arc::new(MemoryDb::over(db)))
+    } else {
+        Ok(db)
+    }
+}
+
+/// Checks that every chain has a well-formed RPC url that answers.
+async fn check_config(chains: &[Chain]) -> Result<()> {
+    let rpc_config = load_rpc_config(RPC_CONFIG_PATH, RPC_SECRETS_PATH)?;
+    let (clients, problems) = make_all_clients(chains, &rpc_config).await?;
+
+    for chain in chains {
+        if clients.contains_key(chain) {
+            info!("RPC configuration for {} is ok", chain);
+        }
+    }
+    for (chain, problem) in &problems {
+        error!("RPC configuration for {}: {}", chain, problem);
+    }
+
+    if !problems.is_empty() {
+        bail!(
+            "{} of {} chains have RPC configuration problems",
+            problems.len(),
+            chains.len()
+        );
+    }
+
+    Ok(())
+}
+
+/// Creates a client for each chain that can have one, logging why the
+/// others are skipped.
+async fn make_available_clients(
+    chains: &[Chain],
+    rpc_config: &RpcConfig,
+) -> Result<HashMap<Chain, (Box<dyn Client>, Tuning)>> {
+    let (clients, problems) = make_all_clients(chains, rpc_config).await?;
+    for (chain, problem) in problems {
+        if clients.contains_key(&chain) {
+            warn!("not using an RPC endpoint for {}: {}", chain, problem);
+        } else {
+            error!("skipping {}: {}", chain, problem);
+        }
+    }
+    Ok(clients)
+}
+
+/// Creates a client for each chain, failing over between the chain's RPC
+/// endpoints, also returning the problems with endpoints that couldn't be
+/// used.
 async fn make_all_clients(
     chains: &[Chain],
     rpc_config: &RpcConfig,
-) -> Result<HashMap<Chain, Box<dyn Client>>> {
+) -> Result<(
+    HashMap<Chain, (Box<dyn Client>, Tuning)>,
+    Vec<(Chain, ConfigProblem)>,
+)> {
     let mut client_futures = FuturesUnordered::new();
+    let mut problems = vec![];
 
     for chain in chains {
-        let rpc_url = get_rpc_url(chain, rpc_config).to_string();
-        let client_future = task::spawn(make_client(*chain, rpc_url));
-        let client_future = client_future.map(move |client| (*chain, client));
-        client_futures.push(client_future);
+        let chain = *chain;
+        let tuning = rpc_config.tuning(chain);
+        let endpoints = match rpc_config.endpoints(chain) {
+            Ok(endpoints) => endpoints,
+            Err(problem) => {
+                problems.push((chain, problem));
+                continue;
+            }
+        };
+        for endpoint in endpoints {
+            let client_future = task::spawn(make_client(chain, endpoint.clone(), tuning));
+            let client_future = client_future.map(move |client| (chain, endpoint, client));
+            client_futures.push(client_future);
+        }
     }
 
-    let mut clients = HashMap::new();
+    let mut endpoint_clients: HashMap<Chain, Vec<(Endpoint, Box<dyn Client>)>> = HashMap::new();
 
-    while let Some((chain, client)) = client_futures.next().await {
+    while let Some((chain, endpoint, client)) = client_futures.next().await {
         let client = client?;
         match client {
             Ok(Some(client)) => {
-                clients.insert(chain, client);
+                endpoint_clients
+                    .entry(chain)
+                    .or_default()
+                    .push((endpoint, client));
             }
             Ok(None) => { /* pass */ }
             Err(e) => {
-                error!("{}", e);
+                let problem = ConfigProblem::Unreachable {
+                    reason: endpoint.redact(&format!("{:#}", e)),
+                    url: endpoint.display_url,
+                };
+                problems.push((chain, problem));
             }
         }
     }
 
-    Ok(clients)
+    let clients = endpoint_clients
+        .into_iter()
+        .map(|(chain, endpoints)| {
+            let tuning = rpc_config.tuning(chain);
+            let client: Box<dyn Client> = Box::new(FailoverClient::new(chain, endpoints, tuning));
+            (chain, (client, tuning))
+        })
+        .collect();
+
+    Ok((clients, problems))
 }
 
-async fn make_client(chain: Chain, rpc_url: String) -> Result<Option<Box<dyn Client>>> {
-    info!("creating client for {} at {}", chain, rpc_url);
+async fn make_client(
+    chain: Chain,
+    endpoint: Endpoint,
+    tuning: Tuning,
+) -> Result<Option<Box<dyn Client>>> {
+    info!("creating client for {} at {}", chain, endpoint.display_url);
 
     let client: Option<Box<dyn Client>> = match chain.chain_type() {
-        ChainType::Elrond => Some(Box::new(ElrondClient::new(&rpc_url)?)),
-        ChainType::Ethers => Some(Box::new(EthersClient::new(chain, &rpc_url)?)),
-        ChainType::Near => Some(Box::new(NearClient::new(&rpc_url)?)),
-        ChainType::Solana => Some(Box::new(SolanaClient::new(&rpc_url)?)),
-        ChainType::Stellar => Some(Box::new(StellarClient::new(&rpc_url)?)),
-        ChainType::Tendermint => Some(Box::new(TendermintClient::new(chain, &rpc_url)?)),
-        ChainType::Substrate => Some(Box::new(SubstrateClient::new(chain, &rpc_url).await?)),
+        ChainType::Elrond => Some(Box::new(ElrondClient::new(chain, &endpoint)?)),
+        ChainType::Ethers => Some(Box::new(EthersClient::new(chain, &endpoint)?)),
+        ChainType::Near => Some(Box::new(NearClient::new(chain, &endpoint)?)),
+        ChainType::Solana => Some(Box::new(SolanaClient::new(chain, &endpoint)?)),
+        ChainType::Stellar => Some(Box::new(StellarClient::new(chain, &endpoint)?)),
+        ChainType::Tendermint => Some(Box::new(TendermintClient::new(chain, &endpoint)?)),
+        ChainType::Substrate => Some(Box::new(SubstrateClient::new(chain, &endpoint)?)),
+        ChainType::Unknown => None,
     };
 
     if let Some(ref client) = client {
-        let version = retry_if_err(chain, || client.client_version())
-            .await
-            .context(format!("error getting client version for {}", chain))?;
+        let request_timeout = tuning.request_timeout;
+        let endpoint = &endpoint;
+        let version = retry_if_err(chain, &tuning, || {
+            Box::pin(async move {
+                time::timeout(request_timeout, client.client_version())
+                    .await
+                    .map_err(|_| anyhow!("request timed out"))?
+                    .map_err(|e| endpoint.redact_error(e))
+            })
+        })
+        .await
+        .context(format!("error getting client version for {}", chain))?;
         info!("node version for {}: {}", chain, version);
     }
 
     Ok(client)
 }
-
-fn get_rpc_url<'a>(chain: &Chain, rpc_config: &'a RpcConfig) -> &'a str {
-    if let Some(url) = rpc_config.chains.get(chain) {
-        url
-    } else {
-        todo!()
-    }
-}
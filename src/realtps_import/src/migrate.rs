@@ -0,0 +1,323 @@
+use anyhow::{bail, Context, Result};
+use chrono::{TimeZone, Utc};
+use log::info;
+use realtps_common::{
+    chain::Chain,
+    db::{Db, DbConfig},
+};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::task;
+
+/// How many blocks to write to the destination in one batch.
+const BATCH_SIZE: usize = 1000;
+
+/// Copies every chain's blocks and meta data from one db to another, then
+/// checks that the copy is complete.
+///
+/// Blocks are copied by walking backwards from the highest known block
+/// number, so only blocks on the chain we know are copied.
+pub async fn migrate(chains: &[Chain], from: &DbConfig, to: &DbConfig) -> Result<()> {
+    if canonical_path(&from.path) == canonical_path(&to.path) {
+        bail!("can't migrate db {} to itself", from);
+    }
+
+    info!("migrating db {} to {}", from, to);
+
+    let from_db = from.open()?;
+    let to_db = to.open()?;
+
+    for chain in chains {
+        let chain = *chain;
+        let from_db = from_db.clone();
+        let to_db = to_db.clone();
+        task::spawn_blocking(move || migrate_chain(chain, &from_db, &to_db)).await??;
+    }
+
+    info!("migrated db {} to {}", from, to);
+
+    Ok(())
+}
+
+/// Resolves `path` as far as it exists, so that two spellings of the same
+/// location compare equal even if the db hasn't been created yet.
+fn canonical_path(path: &Path) -> PathBuf {
+    if let Ok(path) = path.canonicalize() {
+        return path;
+    }
+
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(file_name)) => {
+            let parent = if parent.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                parent
+            };
+            canonical_path(parent).join(file_name)
+        }
+        _ => path.to_path_buf(),
+    }
+}
+
+fn migrate_chain(chain: Chain, from: &Arc<dyn Db>, to: &Arc<dyn Db>) -> Result<()> {
+    let highest_block_number = match from.load_highest_block_number(chain)? {
+        Some(highest_block_number) => highest_block_number,
+        None => {
+            info!("no data to migrate for {}", chain);
+            return Ok(());
+        }
+    };
+
+    // Check the source before writing anything, so a broken chain doesn't
+    // leave a partial copy behind.
+    let source_num_blocks =
+        verify_chain(chain, from.as_ref(), highest_block_number).with_context(|| {
+            format!(
+                "not migrating {}; run verify --repair on the source first",
+                chain
+            )
+        })?;
+
+    info!("migrating {} from block {}", chain, highest_block_number);
+
+    let mut num_blocks = 0;
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
+    let mut next_block_number = Some(highest_block_number);
+
+    while let Some(block_number) = next_block_number {
+        let block = match from.load_block(chain, block_number)? {
+            Some(block) => block,
+            None => break,
+        };
+
+        next_block_number = block.prev_block_number;
+        batch.push(block);
+        num_blocks += 1;
+
+        if batch.len() == BATCH_SIZE {
+            to.store_blocks(batch)?;
+            batch = Vec::with_capacity(BATCH_SIZE);
+            info!("migrated {} blocks for {}", num_blocks, chain);
+        }
+    }
+
+    to.store_blocks(batch)?;
+
+    if let Some(block_number) = from.load_finalized_block_number(chain)? {
+        to.store_finalized_block_number(chain, block_number)?;
+    }
+    match from.load_tps_and_calculation_log(chain)? {
+        (Some(tps), Some(log)) => to.store_tps_and_calculation_log(chain, tps, &log)?,
+        (Some(tps), None) => to.store_tps(chain, tps)?,
+        (None, Some(log)) => to.store_calculation_log(chain, &log)?,
+        (None, None) => {}
+    }
+
+    // Records and events already copied by an interrupted migration aren't
+    // copied again
+    let history = from.load_tps_history(chain, Utc.timestamp(0, 0), Utc::now())?;
+    let last_copied = to
+        .load_tps_history(chain, Utc.timestamp(0, 0), Utc::now())?
+        .last()
+        .map(|record| record.calculated_at);
+    for record in &history {
+        if Some(record.calculated_at) > last_copied {
+            to.append_tps_record(chain, record)?;
+        }
+    }
+
+    let reorgs = from.load_reorg_events(chain, Utc.timestamp(0, 0), Utc::now())?;
+    let last_copied = to
+        .load_reorg_events(chain, Utc.timestamp(0, 0), Utc::now())?
+        .last()
+        .map(|event| event.detected_at);
+    for event in &reorgs {
+        if Some(event.detected_at) > last_copied {
+            to.append_reorg_event(event)?;
+        }
+    }
+
+    // Written last, so that an interrupted migration doesn't leave a
+    // destination claiming a chain it only partly has
+    to.store_highest_block_number(chain, highest_block_number)?;
+
+    if to.load_highest_block_number(chain)? != Some(highest_block_number) {
+        bail!("highest block number for {} was not migrated", chain);
+    }
+
+    let migrated_num_blocks = verify_chain(chain, to.as_ref(), highest_block_number)?;
+    if migrated_num_blocks != source_num_blocks {
+        bail!(
+            "source has {} blocks for {} but only {} were migrated",
+            source_num_blocks,
+            chain,
+            migrated_num_blocks
+        );
+    }
+
+    let migrated_history = to.load_tps_history(chain, Utc.timestamp(0, 0), Utc::now())?;
+    if migrated_history.len() != history.len() {
+        bail!(
+            "source has {} tps records for {} but {} were migrated",
+            history.len(),
+            chain,
+            migrated_history.len()
+        );
+    }
+
+    let migrated_reorgs = to.load_reorg_events(chain, Utc.timestamp(0, 0), Utc::now())?;
+    if migrated_reorgs.len() != reorgs.len() {
+        bail!(
+            "source has {} reorg events for {} but {} were migrated",
+            reorgs.len(),
+            chain,
+            migrated_reorgs.len()
+        );
+    }
+
+    info!(
+        "migrated {} blocks, {} tps records and {} reorg events for {}",
+        num_blocks,
+        history.len(),
+        reorgs.len(),
+        chain
+    );
+
+    Ok(())
+}
+
+/// Walks the chain back from `highest_block_number` until a block is missing,
+/// checking that each block's parent hash is the hash of the block before,
+/// and returns the number of blocks walked.
+fn verify_chain(chain: Chain, db: &dyn Db, highest_block_number: u64) -> Result<u64> {
+    let mut num_blocks = 0;
+    let mut next_block_number = Some(highest_block_number);
+    let mut parent_hash: Option<String> = None;
+
+    while let Some(block_number) = next_block_number {
+        let block = match db.load_block(chain, block_number)? {
+            Some(block) => block,
+            None => break,
+        };
+
+        if let Some(parent_hash) = parent_hash {
+            if parent_hash != block.hash {
+                bail!(
+                    "hash chain for {} is broken at block {}; expected hash {}; found {}",
+                    chain,
+                    block_number,
+                    parent_hash,
+                    block.hash
+                );
+            }
+        }
+
+        next_block_number = block.prev_block_number;
+        parent_hash = Some(block.parent_hash);
+        num_blocks += 1;
+    }
+
+    Ok(num_blocks)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::client::{mock::MockClient, Client};
+    use realtps_common::db::{MemoryDb, ReorgEvent, TpsRecord};
+
+    fn chain() -> Chain {
+        Chain::try_from("solana").expect("chain")
+    }
+
+    #[tokio::test]
+    async fn broken_chain_is_not_migrated() -> Result<()> {
+        let client = MockClient::new(chain(), 1);
+        client.set_blocks(0..=10, "a");
+
+        let from: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        for block_number in 0..=10 {
+            from.store_block(client.get_block(block_number).await?.expect("block"))?;
+        }
+        from.store_highest_block_number(chain(), 10)?;
+
+        client.set_blocks(4..=4, "b");
+        from.store_block(client.get_block(4).await?.expect("block"))?;
+
+        let memory_db = Arc::new(MemoryDb::new());
+        let to: Arc<dyn Db> = memory_db.clone();
+        assert!(migrate_chain(chain(), &from, &to).is_err());
+        assert!(memory_db.block_numbers(chain()).is_empty());
+        assert_eq!(to.load_highest_block_number(chain())?, None);
+
+        client.set_blocks(4..=4, "a");
+        from.store_block(client.get_block(4).await?.expect("block"))?;
+        migrate_chain(chain(), &from, &to)?;
+        assert_eq!(
+            memory_db.block_numbers(chain()),
+            (0..=10).collect::<Vec<_>>()
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn history_and_reorgs_are_migrated_once() -> Result<()> {
+        let client = MockClient::new(chain(), 1);
+        client.set_blocks(0..=10, "a");
+
+        let from: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        for block_number in 0..=10 {
+            from.store_block(client.get_block(block_number).await?.expect("block"))?;
+        }
+        from.store_highest_block_number(chain(), 10)?;
+        for day in 1..=3 {
+            let timestamp = Utc.timestamp(day * 60 * 60 * 24, 0);
+            let record = TpsRecord {
+                calculated_at: timestamp,
+                tps: day as f64,
+                oldest_block_number: 0,
+                newest_block_number: 10,
+                oldest_block_timestamp: timestamp,
+                newest_block_timestamp: timestamp,
+                num_txs: 10,
+            };
+            from.append_tps_record(chain(), &record)?;
+        }
+        let reorg = ReorgEvent {
+            chain: chain(),
+            block_number: 8,
+            old_hash: "b8".to_string(),
+            new_hash: "a8".to_string(),
+            depth: 1,
+            detected_at: Utc.timestamp(60 * 60 * 24, 0),
+        };
+        from.append_reorg_event(&reorg)?;
+
+        // Running again, as after an interrupted migration, adds nothing
+        let to: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        migrate_chain(chain(), &from, &to)?;
+        migrate_chain(chain(), &from, &to)?;
+        assert_eq!(
+            to.load_tps_history(chain(), Utc.timestamp(0, 0), Utc::now())?,
+            from.load_tps_history(chain(), Utc.timestamp(0, 0), Utc::now())?
+        );
+        assert_eq!(
+            to.load_reorg_events(chain(), Utc.timestamp(0, 0), Utc::now())?,
+            vec![reorg]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn paths_to_the_same_db_compare_equal() {
+        let dir = std::env::temp_dir();
+        assert_eq!(
+            canonical_path(&dir.join("realtps-migrate.sqlite")),
+            canonical_path(&dir.join(".").join("realtps-migrate.sqlite"))
+        );
+    }
+}
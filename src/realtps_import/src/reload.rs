@@ -0,0 +1,167 @@
+use crate::delay::Tuning;
+use crate::jobs::{Job, JobRunner};
+use crate::make_all_clients;
+use crate::rpc_config::{
+    load_rpc_config, ConfigProblem, Endpoint, RpcConfig, RPC_CONFIG_PATH, RPC_SECRETS_PATH,
+};
+use anyhow::Result;
+use log::{error, info, warn};
+use realtps_common::chain::Chain;
+use std::collections::HashMap;
+use std::fs;
+use std::time::SystemTime;
+use tokio::sync::Mutex;
+use tokio::time::{self, Duration};
+
+/// How often the RPC configuration files are checked for changes.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Waits for the RPC configuration to change: for `rpc_config.toml` or
+/// `rpc_secrets.toml` to be modified, or for a SIGHUP.
+pub struct ConfigWatcher {
+    modified: Vec<Option<SystemTime>>,
+    #[cfg(unix)]
+    hangup: tokio::signal::unix::Signal,
+}
+
+impl ConfigWatcher {
+    pub fn new() -> Result<ConfigWatcher> {
+        Ok(ConfigWatcher {
+            modified: modified_times(),
+            #[cfg(unix)]
+            hangup: tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?,
+        })
+    }
+
+    pub async fn changed(&mut self) {
+        loop {
+            #[cfg(unix)]
+            tokio::select! {
+                _ = self.hangup.recv() => {
+                    info!("received SIGHUP");
+                    self.modified = modified_times();
+                    return;
+                }
+                _ = time::sleep(POLL_INTERVAL) => {}
+            }
+            #[cfg(not(unix))]
+            time::sleep(POLL_INTERVAL).await;
+
+            let modified = modified_times();
+            if modified != self.modified {
+                info!("RPC configuration modified");
+                self.modified = modified;
+                return;
+            }
+        }
+    }
+}
+
+fn modified_times() -> Vec<Option<SystemTime>> {
+    [RPC_CONFIG_PATH, RPC_SECRETS_PATH]
+        .iter()
+        .map(|path| fs::metadata(path).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+/// What a chain's client was made from.
+#[derive(PartialEq)]
+struct ChainConfig {
+    endpoints: Vec<Endpoint>,
+    tuning: Tuning,
+}
+
+/// Rebuilds the clients of the chains whose RPC configuration changed.
+pub struct Reloader {
+    chains: Vec<Chain>,
+    configs: Mutex<HashMap<Chain, ChainConfig>>,
+}
+
+impl Reloader {
+    pub fn new(chains: &[Chain], rpc_config: &RpcConfig) -> Reloader {
+        let configs = chains
+            .iter()
+            .filter_map(|chain| Some((*chain, chain_config(*chain, rpc_config).ok()?)))
+            .collect();
+
+        Reloader {
+            chains: chains.to_vec(),
+            configs: Mutex::new(configs),
+        }
+    }
+
+    /// Reloads the RPC configuration, returning the jobs to import the
+    /// chains that now have a client.
+    ///
+    /// A chain that is no longer configured loses its client. A chain whose
+    /// new configuration is broken keeps its previous client.
+    pub async fn reload(&self, job_runner: &JobRunner) -> Vec<Job> {
+        match self.try_reload(job_runner).await {
+            Ok(jobs) => jobs,
+            Err(e) => {
+                error!("not reloading RPC configuration: {:#}", e);
+                vec![]
+            }
+        }
+    }
+
+    async fn try_reload(&self, job_runner: &JobRunner) -> Result<Vec<Job>> {
+        // One reload at a time
+        let mut configs = self.configs.lock().await;
+        let rpc_config = load_rpc_config(RPC_CONFIG_PATH, RPC_SECRETS_PATH)?;
+
+        let mut changed = vec![];
+        let mut new_configs = HashMap::new();
+        let mut removed = vec![];
+
+        for chain in &self.chains {
+            let chain = *chain;
+            match chain_config(chain, &rpc_config) {
+                Ok(config) => {
+                    // Chains without a client are retried even if unchanged
+                    if configs.get(&chain) != Some(&config) || !job_runner.has_client(chain) {
+                        changed.push(chain);
+                        new_configs.insert(chain, config);
+                    }
+                }
+                Err(ConfigProblem::Missing) => {
+                    configs.remove(&chain);
+                    if job_runner.has_client(chain) {
+                        info!("removing client for {}", chain);
+                        removed.push(chain);
+                    }
+                }
+                Err(problem) => {
+                    error!("not using new RPC configuration for {}: {}", chain, problem);
+                }
+            }
+        }
+
+        if !changed.is_empty() {
+            info!("rebuilding clients for {:?}", changed);
+        }
+
+        let (clients, problems) = make_all_clients(&changed, &rpc_config).await?;
+        for (chain, problem) in problems {
+            if clients.contains_key(&chain) {
+                warn!("not using an RPC endpoint for {}: {}", chain, problem);
+            } else {
+                error!("not using new RPC configuration for {}: {}", chain, problem);
+            }
+        }
+        for chain in clients.keys() {
+            if let Some(config) = new_configs.remove(chain) {
+                configs.insert(*chain, config);
+            }
+        }
+
+        Ok(job_runner.update_clients(clients, &removed))
+    }
+}
+
+fn chain_config(chain: Chain, rpc_config: &RpcConfig) -> Result<ChainConfig, ConfigProblem> {
+    Ok(ChainConfig {
+        endpoints: rpc_config.endpoints(chain)?,
+        tuning: rpc_config.tuning(chain),
+    })
+}
@@ -16,9 +16,7 @@ pub async fn remove_old_data_for_chain(chain: Chain, db: Arc<dyn Db>) -> Result<
     let highest_block_number =
         highest_block_number.ok_or_else(|| anyhow!("no data for chain {}", chain))?;
 
-    let load_block = |number| load_block(chain, &db, number);
-
-    let latest_timestamp = load_block(highest_block_number)
+    let latest_timestamp = load_block(chain, &db, highest_block_number)
         .await?
         .expect("firt block")
         .timestamp;
@@ -28,50 +26,48 @@ pub async fn remove_old_data_for_chain(chain: Chain, db: Arc<dyn Db>) -> Result<
         .checked_sub(seconds_per_week)
         .expect("underflow");
 
-    let mut current_block = load_block(highest_block_number).await?.expect("firt_block");
-    let mut to_remove_blocks = vec![];
-    let mut is_old_block = false;
-
-    loop {
-        if is_old_block {
-            to_remove_blocks.push(current_block.block_number);
+    // Remove every block older than a week, on the chain or not
+    let newest_old_block = match min_timestamp.checked_sub(1) {
+        Some(timestamp) => find_block_at_or_before_timestamp(chain, &db, timestamp).await?,
+        None => None,
+    };
+    match newest_old_block {
+        Some(newest_old_block) => {
+            let num_removed =
+                remove_blocks_before(chain, &db, newest_old_block.block_number + 1).await?;
+            info!("removed {} blocks for chain: {}", num_removed, chain);
         }
-
-        let prev_block_number = current_block.prev_block_number;
-
-        if prev_block_number.is_none() {
-            break;
-        }
-        let prev_block_number = prev_block_number.unwrap();
-
-        let prev_block = load_block(prev_block_number).await?;
-
-        if prev_block.is_none() {
-            break;
+        None => {
+            info!("no old data in chain {}", chain);
         }
+    }
 
-        let prev_block = prev_block.unwrap();
+    Ok(())
+}
 
-        if !is_old_block && prev_block.timestamp < min_timestamp {
-            is_old_block = true;
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::client::mock::MockClient;
+    use realtps_common::db::MemoryDb;
+
+    #[tokio::test]
+    async fn remove_blocks_older_than_a_week() -> Result<()> {
+        let chain = Chain::try_from("ethereum")?;
+        let client = MockClient::new(chain, 60 * 60 * 24);
+        client.set_blocks(0..=10, "a");
+
+        let memory_db = Arc::new(MemoryDb::new());
+        for block_number in 0..=10 {
+            let block = client.get_block(block_number).await?.expect("block");
+            memory_db.store_block(block)?;
         }
+        memory_db.store_highest_block_number(chain, 10)?;
 
-        current_block = prev_block;
-    }
+        remove_old_data_for_chain(chain, memory_db.clone()).await?;
 
-    if !to_remove_blocks.is_empty() {
-        info!(
-            "removing {} blocks for chain: {}",
-            to_remove_blocks.len(),
-            chain
-        );
+        assert_eq!(memory_db.block_numbers(chain), (3..=10).collect::<Vec<_>>());
 
-        to_remove_blocks.reverse();
-
-        remove_blocks(chain, &db, to_remove_blocks).await?;
-    } else {
-        info!("no old data in chain {}", chain);
+        Ok(())
     }
-
-    Ok(())
 }
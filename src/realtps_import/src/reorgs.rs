@@ -0,0 +1,40 @@
+use anyhow::{bail, Result};
+use chrono::{Duration, Utc};
+use realtps_common::{chain::Chain, db::DbConfig};
+use tokio::task;
+
+/// Prints a summary of the reorgs recorded for every chain over the last
+/// `days` days.
+pub async fn report(chains: &[Chain], db_config: &DbConfig, days: i64) -> Result<()> {
+    if days < 1 {
+        bail!("--days must be at least 1");
+    }
+
+    let db = db_config.open()?;
+    let to = Utc::now();
+    let from = to - Duration::days(days);
+
+    for chain in chains {
+        let chain = *chain;
+        let db = db.clone();
+        let events = task::spawn_blocking(move || db.load_reorg_events(chain, from, to)).await??;
+
+        if events.is_empty() {
+            println!("{}: no reorgs in the last {} days", chain, days);
+            continue;
+        }
+
+        let num_events = events.len();
+        let per_day = num_events as f64 / days as f64;
+        let mean_depth = events.iter().map(|e| e.depth).sum::<u64>() as f64 / num_events as f64;
+        let max_depth = events.iter().map(|e| e.depth).max().unwrap_or_default();
+        let last = events.iter().map(|e| e.detected_at).max().expect("events");
+
+        println!(
+            "{}: {} reorgs in the last {} days ({:.2} per day); mean depth {:.1}; max depth {}; last at {}",
+            chain, num_events, days, per_day, mean_depth, max_depth, last
+        );
+    }
+
+    Ok(())
+}
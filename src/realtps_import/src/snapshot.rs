@@ -0,0 +1,549 @@
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, TimeZone, Utc};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use log::info;
+use realtps_common::{
+    chain::Chain,
+    db::{decode_record, encode_record, Block, Db, DbConfig, Record, ReorgEvent, TpsRecord},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::task;
+
+/// The version of the snapshot layout written by this version of realtps.
+const SNAPSHOT_VERSION: u32 = 1;
+
+/// How many blocks to read from or write to the db at once.
+const BATCH_SIZE: usize = 1000;
+
+static MANIFEST: &str = "manifest.json";
+static META: &str = "meta.json";
+static BLOCKS: &str = "blocks.jsonl";
+static TPS_HISTORY: &str = "tps_history.jsonl";
+static REORGS: &str = "reorgs.jsonl";
+
+/// Describes a snapshot, and is the last file in its archive.
+///
+/// Each chain in the snapshot has a directory holding `meta.json`, then
+/// `blocks.jsonl`, `tps_history.jsonl` and `reorgs.jsonl`, with one
+/// versioned record per line.
+#[derive(Serialize, Deserialize, Debug)]
+struct Manifest {
+    version: u32,
+    created_at: DateTime<Utc>,
+    chains: Vec<ChainManifest>,
+    /// The hex SHA-256 of every other file in the archive, by path.
+    files: BTreeMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ChainManifest {
+    chain: Chain,
+    highest_block_number: u64,
+    num_blocks: u64,
+    num_tps_records: u64,
+    /// Missing from snapshots made before reorgs were recorded.
+    #[serde(default)]
+    num_reorg_events: u64,
+}
+
+/// A chain's meta values, as versioned records.
+#[derive(Serialize, Deserialize, Default)]
+struct Meta {
+    highest_block_number: Option<Value>,
+    /// Missing from snapshots made before finality was tracked.
+    finalized_block_number: Option<Value>,
+    tps: Option<Value>,
+    calculation_log: Option<Value>,
+}
+
+/// Writes every chain's blocks, meta values, TPS history and reorg events to
+/// a gzipped tar archive at `path`.
+pub async fn create(chains: &[Chain], db_config: &DbConfig, path: &Path) -> Result<()> {
+    info!("creating snapshot {} of db {}", path.display(), db_config);
+
+    let db = db_config.open()?;
+    let chains = chains.to_vec();
+    let path = path.to_path_buf();
+    task::spawn_blocking(move || create_snapshot(&chains, &db, &path)).await??;
+
+    info!("created snapshot");
+
+    Ok(())
+}
+
+/// Loads the chains in the snapshot at `path` into a db, after checking the
+/// whole snapshot against its manifest.
+///
+/// The db must not have data for any of the chains yet, other than what an
+/// interrupted restore left behind.
+pub async fn restore(chains: &[Chain], db_config: &DbConfig, path: &Path) -> Result<()> {
+    info!("restoring snapshot {} to db {}", path.display(), db_config);
+
+    let db = db_config.open()?;
+    let chains = chains.to_vec();
+    let path = path.to_path_buf();
+    task::spawn_blocking(move || restore_snapshot(&chains, &db, &path)).await??;
+
+    info!("restored snapshot");
+
+    Ok(())
+}
+
+fn create_snapshot(chains: &[Chain], db: &Arc<dyn Db>, path: &Path) -> Result<()> {
+    // Written to a temp file and renamed, so a failed snapshot doesn't leave
+    // a truncated archive behind
+    let temp_path = sibling_path(path, "tmp");
+    let file = BufWriter::new(File::create(&temp_path)?);
+    let mut archive = Archive {
+        builder: tar::Builder::new(GzEncoder::new(file, Compression::default())),
+        part_path: sibling_path(path, "part"),
+        manifest: Manifest {
+            version: SNAPSHOT_VERSION,
+            created_at: Utc::now(),
+            chains: vec![],
+            files: BTreeMap::new(),
+        },
+    };
+
+    for chain in chains {
+        let chain = *chain;
+        let highest_block_number = match db.load_highest_block_number(chain)? {
+            Some(highest_block_number) => highest_block_number,
+            None => {
+                info!("no data to snapshot for {}", chain);
+                continue;
+            }
+        };
+
+        let meta = Meta {
+            highest_block_number: Some(to_value(&highest_block_number)?),
+            finalized_block_number: db
+                .load_finalized_block_number(chain)?
+                .map(|block_number| to_value(&block_number))
+                .transpose()?,
+            tps: db.load_tps(chain)?.map(|tps| to_value(&tps)).transpose()?,
+            calculation_log: db
+                .load_calculation_log(chain)?
+                .map(|log| to_value(&log))
+                .transpose()?,
+        };
+        archive.append(chain, META, |out| Ok(serde_json::to_writer(out, &meta)?))?;
+
+        let num_blocks = archive.append(chain, BLOCKS, |out| write_blocks(chain, db, out))?;
+
+        let num_tps_records = archive.append(chain, TPS_HISTORY, |out| {
+            let records = db.load_tps_history(chain, Utc.timestamp(0, 0), Utc::now())?;
+            for record in &records {
+                write_line(out, record)?;
+            }
+            Ok(records.len() as u64)
+        })?;
+
+        let num_reorg_events = archive.append(chain, REORGS, |out| {
+            let events = db.load_reorg_events(chain, Utc.timestamp(0, 0), Utc::now())?;
+            for event in &events {
+                write_line(out, event)?;
+            }
+            Ok(events.len() as u64)
+        })?;
+
+        info!("added {} blocks for {} to snapshot", num_blocks, chain);
+
+        archive.manifest.chains.push(ChainManifest {
+            chain,
+            highest_block_number,
+            num_blocks,
+            num_tps_records,
+            num_reorg_events,
+        });
+    }
+
+    let manifest = serde_json::to_vec_pretty(&archive.manifest)?;
+    let mut header = archive.header(manifest.len() as u64);
+    archive
+        .builder
+        .append_data(&mut header, MANIFEST, manifest.as_slice())?;
+
+    let file = archive.builder.into_inner()?.finish()?;
+    file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    fs::remove_file(&archive.part_path)?;
+    fs::rename(&temp_path, path)?;
+
+    Ok(())
+}
+
+struct Archive {
+    builder: tar::Builder<GzEncoder<BufWriter<File>>>,
+    /// Where each file is staged before it goes in the archive, since its
+    /// size has to be known up front.
+    part_path: PathBuf,
+    manifest: Manifest,
+}
+
+impl Archive {
+    /// Adds the file `<chain>/<name>`, written by `write`, to the archive,
+    /// recording its checksum in the manifest.
+    fn append<T>(
+        &mut self,
+        chain: Chain,
+        name: &str,
+        write: impl FnOnce(&mut dyn Write) -> Result<T>,
+    ) -> Result<T> {
+        let mut part = HashingWriter::new(BufWriter::new(File::create(&self.part_path)?));
+        let r = write(&mut part)?;
+        part.flush()?;
+        let (len, checksum) = (part.len, part.checksum());
+
+        let path = format!("{}/{}", chain, name);
+        let mut header = self.header(len);
+        self.builder
+            .append_data(&mut header, &path, File::open(&self.part_path)?)?;
+        self.manifest.files.insert(path, checksum);
+
+        Ok(r)
+    }
+
+    fn header(&self, len: u64) -> tar::Header {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(len);
+        header.set_mode(0o644);
+        header.set_mtime(u64::try_from(self.manifest.created_at.timestamp()).unwrap_or(0));
+        header
+    }
+}
+
+fn write_blocks(chain: Chain, db: &Arc<dyn Db>, out: &mut dyn Write) -> Result<u64> {
+    let block_numbers = db.list_block_numbers(chain)?;
+    let mut num_blocks = 0;
+
+    for batch in block_numbers.chunks(BATCH_SIZE) {
+        let (first, last) = (batch[0], batch[batch.len() - 1]);
+        for block in db.load_blocks_in_range(chain, first, last)? {
+            write_line(out, &block)?;
+            num_blocks += 1;
+        }
+    }
+
+    Ok(num_blocks)
+}
+
+fn restore_snapshot(chains: &[Chain], db: &Arc<dyn Db>, path: &Path) -> Result<()> {
+    let manifest = check_snapshot(path)?;
+
+    let chains: HashMap<String, &ChainManifest> = manifest
+        .chains
+        .iter()
+        .filter(|chain_manifest| chains.contains(&chain_manifest.chain))
+        .map(|chain_manifest| (chain_manifest.chain.to_string(), chain_manifest))
+        .collect();
+
+    // The newest TPS record and reorg event of each chain, left by an
+    // interrupted restore, up to which they aren't restored again
+    let mut last_restored = HashMap::new();
+    for chain_manifest in chains.values() {
+        let chain = chain_manifest.chain;
+        if db.load_highest_block_number(chain)?.is_some() {
+            bail!(
+                "db already has data for {}; can only restore into an empty db",
+                chain
+            );
+        }
+
+        let last_record = db
+            .load_tps_history(chain, Utc.timestamp(0, 0), Utc::now())?
+            .last()
+            .map(|record| record.calculated_at);
+        let last_event = db
+            .load_reorg_events(chain, Utc.timestamp(0, 0), Utc::now())?
+            .last()
+            .map(|event| event.detected_at);
+        last_restored.insert(chain, (last_record, last_event));
+    }
+
+    let mut archive = open_archive(path)?;
+    for entry in archive.entries()? {
+        let entry = entry?;
+        let path = entry.path()?.to_string_lossy().into_owned();
+        let chain_manifest = match path.split_once('/') {
+            Some((chain, _)) => match chains.get(chain) {
+                Some(chain_manifest) => *chain_manifest,
+                None => continue,
+            },
+            None => continue,
+        };
+        let chain = chain_manifest.chain;
+        let (last_record, last_event) = last_restored[&chain];
+        let mut entry = BufReader::new(entry);
+
+        if path.ends_with(META) {
+            let meta: Meta = serde_json::from_reader(entry)?;
+            if let Some(block_number) = meta.finalized_block_number {
+                db.store_finalized_block_number(chain, from_value(block_number)?)?;
+            }
+            if let Some(tps) = meta.tps {
+                db.store_tps(chain, from_value(tps)?)?;
+            }
+            if let Some(log) = meta.calculation_log {
+                db.store_calculation_log(chain, &from_value(log)?)?;
+            }
+        } else if path.ends_with(BLOCKS) {
+            let mut num_blocks = 0;
+            let mut batch = Vec::with_capacity(BATCH_SIZE);
+            while let Some(block) = read_line::<Block>(&mut entry)? {
+                batch.push(block);
+                num_blocks += 1;
+                if batch.len() == BATCH_SIZE {
+                    db.store_blocks(batch)?;
+                    batch = Vec::with_capacity(BATCH_SIZE);
+                }
+            }
+            db.store_blocks(batch)?;
+            info!("restored {} blocks for {}", num_blocks, chain);
+        } else if path.ends_with(TPS_HISTORY) {
+            while let Some(record) = read_line::<TpsRecord>(&mut entry)? {
+                if Some(record.calculated_at) > last_record {
+                    db.append_tps_record(chain, &record)?;
+                }
+            }
+        } else if path.ends_with(REORGS) {
+            while let Some(event) = read_line::<ReorgEvent>(&mut entry)? {
+                if Some(event.detected_at) > last_event {
+                    db.append_reorg_event(&event)?;
+                }
+            }
+        }
+    }
+
+    for chain_manifest in chains.values() {
+        check_restored(db, chain_manifest)?;
+    }
+
+    // Written last, so an interrupted restore leaves the chains looking empty,
+    // and the restore can be run again
+    for chain_manifest in chains.values() {
+        db.store_highest_block_number(chain_manifest.chain, chain_manifest.highest_block_number)?;
+    }
+
+    Ok(())
+}
+
+/// Checks that the db has as many of the chain's blocks, TPS records and
+/// reorg events as the snapshot.
+fn check_restored(db: &Arc<dyn Db>, chain_manifest: &ChainManifest) -> Result<()> {
+    let chain = chain_manifest.chain;
+    let num_blocks = db.list_block_numbers(chain)?.len() as u64;
+    let num_tps_records = db
+        .load_tps_history(chain, Utc.timestamp(0, 0), Utc::now())?
+        .len() as u64;
+    let num_reorg_events = db
+        .load_reorg_events(chain, Utc.timestamp(0, 0), Utc::now())?
+        .len() as u64;
+
+    let expected = (
+        chain_manifest.num_blocks,
+        chain_manifest.num_tps_records,
+        chain_manifest.num_reorg_events,
+    );
+    if (num_blocks, num_tps_records, num_reorg_events) != expected {
+        bail!(
+            "restored {} blocks, {} tps records and {} reorg events for {}, but the snapshot has {}, {} and {}",
+            num_blocks,
+            num_tps_records,
+            num_reorg_events,
+            chain,
+            expected.0,
+            expected.1,
+            expected.2
+        );
+    }
+
+    Ok(())
+}
+
+/// Reads the whole snapshot, checking every file against the checksums in
+/// its manifest, and returns the manifest.
+fn check_snapshot(path: &Path) -> Result<Manifest> {
+    let mut checksums = BTreeMap::new();
+    let mut manifest: Option<Manifest> = None;
+
+    let mut archive = open_archive(path)?;
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.to_string_lossy().into_owned();
+        if path == MANIFEST {
+            manifest = Some(serde_json::from_reader(entry)?);
+        } else {
+            let mut hasher = HashingWriter::new(io::sink());
+            io::copy(&mut entry, &mut hasher)?;
+            checksums.insert(path, hasher.checksum());
+        }
+    }
+
+    let manifest =
+        manifest.ok_or_else(|| anyhow!("snapshot {} has no manifest", path.display()))?;
+
+    if manifest.version > SNAPSHOT_VERSION {
+        bail!(
+            "snapshot version {} is newer than supported version {}",
+            manifest.version,
+            SNAPSHOT_VERSION
+        );
+    }
+
+    for (file, checksum) in &manifest.files {
+        match checksums.remove(file) {
+            Some(actual) if actual == *checksum => {}
+            Some(_) => bail!("checksum mismatch for {} in snapshot", file),
+            None => bail!("{} is missing from snapshot", file),
+        }
+    }
+    if let Some(file) = checksums.keys().next() {
+        bail!("unexpected file {} in snapshot", file);
+    }
+
+    Ok(manifest)
+}
+
+fn open_archive(path: &Path) -> Result<tar::Archive<GzDecoder<BufReader<File>>>> {
+    let file = BufReader::new(File::open(path)?);
+    Ok(tar::Archive::new(GzDecoder::new(file)))
+}
+
+fn sibling_path(path: &Path, extension: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(".");
+    path.push(extension);
+    PathBuf::from(path)
+}
+
+fn to_value<T: Record>(record: &T) -> Result<Value> {
+    Ok(serde_json::to_value(encode_record(record))?)
+}
+
+fn from_value<T: Record>(value: Value) -> Result<T> {
+    Ok(decode_record(value)?.record)
+}
+
+fn write_line<T: Record>(out: &mut dyn Write, record: &T) -> Result<()> {
+    serde_json::to_writer(&mut *out, &encode_record(record))?;
+    out.write_all(b"\n")?;
+    Ok(())
+}
+
+fn read_line<T: Record>(reader: &mut impl BufRead) -> Result<Option<T>> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    Ok(Some(from_value(serde_json::from_str(&line)?)?))
+}
+
+/// Computes the SHA-256 of everything written through it.
+struct HashingWriter<W> {
+    inner: W,
+    hasher: Sha256,
+    len: u64,
+}
+
+impl<W: Write> HashingWriter<W> {
+    fn new(inner: W) -> HashingWriter<W> {
+        HashingWriter {
+            inner,
+            hasher: Sha256::new(),
+            len: 0,
+        }
+    }
+
+    fn checksum(self) -> String {
+        hex::encode(self.hasher.finalize())
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.hasher.update(&buf[..len]);
+        self.len += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use realtps_common::db::MemoryDb;
+
+    fn chain() -> Chain {
+        Chain::try_from("solana").expect("chain")
+    }
+
+    fn make_block(block_number: u64) -> Block {
+        Block {
+            chain: chain(),
+            block_number,
+            prev_block_number: block_number.checked_sub(1),
+            timestamp: block_number,
+            num_txs: block_number,
+            hash: format!("{}", block_number),
+            parent_hash: format!("{}", block_number.saturating_sub(1)),
+        }
+    }
+
+    #[test]
+    fn create_and_restore() -> Result<()> {
+        let path =
+            std::env::temp_dir().join(format!("realtps-snapshot-{}.tar.gz", std::process::id()));
+
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        let blocks: Vec<Block> = (0..2500).map(make_block).collect();
+        db.store_blocks_and_highest_block_number(chain(), blocks, 2499)?;
+        db.store_finalized_block_number(chain(), 2490)?;
+        db.store_tps(chain(), 1.5)?;
+        let reorg = ReorgEvent {
+            chain: chain(),
+            block_number: 2000,
+            old_hash: "a".to_string(),
+            new_hash: "b".to_string(),
+            depth: 2,
+            detected_at: Utc.timestamp(1_000_000, 0),
+        };
+        db.append_reorg_event(&reorg)?;
+
+        create_snapshot(&[chain(), Chain::try_from("ethereum")?], &db, &path)?;
+
+        // An interrupted restore already wrote the reorg, which isn't
+        // restored twice
+        let restored_db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        restored_db.append_reorg_event(&reorg)?;
+        restore_snapshot(&[chain()], &restored_db, &path)?;
+        assert_eq!(restored_db.load_highest_block_number(chain())?, Some(2499));
+        assert_eq!(
+            restored_db.load_finalized_block_number(chain())?,
+            Some(2490)
+        );
+        assert_eq!(restored_db.load_tps(chain())?, Some(1.5));
+        assert_eq!(restored_db.list_block_numbers(chain())?.len(), 2500);
+        let block = restored_db.load_block(chain(), 1234)?.expect("block");
+        assert_eq!(block.hash, make_block(1234).hash);
+        let reorgs = restored_db.load_reorg_events(chain(), Utc.timestamp(0, 0), Utc::now())?;
+        assert_eq!(reorgs, vec![reorg]);
+
+        // Only into an empty db
+        assert!(restore_snapshot(&[chain()], &restored_db, &path).is_err());
+
+        fs::remove_file(&path)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,23 @@
+use anyhow::Result;
+use log::info;
+use realtps_common::{chain::Chain, db::DbConfig};
+use tokio::task;
+
+/// Rewrites every chain's records that were stored by older versions of
+/// realtps in the current record format.
+pub async fn upgrade(chains: &[Chain], db_config: &DbConfig) -> Result<()> {
+    info!("upgrading records in db {}", db_config);
+
+    let db = db_config.open()?;
+
+    for chain in chains {
+        let chain = *chain;
+        let db = db.clone();
+        let num_upgraded = task::spawn_blocking(move || db.upgrade_records(chain)).await??;
+        info!("upgraded {} records for {}", num_upgraded, chain);
+    }
+
+    info!("upgraded records in db {}", db_config);
+
+    Ok(())
+}
@@ -0,0 +1,292 @@
+use crate::client::Client;
+use crate::delay::Tuning;
+use crate::helpers::*;
+use anyhow::{bail, Result};
+use log::{info, warn};
+use realtps_common::{
+    chain::Chain,
+    db::{Block, Db},
+};
+use std::collections::{BTreeSet, HashMap};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::task;
+
+/// The problems found in one chain's stored blocks.
+#[derive(Default, Debug)]
+pub struct Report {
+    /// Missing blocks, referenced by the block after them, with older blocks
+    /// still stored.
+    pub gaps: Vec<u64>,
+    /// Blocks whose `parent_hash` is not the hash of the stored block before them.
+    pub broken_links: Vec<u64>,
+    /// Stored blocks that are not on the chain walked back from the highest
+    /// known block.
+    pub orphans: Vec<u64>,
+    pub temp_files: Vec<PathBuf>,
+    /// How many of the above were repaired.
+    pub repaired: usize,
+}
+
+impl Report {
+    pub fn num_problems(&self) -> usize {
+        self.gaps.len() + self.broken_links.len() + self.orphans.len() + self.temp_files.len()
+    }
+}
+
+/// Checks every chain's stored blocks, and with `repair`, fixes what it can.
+///
+/// Repairing refetches missing blocks and blocks with broken links from the
+/// chain's client, if there is one, and removes orphans and temp files. It
+/// should not run while `realtps_import` is importing into the same db.
+pub async fn verify(
+    chains: &[Chain],
+    db: &Arc<dyn Db>,
+    clients: &HashMap<Chain, (Box<dyn Client>, Tuning)>,
+    repair: bool,
+) -> Result<()> {
+    let mut num_unrepaired = 0;
+
+    for chain in chains {
+        let chain = *chain;
+        let client = if repair {
+            let client = clients
+                .get(&chain)
+                .map(|(client, tuning)| (client.as_ref(), tuning));
+            if client.is_none() {
+                warn!("no client for {}; can't refetch blocks", chain);
+            }
+            client
+        } else {
+            None
+        };
+
+        let report = verify_chain(chain, db, client, repair).await?;
+        let num_problems = report.num_problems();
+
+        if num_problems == 0 {
+            info!("no problems found for {}", chain);
+        } else {
+            warn!("problems found for {}: {:?}", chain, report);
+            info!(
+                "repaired {} of {} problems for {}",
+                report.repaired, num_problems, chain
+            );
+            num_unrepaired += num_problems - report.repaired;
+        }
+    }
+
+    if num_unrepaired > 0 {
+        bail!("db has {} unrepaired problems", num_unrepaired);
+    }
+
+    Ok(())
+}
+
+/// Walks back from the highest known block, and with `repair`, refetches
+/// missing blocks and blocks with broken links if there's a `client`.
+pub async fn verify_chain(
+    chain: Chain,
+    db: &Arc<dyn Db>,
+    client: Option<(&dyn Client, &Tuning)>,
+    repair: bool,
+) -> Result<Report> {
+    info!("verifying {}", chain);
+
+    let client = if repair { client } else { None };
+
+    let mut report = Report::default();
+
+    let stored_block_numbers = {
+        let db = db.clone();
+        task::spawn_blocking(move || db.list_block_numbers(chain)).await??
+    };
+    let stored_block_numbers: BTreeSet<u64> = stored_block_numbers.into_iter().collect();
+    let mut canonical_block_numbers = BTreeSet::new();
+
+    // The next older stored block, for continuing past a gap
+    let older_stored_block = |block_number: u64| stored_block_numbers.range(..block_number).last();
+
+    let highest_block_number = load_highest_known_block_number(chain, db).await?;
+
+    let mut next_block = match highest_block_number {
+        Some(highest_block_number) => match load_block(chain, db, highest_block_number).await? {
+            Some(block) => Some(block),
+            None => {
+                report.gaps.push(highest_block_number);
+                match refetch_block(chain, client, db, highest_block_number, None).await? {
+                    Some(block) => {
+                        report.repaired += 1;
+                        Some(block)
+                    }
+                    None => match older_stored_block(highest_block_number) {
+                        Some(block_number) => load_block(chain, db, *block_number).await?,
+                        None => None,
+                    },
+                }
+            }
+        },
+        None => {
+            info!("no highest block number for {}", chain);
+            None
+        }
+    };
+
+    while let Some(block) = next_block {
+        canonical_block_numbers.insert(block.block_number);
+
+        let prev_block_number = match block.prev_block_number {
+            Some(prev_block_number) => prev_block_number,
+            None => break,
+        };
+
+        next_block = match load_block(chain, db, prev_block_number).await? {
+            Some(prev_block) if prev_block.hash == block.parent_hash => Some(prev_block),
+            Some(prev_block) => {
+                report.broken_links.push(block.block_number);
+                let parent_hash = Some(&block.parent_hash);
+                match refetch_block(chain, client, db, prev_block_number, parent_hash).await? {
+                    Some(prev_block) => {
+                        report.repaired += 1;
+                        Some(prev_block)
+                    }
+                    None => Some(prev_block),
+                }
+            }
+            None => match older_stored_block(prev_block_number) {
+                // Nothing older is stored, so this is just the oldest block we keep
+                None => None,
+                Some(older_block_number) => {
+                    report.gaps.push(prev_block_number);
+                    let parent_hash = Some(&block.parent_hash);
+                    match refetch_block(chain, client, db, prev_block_number, parent_hash).await? {
+                        Some(prev_block) => {
+                            report.repaired += 1;
+                            Some(prev_block)
+                        }
+                        None => load_block(chain, db, *older_block_number).await?,
+                    }
+                }
+            },
+        };
+    }
+
+    report.orphans = stored_block_numbers
+        .difference(&canonical_block_numbers)
+        .cloned()
+        .collect();
+
+    report.temp_files = {
+        let db = db.clone();
+        task::spawn_blocking(move || db.list_temp_files(chain)).await??
+    };
+
+    if repair {
+        if !report.orphans.is_empty() {
+            info!(
+                "removing {} orphaned blocks for {}",
+                report.orphans.len(),
+                chain
+            );
+            remove_blocks(chain, db, report.orphans.clone()).await?;
+            report.repaired += report.orphans.len();
+        }
+
+        for temp_file in report.temp_files.clone() {
+            info!("removing {}", temp_file.display());
+            let db = db.clone();
+            task::spawn_blocking(move || db.remove_temp_file(chain, &temp_file)).await??;
+            report.repaired += 1;
+        }
+    }
+
+    Ok(report)
+}
+
+/// Fetches and stores `block_number` if there's a client to fetch it with,
+/// and its hash is `expected_hash`, returning the stored block.
+async fn refetch_block(
+    chain: Chain,
+    client: Option<(&dyn Client, &Tuning)>,
+    db: &Arc<dyn Db>,
+    block_number: u64,
+    expected_hash: Option<&String>,
+) -> Result<Option<Block>> {
+    let (client, tuning) = match client {
+        Some(client) => client,
+        None => return Ok(None),
+    };
+
+    info!("refetching block {} for {}", block_number, chain);
+    let block = fetch_block(chain, client, tuning, block_number).await?;
+
+    if let Some(expected_hash) = expected_hash {
+        if block.hash != *expected_hash {
+            warn!(
+                "refetched block {} for {} has hash {}; expected {}",
+                block_number, chain, block.hash, expected_hash
+            );
+            return Ok(None);
+        }
+    }
+
+    store_block(db, block.clone()).await?;
+
+    Ok(Some(block))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::client::mock::MockClient;
+    use realtps_common::db::MemoryDb;
+
+    fn chain() -> Chain {
+        Chain::try_from("solana").expect("chain")
+    }
+
+    #[tokio::test]
+    async fn verify_and_repair() -> Result<()> {
+        let client = MockClient::new(chain(), 1);
+        client.set_blocks(0..=10, "a");
+
+        let memory_db = Arc::new(MemoryDb::new());
+        let db: Arc<dyn Db> = memory_db.clone();
+        for block_number in 2..=10 {
+            let block = client.get_block(block_number).await?.expect("block");
+            db.store_block(block)?;
+        }
+        db.store_highest_block_number(chain(), 10)?;
+
+        // A gap at 5, a block from another fork at 8, and an orphan at 11
+        db.remove_block(chain(), 5)?;
+        client.set_blocks(8..=8, "b");
+        db.store_block(client.get_block(8).await?.expect("block"))?;
+        client.set_blocks(8..=11, "a");
+        db.store_block(client.get_block(11).await?.expect("block"))?;
+
+        let tuning = Tuning::for_chain(chain());
+        let report = verify_chain(chain(), &db, Some((&client, &tuning)), false).await?;
+        assert_eq!(report.gaps, vec![5]);
+        assert_eq!(report.broken_links, vec![9]);
+        assert_eq!(report.orphans, vec![11]);
+        assert_eq!(report.repaired, 0);
+        assert_eq!(
+            memory_db.block_numbers(chain()),
+            vec![2, 3, 4, 6, 7, 8, 9, 10, 11]
+        );
+
+        let report = verify_chain(chain(), &db, Some((&client, &tuning)), true).await?;
+        assert_eq!(report.num_problems(), 3);
+        assert_eq!(report.repaired, 3);
+
+        let report = verify_chain(chain(), &db, None, false).await?;
+        assert_eq!(report.num_problems(), 0);
+        assert_eq!(
+            memory_db.block_numbers(chain()),
+            (2..=10).collect::<Vec<_>>()
+        );
+
+        Ok(())
+    }
+}
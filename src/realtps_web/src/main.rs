@@ -1,14 +1,49 @@
 #[macro_use]
 extern crate rocket;
 
-use chrono::Duration;
+use chrono::{DateTime, Duration, Utc};
 use realtps_common::{
-    chain::Chain,
-    db::{CalculationLog, Db, JsonDb},
+    chain::{self, Chain, Layer, CHAIN_REGISTRY_PATH},
+    db::{CalculationLog, Db, DbConfig, DbKind, ReorgEvent, TpsRecord},
 };
 use rocket::fs::{relative, FileServer};
+use rocket::http::Status;
+use rocket::State;
 use rocket_dyn_templates::Template;
 use serde::{Deserialize, Serialize};
+use std::env;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// Our own settings, read from `Rocket.toml` and `ROCKET_*` env vars.
+#[derive(Deserialize, Debug)]
+struct WebConfig {
+    /// Overridden by `REALTPS_DB`.
+    #[serde(default)]
+    db: DbConfig,
+    /// The chain registry. Overridden by `REALTPS_CHAINS`.
+    #[serde(default = "default_chain_registry")]
+    chains: PathBuf,
+    /// Whether to show test networks as well as mainnets.
+    #[serde(default)]
+    show_testnets: bool,
+}
+
+/// The chains the site shows.
+struct ShownChains {
+    mainnets: Vec<Chain>,
+    testnets: Vec<Chain>,
+}
+
+impl ShownChains {
+    fn all(&self) -> impl Iterator<Item = &Chain> {
+        self.mainnets.iter().chain(self.testnets.iter())
+    }
+}
+
+fn default_chain_registry() -> PathBuf {
+    PathBuf::from(CHAIN_REGISTRY_PATH)
+}
 
 #[derive(Serialize, Deserialize, Debug)]
 struct EmptyContext {}
@@ -16,12 +51,15 @@ struct EmptyContext {}
 #[derive(Serialize, Deserialize, Debug)]
 struct Context {
     rows: Vec<Row>,
+    testnet_rows: Vec<Row>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 struct Row {
     chain_id: Chain,
     chain_name: String,
+    layer: Option<String>,
+    homepage: Option<String>,
     note: Option<String>,
     tps: f64,
     tps_str: String,
@@ -40,54 +78,127 @@ struct Log {
     log_details: CalculationLog,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+struct HistoryContext {
+    chain_id: Chain,
+    chain_name: String,
+    details: ChainDetails,
+    days: i64,
+    records: Vec<HistoryRow>,
+    /// The records' TPS as SVG polyline points, in a 100x100 view box.
+    chart_points: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ChainDetails {
+    layer: Option<String>,
+    block_time_secs: f64,
+    slots: bool,
+    homepage: Option<String>,
+    disabled_reason: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct HistoryRow {
+    #[serde(flatten)]
+    record: TpsRecord,
+    oldest_block_url: Option<String>,
+    newest_block_url: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ReorgsContext {
+    /// Set when only one chain's reorgs are shown.
+    chain_id: Option<Chain>,
+    chain_name: Option<String>,
+    days: i64,
+    reorgs: Vec<ReorgRow>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ReorgRow {
+    #[serde(flatten)]
+    event: ReorgEvent,
+    chain_name: String,
+    block_url: Option<String>,
+}
+
+/// How many days of history the history page shows by default.
+const DEFAULT_HISTORY_DAYS: i64 = 30;
+/// The most days of history a page can ask for.
+const MAX_HISTORY_DAYS: i64 = 365;
+
+/// Checks the `days` a page asked for, returning it and the time range it covers.
+fn history_range(days: Option<i64>) -> Result<(i64, DateTime<Utc>, DateTime<Utc>), Status> {
+    let days = days.unwrap_or(DEFAULT_HISTORY_DAYS);
+    if !(1..=MAX_HISTORY_DAYS).contains(&days) {
+        return Err(Status::BadRequest);
+    }
+
+    let to = Utc::now();
+    let from = to - Duration::days(days);
+    Ok((days, from, to))
+}
+
+/// Logs a db error and turns it into a 500.
+fn db_error(chain: Chain, e: impl std::fmt::Display) -> Status {
+    error!("error loading data for chain {}: {:#}", chain, e);
+    Status::InternalServerError
+}
+
 #[get("/")]
-fn index() -> Template {
-    let mut list = Vec::new();
-    let db = JsonDb;
+fn index(db: &State<Arc<dyn Db>>, chains: &State<ShownChains>) -> Template {
+    let rows = |chains: &[Chain]| -> Vec<Row> {
+        chains
+            .iter()
+            .filter_map(|chain| make_row(db.as_ref(), *chain))
+            .collect()
+    };
 
-    for chain in Chain::all_chains() {
-        if let Some(tps) = db
-            .load_tps(chain)
-            .expect(&format!("No tps data for chain {}", &chain))
-        {
-            let mut is_data_too_old = false;
-            if let Some(log_details) = db
-                .load_calculation_log(chain)
-                .expect(&format!("No calculation log for chain {}", &chain))
-            {
-                if log_details.calculating_start - log_details.newest_block_timestamp
-                    > Duration::days(1)
-                {
-                    is_data_too_old = true;
-                }
-            }
+    let context = Context {
+        rows: rows(&chains.mainnets),
+        testnet_rows: rows(&chains.testnets),
+    };
+    Template::render("index", &context)
+}
 
-            let note = chain_note(chain).map(ToString::to_string);
-            let chain_id = chain;
-            let chain_name = chain.description().to_string();
-            let tps_str = format!("{:.2}", tps);
+fn make_row(db: &dyn Db, chain: Chain) -> Option<Row> {
+    let (tps, log_details) = db
+        .load_tps_and_calculation_log(chain)
+        .expect(&format!("No tps data for chain {}", &chain));
+    let tps = tps?;
 
-            list.push(Row {
-                chain_id,
-                chain_name,
-                note,
-                tps,
-                tps_str,
-                is_data_too_old,
-            });
+    let mut is_data_too_old = false;
+    if let Some(log_details) = log_details {
+        if log_details.calculating_start - log_details.newest_block_timestamp > Duration::days(1) {
+            is_data_too_old = true;
         }
     }
 
-    let context = Context { rows: list };
-    Template::render("index", &context)
+    let note = chain.info().about.clone();
+    let chain_id = chain;
+    let chain_name = chain.description().to_string();
+    let layer = layer_note(chain);
+    let homepage = chain.info().homepage.clone();
+    let tps_str = format!("{:.2}", tps);
+
+    Some(Row {
+        chain_id,
+        chain_name,
+        layer,
+        homepage,
+        note,
+        tps,
+        tps_str,
+        is_data_too_old,
+    })
 }
 
 #[get("/log")]
-fn log() -> Template {
+fn log(db: &State<Arc<dyn Db>>, chains: &State<ShownChains>) -> Template {
     let mut list = Vec::new();
-    let db = JsonDb;
 
-    for chain in Chain::all_chains() {
+    for chain in chains.all().copied() {
         if let Some(log_details) = db
             .load_calculation_log(chain)
             .expect(&format!("No calculation log for chain {}", &chain))
@@ -107,6 +218,95 @@ fn log() -> Template {
     Template::render("log", &context)
 }
 
+#[get("/history/<chain>?<days>")]
+fn history(
+    db: &State<Arc<dyn Db>>,
+    chains: &State<ShownChains>,
+    chain: &str,
+    days: Option<i64>,
+) -> Result<Template, Status> {
+    let chain = Chain::try_from(chain).map_err(|_| Status::NotFound)?;
+    if chain.is_testnet() && !chains.testnets.contains(&chain) {
+        return Err(Status::NotFound);
+    }
+    let (days, from, to) = history_range(days)?;
+
+    let records = db
+        .load_tps_history(chain, from, to)
+        .map_err(|e| db_error(chain, e))?;
+
+    let chart_points = chart_points(&records);
+    let records = records
+        .into_iter()
+        .map(|record| HistoryRow {
+            oldest_block_url: chain.explorer_url(record.oldest_block_number),
+            newest_block_url: chain.explorer_url(record.newest_block_number),
+            record,
+        })
+        .collect();
+
+    let info = chain.info();
+    let details = ChainDetails {
+        layer: layer_note(chain),
+        block_time_secs: chain.block_time().as_secs_f64(),
+        slots: info.slots,
+        homepage: info.homepage.clone(),
+        disabled_reason: info.disabled_reason.clone(),
+    };
+
+    let context = HistoryContext {
+        chain_id: chain,
+        chain_name: chain.description().to_string(),
+        details,
+        days,
+        records,
+        chart_points,
+    };
+    Ok(Template::render("history", &context))
+}
+
+#[get("/reorgs?<chain>&<days>")]
+fn reorgs(
+    db: &State<Arc<dyn Db>>,
+    chains: &State<ShownChains>,
+    chain: Option<&str>,
+    days: Option<i64>,
+) -> Result<Template, Status> {
+    let shown: Vec<Chain> = match chain {
+        Some(chain) => {
+            let chain = Chain::try_from(chain).map_err(|_| Status::NotFound)?;
+            if chain.is_testnet() && !chains.testnets.contains(&chain) {
+                return Err(Status::NotFound);
+            }
+            vec![chain]
+        }
+        None => chains.all().cloned().collect(),
+    };
+    let (days, from, to) = history_range(days)?;
+
+    let mut reorgs = vec![];
+    for chain in &shown {
+        let events = db
+            .load_reorg_events(*chain, from, to)
+            .map_err(|e| db_error(*chain, e))?;
+        reorgs.extend(events.into_iter().map(|event| ReorgRow {
+            chain_name: event.chain.description().to_string(),
+            block_url: event.chain.explorer_url(event.block_number),
+            event,
+        }));
+    }
+    reorgs.sort_by(|a, b| b.event.detected_at.cmp(&a.event.detected_at));
+
+    let chain = chain.and(shown.first().cloned());
+    let context = ReorgsContext {
+        chain_id: chain,
+        chain_name: chain.map(|chain| chain.description().to_string()),
+        days,
+        reorgs,
+    };
+    Ok(Template::render("reorgs", &context))
+}
+
 #[get("/about")]
 fn about() -> Template {
     Template::render("about", EmptyContext {})
@@ -114,15 +314,69 @@ fn about() -> Template {
 
 #[launch]
 fn rocket() -> _ {
-    rocket::build()
-        .mount("/", routes![index, about, log])
+    let rocket = rocket::build();
+    let config: WebConfig = rocket.figment().extract().expect("web config");
+    let chain_registry = env::var_os("REALTPS_CHAINS")
+        .map(PathBuf::from)
+        .unwrap_or(config.chains);
+    chain::load_registry(chain_registry).expect("chain registry");
+    let db_config = DbConfig::from_env()
+        .expect("db config")
+        .unwrap_or(config.db);
+    // sled can't be opened by two processes, and realtps_import needs it
+    if db_config.kind == DbKind::Sled {
+        panic!(
+            "db {} can't be shared with realtps_import; use json, sqlite or segment",
+            db_config
+        );
+    }
+    info!("using db {}", db_config);
+    let db = db_config.open().expect("opening db");
+    let chains = ShownChains {
+        mainnets: Chain::all_chains(),
+        testnets: if config.show_testnets {
+            Chain::all_testnets()
+        } else {
+            vec![]
+        },
+    };
+
+    rocket
+        .manage(db)
+        .manage(chains)
+        .mount("/", routes![index, about, log, history, reorgs])
         .mount("/static", FileServer::from(relative!("static")))
         .attach(Template::fairing())
 }
 
-fn chain_note(chain: Chain) -> Option<&'static str> {
-    match chain {
-        Chain::Solana => Some("solana"),
-        _ => None,
+/// Describes how a chain that isn't an L1 is attached to its parent chain,
+/// e.g. "L2 of Ethereum".
+fn layer_note(chain: Chain) -> Option<String> {
+    match (chain.info().layer, chain.parent()) {
+        (Layer::L1, _) => None,
+        (layer, Some(parent)) => Some(format!("{} of {}", layer, parent.description())),
+        (layer, None) => Some(layer.to_string()),
     }
 }
+
+fn chart_points(records: &[TpsRecord]) -> String {
+    let (first, last) = match (records.first(), records.last()) {
+        (Some(first), Some(last)) => (first.calculated_at, last.calculated_at),
+        _ => return String::new(),
+    };
+    let seconds = (last - first).num_seconds().max(1) as f64;
+    let max_tps = records
+        .iter()
+        .map(|record| record.tps)
+        .fold(f64::EPSILON, f64::max);
+
+    records
+        .iter()
+        .map(|record| {
+            let x = (record.calculated_at - first).num_seconds() as f64 / seconds * 100.0;
+            let y = 100.0 - record.tps / max_tps * 100.0;
+            format!("{:.2},{:.2}", x, y)
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}